@@ -1,25 +1,67 @@
-use chat_app::{init, Client, Result};
-use tracing::Level;
+use chat_app::{
+    common::{messages::Authentication, UserName},
+    config::{ClientArgs, ClientConfig},
+    init, Client, Result,
+};
 
-fn get_username() -> Result<String> {
+use clap::Parser;
+use tracing::debug;
+
+fn get_username() -> Result<UserName> {
     println!("Enter your username:");
     let mut username = String::new();
     std::io::stdin().read_line(&mut username)?;
-    let username = username.trim();
-    if username.is_empty() {
-        println!("Username cannot be empty, please try again");
-        get_username()
+    match UserName::try_from(username.as_str()) {
+        Ok(username) => Ok(username),
+        Err(e) => {
+            println!("Invalid username, {}, please try again", e);
+            get_username()
+        }
+    }
+}
+
+fn get_authentication() -> Result<Authentication> {
+    println!("Do you already have an account? [Y/n]");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let register = matches!(answer.trim().to_lowercase().as_str(), "n" | "no");
+
+    let password = rpassword::prompt_password("Enter your password: ")?;
+    if register {
+        let confirm = rpassword::prompt_password("Confirm your password: ")?;
+        if password != confirm {
+            println!("Passwords do not match, please try again");
+            return get_authentication();
+        }
+        Ok(Authentication::Register { password })
     } else {
-        Ok(username.trim().to_string())
+        Ok(Authentication::Login { password })
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let address = init(Level::INFO);
+    let args = ClientArgs::parse();
+    let config = ClientConfig::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    init(&config.log);
+    debug!("Effective config:\n{}", config.to_toml()?);
+    let address = config.address();
+
     let username = get_username()?;
+    let authentication = get_authentication()?;
 
-    let client = Client::new(username).await;
+    let mut client = Client::new(username, authentication).await;
+    if let Some(tls) = config.tls() {
+        client = client.with_tls(tls);
+    }
 
-    Ok(client.run(address).await?)
+    if args.tui {
+        Ok(client.run_tui(address).await?)
+    } else {
+        Ok(client.run(address).await?)
+    }
 }
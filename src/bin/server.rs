@@ -1,12 +1,30 @@
-use chat_app::{init, Result, Server};
+use chat_app::{
+    config::{ServerArgs, ServerConfig},
+    init, Result, Server,
+};
 
-use tracing::Level;
+use clap::Parser;
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let addr = init(Level::TRACE);
+    let args = ServerArgs::parse();
+    let config = ServerConfig::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    init(&config.log);
+    info!("Effective config:\n{}", config.to_toml()?);
 
-    let mut server = Server::default();
+    let mut server = Server::new(config);
 
-    Ok(server.run(addr).await?)
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown(None);
+        }
+    });
+
+    Ok(server.run().await?)
 }
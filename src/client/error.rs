@@ -8,6 +8,9 @@ pub enum ClientError {
     Io(std::io::Error),
 
     InvalidCommand,
+    HandshakeRejected(crate::common::messages::HandshakeRejection),
+    /// The server answered a request with an error.
+    Request(crate::common::messages::ErrorMessage),
 }
 
 //Error boilerplate
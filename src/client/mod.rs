@@ -1,10 +1,15 @@
 mod error;
+mod session;
+mod tui;
 
-use crate::common::messages::{ClientMessage, Handshake, ServerInternal};
-use crate::common::UserName;
-use crate::connection::{Connection, ConnectionError, FrameType, OwnedReader, OwnedWriter};
+use crate::common::messages::{
+    AdminCommand, Authentication, ClientMessage, Handshake, HandshakeResponse, ServerInternal,
+};
+use crate::common::{MessageId, Presence, Reaction, RoomMode, RoomRole, UserName};
+use crate::connection::{ClientTlsConfig, Connection, FrameType};
 pub use error::ClientError;
 use error::Result;
+pub use session::{Events, Session};
 
 use crossterm::cursor::{MoveToColumn, MoveUp};
 use crossterm::execute;
@@ -18,18 +23,58 @@ use tracing::{error, info, instrument, warn};
 
 pub struct Client {
     user: UserName,
+    authentication: Authentication,
+    tls: Option<ClientTlsConfig>,
 }
 
 impl Client {
-    pub async fn new(user: impl Into<UserName>) -> Self {
-        Self { user: user.into() }
+    pub async fn new(user: impl Into<UserName>, authentication: Authentication) -> Self {
+        Self {
+            user: user.into(),
+            authentication,
+            tls: None,
+        }
+    }
+
+    /// Connect over TLS, verifying the server as configured in `tls`.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    async fn connect(&self, addr: impl ToSocketAddrs) -> Result<Connection> {
+        let connection = match &self.tls {
+            Some(tls) => Connection::init_tls(addr, tls).await?,
+            None => Connection::init(addr).await?,
+        };
+        Ok(connection)
     }
 
     async fn authenticate(&self, connection: &mut Connection) -> Result<()> {
         connection
-            .write_frame(&Handshake(self.user.clone()))
+            .write_frame(&Handshake::new(
+                self.user.clone(),
+                self.authentication.clone(),
+            ))
             .await?;
-        Ok(())
+        let response: HandshakeResponse = connection.read_frame().await?;
+        println!("{}", response);
+        match response {
+            HandshakeResponse::Accepted {
+                version,
+                capabilities,
+                ..
+            } => {
+                info!(
+                    "Handshake accepted, protocol v{} with capabilities: {:?}",
+                    version, capabilities
+                );
+                Ok(())
+            }
+            HandshakeResponse::Rejected(rejection) => {
+                Err(ClientError::HandshakeRejected(rejection))
+            }
+        }
     }
 
     #[instrument(skip(input_sender), level = "debug")]
@@ -67,40 +112,53 @@ impl Client {
         }
     }
 
-    #[instrument(skip_all, level = "debug")]
-    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
-        let mut connection = Connection::init(addr).await?;
+    /// Connect and authenticate, then serve the connection in the background. Requests are sent
+    /// through the [`Session`], everything else the server sends comes out of the [`Events`].
+    pub async fn start(&self, addr: impl ToSocketAddrs) -> Result<(Session, Events)> {
+        let mut connection = self.connect(addr).await?;
 
         self.authenticate(&mut connection).await?;
 
+        let (reader, writer) = connection.split_into();
+        Ok(Session::start(reader, writer))
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        let (session, mut events) = self.start(addr).await?;
+
         let (input_sender, mut input_receiver) = mpsc::channel(16);
 
         tokio::spawn(async move {
             Self::handle_user_input(input_sender).await;
         });
-        let (mut reader, mut writer) = connection.split_into();
 
         loop {
             tokio::select! {
                 Some(frame) = input_receiver.recv() =>{
-                    Self::process_frame(frame, &mut reader, &mut writer).await?;
+                    Self::process_frame(&session, frame).await?;
                 }
-                frame = ServerInternal::read_frame_from(&mut reader) => {
+                frame = events.recv() => {
                     match frame {
-                        Ok(frame) => {
-                            handle_and_print_frame(frame)?
-                        }
-                        Err(e) => {
-                            match e {
-                                ConnectionError::ConnectionDropped => {
-                                    exit(0);
-                                }
-                                _ => {
-                                    error!("Failed to read frame: {:?}", e);
-                                    break;
-                                }
+                        // A printed line can't be taken back, only starting to type is shown.
+                        Some(ServerInternal::Typing { typing: false, .. }) => {}
+                        Some(frame) => {
+                            let shutdown = matches!(
+                                frame,
+                                ServerInternal::ServerShutdown { .. }
+                                    | ServerInternal::Disconnected { .. }
+                            );
+                            handle_and_print_frame(frame)?;
+                            if shutdown {
+                                // The server closes the connection right after, and the stdin
+                                // task would keep the runtime alive.
+                                exit(0);
                             }
                         }
+                        None => {
+                            error!("Connection closed");
+                            exit(0);
+                        }
                     }
                 }
                 else => {
@@ -113,157 +171,437 @@ impl Client {
         Ok(())
     }
 
-    #[instrument(skip(reader, writer), level = "debug")]
-    async fn process_frame(
-        frame: ClientMessage,
-        reader: &mut OwnedReader,
-        writer: &mut OwnedWriter,
-    ) -> Result<()> {
+    /// Run the client with a full-screen terminal UI instead of the line based interface.
+    #[instrument(skip_all, level = "debug")]
+    pub async fn run_tui(self, addr: impl ToSocketAddrs) -> Result<()> {
+        let (session, events) = self.start(addr).await?;
+        tui::run(self.user, session, events).await
+    }
+
+    /// Send a request without holding up the input loop, reporting its outcome once it is
+    /// answered.
+    #[instrument(skip(session), level = "debug")]
+    async fn process_frame(session: &Session, frame: ClientMessage) -> Result<()> {
         match frame {
             ClientMessage::Ping(nonce) => {
                 info!("Sending ping frame");
-                frame.write_frame_to(writer).await?;
-                let server_frame = ServerInternal::read_frame_from(reader).await?;
-                let n = match server_frame {
-                    ServerInternal::Pong(n) => {
-                        println!("{}", server_frame.to_string().yellow());
-                        info!("Received pong frame: {}", n);
-                        n
+                let session = session.clone();
+                tokio::spawn(async move {
+                    match session.ping(nonce).await {
+                        Ok(elapsed) => println!(
+                            "{}",
+                            format!("Ping-pong successful in {:?}", elapsed).green()
+                        ),
+                        Err(e) => print_request_error(e),
                     }
-                    _ => {
-                        error!("Received invalid frame: {:?}", server_frame);
-                        return Ok(());
-                    }
-                };
-                assert_eq!(nonce, n);
-                println!("{}", "Ping-pong successful".green());
+                });
             }
             ClientMessage::Disconnect => {
                 info!("Sending disconnect frame");
-                frame.write_frame_to(writer).await?;
-                return Err(ConnectionError::ConnectionDropped.into());
+                // The server may hang up before acknowledging, we are leaving either way.
+                if let Err(e) = session.request(frame).await {
+                    warn!("Disconnect was not acknowledged: {}", e);
+                }
+                // The stdin task would keep the runtime alive.
+                exit(0);
             }
             _ => {
-                frame.write_frame_to(writer).await?;
+                let session = session.clone();
+                tokio::spawn(async move {
+                    match session.request(frame).await {
+                        Ok(Some(id)) => println!("{}", format!("Sent as {}", id).dark_grey()),
+                        Ok(None) => {}
+                        Err(e) => print_request_error(e),
+                    }
+                });
             }
         }
         Ok(())
     }
 }
 
+fn print_request_error(e: ClientError) {
+    match e {
+        ClientError::Request(error) => println!("{}", error),
+        e => error!("Request failed: {}", e),
+    }
+}
+
+/// The commands understood by [`parse_command`].
+const VALID_COMMANDS: &str =
+    ":quit, :ping, :pm, :nick, :status, :edit, :delete, :react, :unreact, :cr, :jr, :lr, :lrs, \
+    :lru, :rm, :role, :roles, :kick, :ban, :unban, :mute, :unmute, :mode, :invite, :uninvite, \
+    :topic, :desc, :delroom, :admin";
+
 fn parse_user_input(input: impl Into<String>) -> Option<ClientMessage> {
     let line: String = input.into();
     println!("{:<10}: {}", "You".blue(), line);
 
     if line.starts_with(':') {
-        match line.split(' ').next().unwrap().to_lowercase().as_str() {
-            ":quit" => Some(ClientMessage::Disconnect),
-            // ":create" => {
-            //     let mut parts = line.splitn(2, ' ');
-            //     parts.next();
-            //     let room = parts.next().unwrap_or_default();
-            //     info!("Creating room: {}", room);
-            //     Some(ClientMessage::CreateRoom(room.into()))
-            // }
-            // ":join" => {
-            //     let mut parts = line.splitn(2, ' ');
-            //     parts.next();
-            //     let room = parts.next().unwrap_or_default();
-            //     info!("Joining room: {}", room);
-            //     Some(ClientMessage::Join(room.into()))
-            // }
-            // ":room" => {
-            //     let mut parts = line.splitn(3, ' ');
-            //     parts.next();
-            //     let room = parts.next().unwrap_or_default();
-            //     let content = parts.next().unwrap_or_default();
-            //     info!("Sending room message to {}", room);
-            //     info!("Message: {}", content);
-            //     Some(ClientMessage::RoomMessage {
-            //         room: room.into(),
-            //         content: content.to_string(),
-            //     })
-            // }
-            // ":rooms" => {
-            //     info!("Requesting list of rooms");
-            //     Some(ClientMessage::ListRooms)
-            // }
-            ":users" => Some(ClientMessage::ListUsers),
-            ":ping" => {
-                let frame = ClientMessage::Ping(rand::random());
-                println!("{}", frame.to_string().blue());
+        match parse_command(&line) {
+            Ok(frame) => {
+                if let ClientMessage::Ping(_) = frame {
+                    println!("{}", frame.to_string().blue());
+                }
                 Some(frame)
             }
-            ":pm" => {
-                let mut parts = line.splitn(3, ' ');
-                parts.next();
-                let user = parts.next().unwrap_or_default();
-                info!("Sending private message to {}", user);
-                let content = parts.next().unwrap_or_default();
-                info!("Message: {}", content);
-                Some(ClientMessage::PrivateMessage {
-                    to_user: user.into(),
-                    content: content.to_string(),
-                })
+            Err(_) => {
+                println!("List of valid commands: {}", VALID_COMMANDS);
+                None
             }
-            ":cr" => {
-                let mut parts = line.splitn(2, ' ');
-                parts.next();
-                let room = parts.next().unwrap_or_default();
-                info!("Creating room: {}", room);
-                Some(ClientMessage::CreateRoom(room.into()))
+        }
+    } else {
+        info!("Sending chat message frame");
+        Some(ClientMessage::GlobalChatMessage(line))
+    }
+}
+
+/// Parse a line starting with `:` into the message it stands for.
+fn parse_command(line: &str) -> Result<ClientMessage> {
+    let command = line.split(' ').next().unwrap_or_default().to_lowercase();
+    if let Ok(command) = Commands::try_from(command.as_str()) {
+        return Ok(match command {
+            Commands::Quit => ClientMessage::Disconnect,
+            Commands::Ping => ClientMessage::Ping(rand::random()),
+        });
+    }
+    match command.as_str() {
+        ":users" => Ok(ClientMessage::ListUsers),
+        ":pm" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let user = parts.next().unwrap_or_default();
+            info!("Sending private message to {}", user);
+            let content = parts.next().unwrap_or_default();
+            info!("Message: {}", content);
+            Ok(ClientMessage::PrivateMessage {
+                to_user: UserName::new(user),
+                content: content.to_string(),
+            })
+        }
+        ":cr" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            info!("Creating room: {}", room);
+            match parts.next() {
+                Some(mode) => Ok(ClientMessage::CreateRoomWithMode {
+                    room: room.into(),
+                    mode: parse_room_mode(mode)?,
+                }),
+                None => Ok(ClientMessage::CreateRoom(room.into())),
             }
-            ":jr" => {
-                let mut parts = line.splitn(2, ' ');
-                parts.next();
-                let room = parts.next().unwrap_or_default();
-                info!("Joining room: {}", room);
-                Some(ClientMessage::JoinRoom(room.into()))
+        }
+        ":jr" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            info!("Joining room: {}", room);
+            match parts.next() {
+                Some(password) => Ok(ClientMessage::JoinRoomWithPassword {
+                    room: room.into(),
+                    password: password.into(),
+                }),
+                None => Ok(ClientMessage::JoinRoom(room.into())),
             }
-            ":lr" => {
-                let mut parts = line.splitn(2, ' ');
-                parts.next();
-                let room = parts.next().unwrap_or_default();
-                info!("Leaving room: {}", room);
-                Some(ClientMessage::LeaveRoom(room.into()))
+        }
+        ":mode" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            let mode = parse_room_mode(parts.next().unwrap_or_default())?;
+            info!("Making room {} {}", room, mode);
+            Ok(ClientMessage::SetRoomMode {
+                room: room.into(),
+                mode,
+            })
+        }
+        command @ (":invite" | ":uninvite") => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default().into();
+            let user = UserName::new(parts.next().unwrap_or_default());
+            info!("{} {} to room: {}", command, user, room);
+            match command {
+                ":invite" => Ok(ClientMessage::InviteToRoom { room, user }),
+                _ => Ok(ClientMessage::UninviteFromRoom { room, user }),
             }
-            ":lrs" => {
-                info!("Requesting list of rooms");
-                Some(ClientMessage::ListRooms)
+        }
+        ":lr" => {
+            let mut parts = line.splitn(2, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            info!("Leaving room: {}", room);
+            Ok(ClientMessage::LeaveRoom(room.into()))
+        }
+        ":lrs" => {
+            info!("Requesting list of rooms");
+            Ok(ClientMessage::ListRooms)
+        }
+        ":lru" => {
+            let mut parts = line.splitn(2, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            info!("Requesting list of users in room: {}", room);
+            Ok(ClientMessage::ListRoomUsers(room.into()))
+        }
+        ":rm" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            let content = parts.next().unwrap_or_default();
+            info!("Sending room message to {}", room);
+            info!("Message: {}", content);
+            Ok(ClientMessage::RoomMessage {
+                room: room.into(),
+                content: content.to_string(),
+            })
+        }
+        ":role" => {
+            let mut parts = line.splitn(4, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            let user = parts.next().unwrap_or_default();
+            let role = match parts.next().unwrap_or_default().to_lowercase().as_str() {
+                "owner" => RoomRole::Owner,
+                "moderator" | "mod" => RoomRole::Moderator,
+                "member" => RoomRole::Member,
+                _ => return Err(ClientError::InvalidCommand),
+            };
+            info!("Making {} a {} of room: {}", user, role, room);
+            Ok(ClientMessage::SetRoomRole {
+                room: room.into(),
+                user: UserName::new(user),
+                role,
+            })
+        }
+        ":roles" => {
+            let mut parts = line.splitn(2, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            info!("Requesting roles in room: {}", room);
+            Ok(ClientMessage::ListRoomRoles(room.into()))
+        }
+        ":kick" => {
+            let mut parts = line.splitn(4, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            let user = parts.next().unwrap_or_default();
+            let reason = parts.next().map(str::to_string);
+            info!("Kicking {} from room: {}", user, room);
+            Ok(ClientMessage::KickUser {
+                room: room.into(),
+                user: UserName::new(user),
+                reason,
+            })
+        }
+        ":ban" => {
+            let mut parts = line.splitn(4, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            let user = parts.next().unwrap_or_default();
+            // The duration is optional, without one the rest of the line is the reason.
+            let rest = parts.next().unwrap_or_default();
+            let (duration, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (duration_secs, reason) = match parse_duration(duration) {
+                Some(seconds) => (Some(seconds), reason),
+                None => (None, rest),
+            };
+            info!("Banning {} from room: {}", user, room);
+            Ok(ClientMessage::BanUser {
+                room: room.into(),
+                user: UserName::new(user),
+                duration_secs,
+                reason: Some(reason.to_string()).filter(|reason| !reason.is_empty()),
+            })
+        }
+        ":mute" => {
+            let mut parts = line.splitn(4, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            let user = parts.next().unwrap_or_default();
+            let duration_secs = match parts.next() {
+                Some(duration) => {
+                    Some(parse_duration(duration).ok_or(ClientError::InvalidCommand)?)
+                }
+                None => None,
+            };
+            info!("Muting {} in room: {}", user, room);
+            Ok(ClientMessage::MuteUser {
+                room: room.into(),
+                user: UserName::new(user),
+                duration_secs,
+            })
+        }
+        command @ (":unban" | ":unmute") => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default().into();
+            let user = UserName::new(parts.next().unwrap_or_default());
+            info!("{} {} in room: {}", command, user, room);
+            match command {
+                ":unban" => Ok(ClientMessage::UnbanUser { room, user }),
+                _ => Ok(ClientMessage::UnmuteUser { room, user }),
             }
-            ":lru" => {
-                let mut parts = line.splitn(2, ' ');
-                parts.next();
-                let room = parts.next().unwrap_or_default();
-                info!("Requesting list of users in room: {}", room);
-                Some(ClientMessage::ListRoomUsers(room.into()))
+        }
+        command @ (":topic" | ":desc") => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default().into();
+            // Nothing after the room clears it.
+            let text = parts
+                .next()
+                .map(str::to_string)
+                .filter(|text| !text.is_empty());
+            info!("{} of room: {}", command, room);
+            match command {
+                ":topic" => Ok(ClientMessage::SetRoomTopic { room, topic: text }),
+                _ => Ok(ClientMessage::SetRoomDescription {
+                    room,
+                    description: text,
+                }),
             }
-            ":rm" => {
-                let mut parts = line.splitn(3, ' ');
-                parts.next();
-                let room = parts.next().unwrap_or_default();
-                let content = parts.next().unwrap_or_default();
-                info!("Sending room message to {}", room);
-                info!("Message: {}", content);
-                Some(ClientMessage::RoomMessage {
-                    room: room.into(),
-                    content: content.to_string(),
-                })
+        }
+        ":nick" => {
+            let mut parts = line.splitn(2, ' ');
+            parts.next();
+            let user = parts.next().unwrap_or_default();
+            info!("Changing name to: {}", user);
+            Ok(ClientMessage::ChangeNick(UserName::new(user)))
+        }
+        ":status" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let presence = parse_presence(parts.next().unwrap_or_default())?;
+            let text = parts.next().map(str::trim).filter(|text| !text.is_empty());
+            info!("Setting presence to: {}", presence);
+            Ok(ClientMessage::SetPresence {
+                presence,
+                text: text.map(str::to_string),
+            })
+        }
+        ":edit" => {
+            let mut parts = line.splitn(3, ' ');
+            parts.next();
+            let id = parse_message_id(parts.next().unwrap_or_default())?;
+            let content = parts.next().map(str::trim).unwrap_or_default();
+            if content.is_empty() {
+                return Err(ClientError::InvalidCommand);
             }
-            _ => {
-                warn!("Invalid command: {}.", line);
-                println!(
-                    "List of valid commands: :quit, :ping, :pm, :cr, :jr, :lr, :lrs, :lru, :rm"
-                );
-                None
+            info!("Editing message: {}", id);
+            Ok(ClientMessage::EditMessage {
+                id,
+                content: content.to_string(),
+            })
+        }
+        ":delete" => {
+            let mut parts = line.splitn(2, ' ');
+            parts.next();
+            let id = parse_message_id(parts.next().unwrap_or_default().trim())?;
+            info!("Deleting message: {}", id);
+            Ok(ClientMessage::DeleteMessage(id))
+        }
+        command @ (":react" | ":unreact") => {
+            let mut parts = line.split_whitespace();
+            parts.next();
+            let id = parse_message_id(parts.next().unwrap_or_default())?;
+            let reaction: Reaction = match (parts.next(), parts.next()) {
+                (Some(reaction), None) => reaction.into(),
+                _ => return Err(ClientError::InvalidCommand),
+            };
+            if command == ":react" {
+                info!("Reacting to message {} with {}", id, reaction);
+                Ok(ClientMessage::AddReaction { id, reaction })
+            } else {
+                info!("Taking back reaction {} to message {}", reaction, id);
+                Ok(ClientMessage::RemoveReaction { id, reaction })
             }
         }
-    } else {
-        info!("Sending chat message frame");
-        Some(ClientMessage::GlobalChatMessage(line))
+        ":delroom" => {
+            let mut parts = line.splitn(2, ' ');
+            parts.next();
+            let room = parts.next().unwrap_or_default();
+            info!("Deleting room: {}", room);
+            Ok(ClientMessage::DeleteRoom(room.into()))
+        }
+        ":admin" => parse_admin_command(line).map(ClientMessage::Admin),
+        _ => {
+            warn!("Invalid command: {}.", line);
+            Err(ClientError::InvalidCommand)
+        }
     }
 }
 
+/// Parse `42` or `#42` into the ID of a message.
+fn parse_message_id(id: &str) -> Result<MessageId> {
+    id.parse().map_err(|_| ClientError::InvalidCommand)
+}
+
+fn parse_presence(presence: &str) -> Result<Presence> {
+    match presence.to_lowercase().as_str() {
+        "online" => Ok(Presence::Online),
+        "away" => Ok(Presence::Away),
+        "busy" => Ok(Presence::Busy),
+        _ => Err(ClientError::InvalidCommand),
+    }
+}
+
+/// Parse `public`, `hidden`, `invite` or `password <password>` into a room mode.
+fn parse_room_mode(mode: &str) -> Result<RoomMode> {
+    let (mode, password) = mode.split_once(' ').unwrap_or((mode, ""));
+    match mode.to_lowercase().as_str() {
+        "public" => Ok(RoomMode::Public),
+        "hidden" => Ok(RoomMode::Hidden),
+        "invite" | "invite-only" => Ok(RoomMode::InviteOnly),
+        "password" if !password.is_empty() => Ok(RoomMode::Password(password.into())),
+        _ => Err(ClientError::InvalidCommand),
+    }
+}
+
+/// Parse `:admin <command> ...` into the admin command it stands for.
+fn parse_admin_command(line: &str) -> Result<AdminCommand> {
+    let mut parts = line.splitn(3, ' ');
+    parts.next();
+    let command = parts.next().unwrap_or_default().to_lowercase();
+    let rest = parts.next().unwrap_or_default();
+    // Everything after the user is the reason.
+    let (user, reason) = match rest.split_once(' ') {
+        Some((user, reason)) => (user, Some(reason.to_string())),
+        None => (rest, None),
+    };
+    let address = || rest.parse().map_err(|_| ClientError::InvalidCommand);
+    info!("Admin command: {} {}", command, rest);
+    match command.as_str() {
+        "disconnect" => Ok(AdminCommand::Disconnect {
+            user: UserName::new(user),
+            reason,
+        }),
+        "ban" => Ok(AdminCommand::BanUser {
+            user: UserName::new(user),
+            reason,
+        }),
+        "unban" => Ok(AdminCommand::UnbanUser(UserName::new(rest))),
+        "banip" => Ok(AdminCommand::BanIp(address()?)),
+        "unbanip" => Ok(AdminCommand::UnbanIp(address()?)),
+        "delroom" => Ok(AdminCommand::DeleteRoom(rest.into())),
+        "announce" if !rest.is_empty() => Ok(AdminCommand::Announce(rest.to_string())),
+        _ => Err(ClientError::InvalidCommand),
+    }
+}
+
+/// Parse durations like `90s`, `10m`, `2h` or `7d` into seconds.
+fn parse_duration(duration: &str) -> Option<u64> {
+    let unit = match duration.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount: u64 = duration[..duration.len() - 1].parse().ok()?;
+    amount.checked_mul(unit)
+}
+
 enum Commands {
     Quit,
     Ping,
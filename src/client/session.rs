@@ -0,0 +1,183 @@
+use super::{ClientError, Result};
+use crate::common::messages::{
+    ClientMessage, ClientRequest, ErrorMessage, RequestId, ServerInternal,
+};
+use crate::common::MessageId;
+use crate::connection::{spawn_frame_reader, ConnectionError, FrameReader, FrameWriter};
+
+use std::collections::HashMap;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error, warn};
+
+/// Frames the server sent on its own account, e.g. chat messages, rather than to answer a request.
+pub type Events = mpsc::Receiver<ServerInternal>;
+
+type Answer = std::result::Result<Option<MessageId>, ErrorMessage>;
+
+#[derive(Debug)]
+struct PendingRequest {
+    message: ClientMessage,
+    answer_tx: oneshot::Sender<Answer>,
+}
+
+/// Sends requests over an authenticated connection and waits for their answers. The connection
+/// is served by a background task which tags every request with an ID and hands each
+/// [`ServerInternal::Ack`] or error back to the caller waiting for it, so answers never get mixed
+/// up with the other frames coming in. Handles can be cloned, the connection is closed once they
+/// are all dropped.
+#[derive(Debug, Clone)]
+pub struct Session {
+    requests_tx: mpsc::Sender<PendingRequest>,
+}
+
+impl Session {
+    /// Serve the connection in the background. Frames that do not answer a request come out of
+    /// the returned [`Events`], which ends when the connection is closed.
+    pub fn start<R, W>(reader: R, writer: W) -> (Self, Events)
+    where
+        R: FrameReader + 'static,
+        W: FrameWriter + 'static,
+    {
+        let (requests_tx, requests_rx) = mpsc::channel(16);
+        let (events_tx, events_rx) = mpsc::channel(64);
+        let frames_rx = spawn_frame_reader(reader);
+        tokio::spawn(async move {
+            if let Err(e) = serve(frames_rx, writer, requests_rx, events_tx).await {
+                match e {
+                    ConnectionError::ConnectionClosed | ConnectionError::ConnectionDropped => {
+                        debug!("Connection closed: {}", e)
+                    }
+                    _ => error!("Connection failed: {}", e),
+                }
+            }
+        });
+        (Self { requests_tx }, events_rx)
+    }
+
+    /// Send `message` and wait for the server to acknowledge it. Whatever the request produces,
+    /// like the [`Pong`](ServerInternal::Pong) to a ping, comes out of the [`Events`] first.
+    /// Chat messages resolve to the ID the server gave them.
+    pub async fn request(&self, message: ClientMessage) -> Result<Option<MessageId>> {
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.requests_tx
+            .send(PendingRequest { message, answer_tx })
+            .await
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        match answer_rx.await {
+            Ok(Ok(id)) => Ok(id),
+            Ok(Err(error)) => Err(ClientError::Request(error)),
+            Err(_) => Err(ConnectionError::ConnectionClosed.into()),
+        }
+    }
+
+    /// Ping the server, returning how long it took to answer.
+    pub async fn ping(&self, nonce: u16) -> Result<Duration> {
+        let sent = Instant::now();
+        self.request(ClientMessage::Ping(nonce)).await?;
+        Ok(sent.elapsed())
+    }
+}
+
+async fn serve<W: FrameWriter>(
+    mut frames_rx: mpsc::Receiver<std::result::Result<ServerInternal, ConnectionError>>,
+    mut writer: W,
+    mut requests_rx: mpsc::Receiver<PendingRequest>,
+    events_tx: mpsc::Sender<ServerInternal>,
+) -> std::result::Result<(), ConnectionError> {
+    let mut pending: HashMap<RequestId, oneshot::Sender<Answer>> = HashMap::new();
+    let mut next_id: u32 = 0;
+
+    loop {
+        tokio::select! {
+            request = requests_rx.recv() => {
+                let Some(PendingRequest { message, answer_tx }) = request else {
+                    // Every handle is gone, nobody is left to send requests.
+                    return writer.close().await;
+                };
+                next_id = next_id.wrapping_add(1);
+                let id = RequestId::new(next_id);
+                writer.write_frame(&ClientRequest { id, message }).await?;
+                pending.insert(id, answer_tx);
+            }
+            frame = frames_rx.recv() => {
+                let frame = frame.ok_or(ConnectionError::ConnectionClosed)?;
+                let frame = match frame? {
+                    ServerInternal::Ack { request, message } => {
+                        match pending.remove(&request) {
+                            Some(answer_tx) => {
+                                let _ = answer_tx.send(Ok(message));
+                            }
+                            None => warn!("Ack for unknown request {}", request),
+                        }
+                        continue;
+                    }
+                    ServerInternal::Error(error) => {
+                        match error.request.and_then(|request| pending.remove(&request)) {
+                            Some(answer_tx) => {
+                                let _ = answer_tx.send(Err(error));
+                                continue;
+                            }
+                            None => ServerInternal::Error(error),
+                        }
+                    }
+                    frame => frame,
+                };
+                if events_tx.send(frame).await.is_err() {
+                    debug!("Nobody is listening for events anymore");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::messages::ErrorCode;
+    use crate::connection::Connection;
+
+    #[tokio::test]
+    async fn answers_go_to_the_request_they_answer() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (reader, writer) = Connection::from_stream(client).split_into();
+        let (session, mut events) = Session::start(reader, writer);
+        let mut server = Connection::from_stream(server);
+
+        let ping = tokio::spawn({
+            let session = session.clone();
+            async move { session.request(ClientMessage::Ping(1)).await }
+        });
+        let first: ClientRequest = server.read_frame().await.unwrap();
+        let join = tokio::spawn({
+            let session = session.clone();
+            async move {
+                session
+                    .request(ClientMessage::JoinRoom("lobby".into()))
+                    .await
+            }
+        });
+        let second: ClientRequest = server.read_frame().await.unwrap();
+        assert_ne!(first.id, second.id);
+
+        // Answered out of order, with an unrelated frame in between.
+        let error = ErrorMessage::new(ErrorCode::RoomNotFound, "").for_request(second.id);
+        server
+            .write_frame(&ServerInternal::Error(error))
+            .await
+            .unwrap();
+        server.write_frame(&ServerInternal::Pong(1)).await.unwrap();
+        let ack = ServerInternal::Ack {
+            request: first.id,
+            message: None,
+        };
+        server.write_frame(&ack).await.unwrap();
+
+        assert_eq!(ping.await.unwrap().unwrap(), None);
+        let joined = join.await.unwrap();
+        assert!(
+            matches!(joined, Err(ClientError::Request(e)) if e.code == ErrorCode::RoomNotFound)
+        );
+        assert!(matches!(events.recv().await, Some(ServerInternal::Pong(1))));
+    }
+}
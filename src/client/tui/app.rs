@@ -0,0 +1,764 @@
+use super::input::Input;
+use crate::client::{parse_command, ClientError, Result, VALID_COMMANDS};
+use crate::common::messages::{ClientMessage, ErrorMessage, Moderation, ServerInternal};
+use crate::common::{
+    ChatRecord, ChatTarget, MessageId, Presence, Reactions, RoomName, Timestamp, UserName,
+    UserStatus,
+};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Number of lines moved by a single scroll.
+const SCROLL_STEP: usize = 5;
+
+/// The spans of a message line after its time and sender, which edits, deletions and reactions
+/// replace.
+const CONTENT: usize = 2;
+const EDITED: usize = 3;
+const REACTIONS: usize = 4;
+
+/// How often the server is told again that the user is still typing, well within
+/// [`TYPING_TIMEOUT`](crate::common::TYPING_TIMEOUT).
+const TYPING_RENEWAL: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Global,
+    Room(RoomName),
+    Private(UserName),
+}
+
+impl Conversation {
+    /// Where messages typed in this conversation go, for those sent to one place.
+    fn target(&self) -> Option<ChatTarget> {
+        match self {
+            Conversation::Global => None,
+            Conversation::Room(room) => Some(ChatTarget::Room(room.clone())),
+            Conversation::Private(user) => Some(ChatTarget::Private(user.clone())),
+        }
+    }
+
+    pub fn title(&self) -> String {
+        match self {
+            Conversation::Global => "Global".to_string(),
+            Conversation::Room(room) => format!("#{}", room),
+            Conversation::Private(user) => format!("@{}", user),
+        }
+    }
+}
+
+pub struct Tab {
+    pub conversation: Conversation,
+    pub lines: Vec<Line<'static>>,
+    /// Number of lines scrolled up from the bottom of the conversation.
+    pub scroll: usize,
+    pub unread: bool,
+    /// The line showing each message, to apply edits and deletions to.
+    messages: HashMap<MessageId, usize>,
+    /// The last message we sent to the conversation, which `:edit` and `:delete` apply to
+    /// unless given an ID.
+    last_sent: Option<MessageId>,
+}
+
+impl Tab {
+    fn new(conversation: Conversation) -> Self {
+        Self {
+            conversation,
+            lines: Vec::new(),
+            scroll: 0,
+            unread: false,
+            messages: HashMap::new(),
+            last_sent: None,
+        }
+    }
+}
+
+/// State of the terminal UI. Frames from the server and key presses are applied to it, and
+/// [`super::ui::draw`] renders it.
+pub struct App {
+    pub user: UserName,
+    pub tabs: Vec<Tab>,
+    pub active: usize,
+    pub online_users: Vec<UserName>,
+    pub room_users: HashMap<RoomName, Vec<UserName>>,
+    /// The presence of the users we know of, from the user list and presence changes.
+    pub presence: HashMap<UserName, UserStatus>,
+    /// Who is typing, and where.
+    typing: HashSet<(Conversation, UserName)>,
+    /// Where the server was last told we are typing, and when.
+    typing_sent: Option<(Conversation, Instant)>,
+    pub input: Input,
+    pub should_quit: bool,
+    /// Printed once the terminal is restored, e.g. why the server closed the connection.
+    pub exit_message: Option<String>,
+    /// Room we asked to join or create, focused as soon as its tab opens.
+    pending_room: Option<RoomName>,
+}
+
+impl App {
+    pub fn new(user: UserName) -> Self {
+        Self {
+            user,
+            tabs: vec![Tab::new(Conversation::Global)],
+            active: 0,
+            online_users: Vec::new(),
+            room_users: HashMap::new(),
+            presence: HashMap::new(),
+            typing: HashSet::new(),
+            typing_sent: None,
+            input: Input::default(),
+            should_quit: false,
+            exit_message: None,
+            pending_room: None,
+        }
+    }
+
+    pub fn active_tab(&self) -> &Tab {
+        &self.tabs[self.active]
+    }
+
+    /// Rooms the user has a tab open for.
+    pub fn joined_rooms(&self) -> Vec<RoomName> {
+        self.tabs
+            .iter()
+            .filter_map(|tab| match &tab.conversation {
+                Conversation::Room(room) => Some(room.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Who is typing in the conversation of the active tab, sorted by name.
+    pub fn typing_here(&self) -> Vec<UserName> {
+        let conversation = &self.active_tab().conversation;
+        let mut users: Vec<_> = self
+            .typing
+            .iter()
+            .filter(|(c, _)| c == conversation)
+            .map(|(_, user)| user.clone())
+            .collect();
+        users.sort_by(|a, b| a.user_name().cmp(b.user_name()));
+        users
+    }
+
+    fn tab_index(&mut self, conversation: Conversation) -> usize {
+        match self
+            .tabs
+            .iter()
+            .position(|tab| tab.conversation == conversation)
+        {
+            Some(index) => index,
+            None => {
+                let focus = matches!(&conversation, Conversation::Room(room) if self.pending_room.as_ref() == Some(room));
+                self.tabs.push(Tab::new(conversation));
+                let index = self.tabs.len() - 1;
+                if focus {
+                    self.pending_room = None;
+                    self.active = index;
+                }
+                index
+            }
+        }
+    }
+
+    fn push(&mut self, conversation: Conversation, line: Line<'static>) {
+        let index = self.tab_index(conversation);
+        let tab = &mut self.tabs[index];
+        tab.lines.push(line);
+        if tab.scroll > 0 {
+            // Keep the view still while the user is reading older messages.
+            tab.scroll += 1;
+        }
+        if index != self.active {
+            tab.unread = true;
+        }
+    }
+
+    /// Push the line showing a message, remembering where it is.
+    fn push_message(&mut self, conversation: Conversation, id: MessageId, line: Line<'static>) {
+        let index = self.tab_index(conversation);
+        self.push(self.tabs[index].conversation.clone(), line);
+        let tab = &mut self.tabs[index];
+        tab.messages.insert(id, tab.lines.len() - 1);
+    }
+
+    /// Replace parts of a message line wherever the message is shown.
+    fn update_message(&mut self, id: MessageId, parts: &[(usize, Span<'static>)]) {
+        for tab in &mut self.tabs {
+            if let Some(line) = tab
+                .messages
+                .get(&id)
+                .and_then(|&index| tab.lines.get_mut(index))
+            {
+                if line.spans.len() <= REACTIONS {
+                    line.spans.resize(REACTIONS + 1, Span::raw(""));
+                }
+                for (part, span) in parts {
+                    line.spans[*part] = span.clone();
+                }
+            }
+        }
+    }
+
+    fn push_active(&mut self, line: Line<'static>) {
+        let conversation = self.active_tab().conversation.clone();
+        self.push(conversation, line);
+    }
+
+    pub fn handle_frame(&mut self, frame: ServerInternal) {
+        match frame {
+            ServerInternal::ServerMessage(content) => {
+                self.push(
+                    Conversation::Global,
+                    line(vec!["Server: ".red().bold(), content.red()]),
+                );
+            }
+            ServerInternal::ChatMessage(content) => {
+                self.push(Conversation::Global, line(vec![content.into()]));
+            }
+            ServerInternal::GlobalChatMessage {
+                id,
+                from_user,
+                content,
+                ..
+            } => {
+                self.push_message(Conversation::Global, id, chat_line(&from_user, content));
+            }
+            ServerInternal::PrivateMessage {
+                id,
+                from_user,
+                content,
+                ..
+            } => {
+                let conversation = Conversation::Private(from_user.clone());
+                self.typing
+                    .remove(&(conversation.clone(), from_user.clone()));
+                self.push_message(conversation, id, chat_line(&from_user, content));
+            }
+            ServerInternal::PrivateMessageQueued { to_user } => {
+                self.push(
+                    Conversation::Private(to_user.clone()),
+                    line(vec![format!(
+                        "{} is offline, they will get your message when they log in",
+                        to_user
+                    )
+                    .dark_gray()]),
+                );
+            }
+            ServerInternal::OfflineMessages { messages } => {
+                for message in messages {
+                    self.push_message(
+                        Conversation::Private(message.from_user.clone()),
+                        message.id,
+                        replayed_line(message, Style::default()),
+                    );
+                }
+            }
+            ServerInternal::UserJoined(user) => {
+                self.push(
+                    Conversation::Global,
+                    line(vec![format!("{} joined", user).yellow()]),
+                );
+            }
+            ServerInternal::NickChanged { from, to } => {
+                if from == self.user {
+                    self.user = to.clone();
+                }
+                let lists = self.room_users.values_mut().chain([&mut self.online_users]);
+                for user in lists.flatten().filter(|user| **user == from) {
+                    *user = to.clone();
+                }
+                self.typing.retain(|(_, user)| *user != from);
+                if let Some(mut status) = self.presence.remove(&from) {
+                    status.user = to.clone();
+                    self.presence.insert(to.clone(), status);
+                }
+                self.push(
+                    Conversation::Global,
+                    line(vec![format!("{} is now known as {}", from, to).yellow()]),
+                );
+            }
+            ServerInternal::UserList { users } => {
+                self.online_users = users.iter().map(|status| status.user.clone()).collect();
+                self.presence = users
+                    .into_iter()
+                    .map(|status| (status.user.clone(), status))
+                    .collect();
+            }
+            ServerInternal::PresenceChanged(status) => {
+                if status.user == self.user {
+                    let text = match &status.text {
+                        Some(text) => format!("You are {}: {}", status.presence, text),
+                        None => format!("You are {}", status.presence),
+                    };
+                    self.push(Conversation::Global, line(vec![text.yellow()]));
+                }
+                self.presence.insert(status.user.clone(), status);
+            }
+            ServerInternal::Error(error) => self.push_active(error_line(&error)),
+            // Answers to our requests are picked up by the session, this is never sent to us.
+            ServerInternal::Ack { .. } => {}
+            ServerInternal::Pong(nonce) => {
+                self.push_active(line(vec![format!("Pong: {}", nonce).yellow()]));
+            }
+            ServerInternal::RoomMessage {
+                room,
+                id,
+                from,
+                content,
+                ..
+            } => {
+                let conversation = Conversation::Room(room);
+                self.typing.remove(&(conversation.clone(), from.clone()));
+                self.push_message(conversation, id, chat_line(&from, content));
+            }
+            ServerInternal::MessageEdited(message) => {
+                let reactions = reactions_span(&message.reactions);
+                self.update_message(
+                    message.id,
+                    &[
+                        (CONTENT, message.content.into()),
+                        (EDITED, " (edited)".dark_gray()),
+                        (REACTIONS, reactions),
+                    ],
+                );
+            }
+            ServerInternal::MessageDeleted { id, by, .. } => {
+                self.update_message(
+                    id,
+                    &[
+                        (CONTENT, format!("deleted by {}", by).dark_gray().italic()),
+                        (EDITED, Span::raw("")),
+                        (REACTIONS, Span::raw("")),
+                    ],
+                );
+            }
+            ServerInternal::ReactionsChanged { id, reactions, .. } => {
+                self.update_message(id, &[(REACTIONS, reactions_span(&reactions))]);
+            }
+            ServerInternal::Typing { user, room, typing } => {
+                let conversation = match room {
+                    Some(room) => Conversation::Room(room),
+                    None => Conversation::Private(user.clone()),
+                };
+                if typing {
+                    self.typing.insert((conversation, user));
+                } else {
+                    self.typing.remove(&(conversation, user));
+                }
+            }
+            ServerInternal::RoomUsers { room, users } => {
+                self.room_users.insert(room, users);
+            }
+            ServerInternal::RoomList { rooms } => {
+                if rooms.is_empty() {
+                    self.push_active(line(vec!["No rooms".yellow()]));
+                }
+                for room in rooms {
+                    self.push_active(line(vec![room.to_string().yellow()]));
+                    if let Some(description) = room.description {
+                        self.push_active(line(vec![format!("  {}", description).dark_gray()]));
+                    }
+                }
+            }
+            ServerInternal::RoomRoles {
+                room,
+                owner,
+                moderators,
+            } => {
+                let moderators = match moderators.is_empty() {
+                    true => "none".to_string(),
+                    false => moderators
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                };
+                self.push(
+                    Conversation::Room(room),
+                    line(vec![format!(
+                        "Owner: {}, Moderators: {}",
+                        owner, moderators
+                    )
+                    .yellow()]),
+                );
+            }
+            ServerInternal::Moderated { room, by, action } => {
+                let conversation = Conversation::Room(room.clone());
+                let text = format!("{} by {}", action, by);
+                match action {
+                    // We are out of the room, the tab has nothing more to show.
+                    Moderation::Kicked { .. } | Moderation::Banned { .. } => {
+                        self.remove_tab(&conversation);
+                        self.room_users.remove(&room);
+                        self.push_active(line(vec![format!("#{}: {}", room, text).red().bold()]));
+                    }
+                    _ => self.push(conversation, line(vec![text.red().bold()])),
+                }
+            }
+            ServerInternal::RoomInvite { room, by } => {
+                self.push(
+                    Conversation::Global,
+                    line(vec![format!("{} invited you to #{}", by, room)
+                        .green()
+                        .bold()]),
+                );
+            }
+            ServerInternal::RoomDeleted { room } => {
+                self.remove_tab(&Conversation::Room(room.clone()));
+                self.room_users.remove(&room);
+                self.push_active(line(vec![format!("#{} was deleted", room).red().bold()]));
+            }
+            ServerInternal::Announcement { from_user, content } => {
+                self.push(
+                    Conversation::Global,
+                    line(vec![
+                        format!("Announcement from {}: ", from_user).blue().bold(),
+                        content.bold(),
+                    ]),
+                );
+            }
+            frame @ (ServerInternal::ServerShutdown { .. }
+            | ServerInternal::Disconnected { .. }) => {
+                self.exit_message = Some(frame.to_string());
+                self.should_quit = true;
+            }
+            ServerInternal::RoomHistory { room, messages } => {
+                let conversation = Conversation::Room(room);
+                for message in messages {
+                    let id = message.id;
+                    let line = replayed_line(message, Style::default().dark_gray());
+                    self.push_message(conversation.clone(), id, line);
+                }
+            }
+        }
+    }
+
+    /// Apply the server's answer to one of our requests.
+    pub fn handle_answer(&mut self, request: ClientMessage, result: Result<Option<MessageId>>) {
+        match result {
+            Ok(id) => match request {
+                ClientMessage::JoinRoom(room)
+                | ClientMessage::JoinRoomWithPassword { room, .. } => {
+                    let index = self.tab_index(Conversation::Room(room));
+                    self.select_tab(index);
+                }
+                // The server does not echo global and private messages back to their sender,
+                // they are shown once it took them.
+                ClientMessage::GlobalChatMessage(content) => {
+                    self.push_sent(Conversation::Global, id, content);
+                }
+                ClientMessage::PrivateMessage { to_user, content } => {
+                    self.push_sent(Conversation::Private(to_user), id, content);
+                }
+                ClientMessage::RoomMessage { room, .. } => {
+                    let index = self.tab_index(Conversation::Room(room));
+                    self.tabs[index].last_sent = id;
+                }
+                _ => {}
+            },
+            // The indicator is a nicety, it is not worth bothering the user about.
+            Err(_) if matches!(request, ClientMessage::Typing { .. }) => {}
+            Err(e) => {
+                if let ClientMessage::CreateRoom(_)
+                | ClientMessage::JoinRoom(_)
+                | ClientMessage::CreateRoomWithMode { .. }
+                | ClientMessage::JoinRoomWithPassword { .. } = request
+                {
+                    self.pending_room = None;
+                }
+                let line = match e {
+                    ClientError::Request(error) => error_line(&error),
+                    e => line(vec![format!("Request failed: {}", e).red()]),
+                };
+                self.push_active(line);
+            }
+        }
+    }
+
+    /// Show a message we sent that the server does not send back to us.
+    fn push_sent(&mut self, conversation: Conversation, id: Option<MessageId>, content: String) {
+        let line = chat_line(&self.user, content);
+        let Some(id) = id else {
+            return self.push(conversation, line);
+        };
+        self.push_message(conversation.clone(), id, line);
+        let index = self.tab_index(conversation);
+        self.tabs[index].last_sent = Some(id);
+    }
+
+    /// `:edit <text>` and `:delete` without an ID, which apply to the last message we sent to
+    /// the conversation of the active tab.
+    fn last_sent_command(&self, text: &str) -> Option<ClientMessage> {
+        let (command, rest) = text.split_once(' ').unwrap_or((text, ""));
+        let rest = rest.trim();
+        let id = self.active_tab().last_sent?;
+        let first = rest.split(' ').next().unwrap_or_default();
+        match command {
+            ":edit" if !rest.is_empty() && first.parse::<MessageId>().is_err() => {
+                Some(ClientMessage::EditMessage {
+                    id,
+                    content: rest.to_string(),
+                })
+            }
+            ":delete" if rest.is_empty() => Some(ClientMessage::DeleteMessage(id)),
+            _ => None,
+        }
+    }
+
+    /// Apply a key press, returning the message to send to the server if there is one.
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<ClientMessage> {
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => {
+                self.should_quit = true;
+                return Some(ClientMessage::Disconnect);
+            }
+            KeyCode::Char('w') if ctrl => return self.close_tab(),
+            KeyCode::Char('u') if ctrl => {
+                self.input.clear();
+                return self.typing_changed();
+            }
+            KeyCode::Char(c) => {
+                self.input.insert(c);
+                return self.typing_changed();
+            }
+            KeyCode::Enter => return self.submit(),
+            KeyCode::Backspace => {
+                self.input.backspace();
+                return self.typing_changed();
+            }
+            KeyCode::Delete => {
+                self.input.delete();
+                return self.typing_changed();
+            }
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.history_previous(),
+            KeyCode::Down => self.input.history_next(),
+            KeyCode::Tab => self.select_tab((self.active + 1) % self.tabs.len()),
+            KeyCode::BackTab => {
+                self.select_tab((self.active + self.tabs.len() - 1) % self.tabs.len())
+            }
+            KeyCode::PageUp => self.scroll_up(),
+            KeyCode::PageDown => self.scroll_down(),
+            _ => {}
+        }
+        None
+    }
+
+    /// Tell the server whether we are typing in the active tab, after the input changed. Only
+    /// rooms and private conversations show it, and never for commands.
+    fn typing_changed(&mut self) -> Option<ClientMessage> {
+        let conversation = self.active_tab().conversation.clone();
+        let text = self.input.text();
+        let typing = !text.trim().is_empty()
+            && !text.starts_with(':')
+            && conversation != Conversation::Global;
+        if !typing {
+            let (sent_to, _) = self.typing_sent.take()?;
+            return Some(ClientMessage::Typing {
+                target: sent_to.target()?,
+                typing: false,
+            });
+        }
+        let renew = match &self.typing_sent {
+            Some((sent_to, sent_at)) => {
+                *sent_to != conversation || sent_at.elapsed() >= TYPING_RENEWAL
+            }
+            None => true,
+        };
+        if !renew {
+            return None;
+        }
+        let target = conversation.target()?;
+        self.typing_sent = Some((conversation, Instant::now()));
+        Some(ClientMessage::Typing { target, typing })
+    }
+
+    fn select_tab(&mut self, index: usize) {
+        self.active = index;
+        self.tabs[index].unread = false;
+    }
+
+    /// Close the active tab, leaving the room if it is a room tab. The global tab stays open.
+    fn close_tab(&mut self) -> Option<ClientMessage> {
+        let conversation = self.active_tab().conversation.clone();
+        match conversation {
+            Conversation::Global => None,
+            Conversation::Room(room) => {
+                self.remove_tab(&Conversation::Room(room.clone()));
+                Some(ClientMessage::LeaveRoom(room))
+            }
+            conversation => {
+                self.remove_tab(&conversation);
+                None
+            }
+        }
+    }
+
+    fn remove_tab(&mut self, conversation: &Conversation) {
+        if let Some(index) = self
+            .tabs
+            .iter()
+            .position(|tab| &tab.conversation == conversation)
+        {
+            self.tabs.remove(index);
+            if self.active >= index {
+                self.select_tab(self.active.saturating_sub(1));
+            }
+        }
+    }
+
+    fn scroll_up(&mut self) {
+        let tab = &mut self.tabs[self.active];
+        tab.scroll = (tab.scroll + SCROLL_STEP).min(tab.lines.len());
+    }
+
+    fn scroll_down(&mut self) {
+        let tab = &mut self.tabs[self.active];
+        tab.scroll = tab.scroll.saturating_sub(SCROLL_STEP);
+    }
+
+    /// Turn the input line into a message. Commands work from any tab, plain text is sent to
+    /// the conversation of the active tab.
+    fn submit(&mut self) -> Option<ClientMessage> {
+        let text = self.input.submit();
+        let text = text.trim();
+        // Sending the message tells the others we are done typing.
+        self.typing_sent = None;
+        if text.is_empty() {
+            return None;
+        }
+
+        let message = if let Some(message) = self.last_sent_command(text) {
+            message
+        } else if text.starts_with(':') {
+            match parse_command(text) {
+                Ok(message) => message,
+                Err(_) => {
+                    self.push_active(line(vec![format!(
+                        "Invalid command: {}. Valid commands: {}",
+                        text, VALID_COMMANDS
+                    )
+                    .red()]));
+                    return None;
+                }
+            }
+        } else {
+            match &self.active_tab().conversation {
+                Conversation::Global => ClientMessage::GlobalChatMessage(text.to_string()),
+                Conversation::Room(room) => ClientMessage::RoomMessage {
+                    room: room.clone(),
+                    content: text.to_string(),
+                },
+                Conversation::Private(user) => ClientMessage::PrivateMessage {
+                    to_user: user.clone(),
+                    content: text.to_string(),
+                },
+            }
+        };
+
+        match &message {
+            ClientMessage::PrivateMessage { to_user, .. } => {
+                let index = self.tab_index(Conversation::Private(to_user.clone()));
+                self.select_tab(index);
+            }
+            ClientMessage::CreateRoom(room)
+            | ClientMessage::JoinRoom(room)
+            | ClientMessage::CreateRoomWithMode { room, .. }
+            | ClientMessage::JoinRoomWithPassword { room, .. } => {
+                self.pending_room = Some(room.clone());
+            }
+            ClientMessage::LeaveRoom(room) => {
+                self.remove_tab(&Conversation::Room(room.clone()));
+            }
+            ClientMessage::Disconnect => self.should_quit = true,
+            _ => {}
+        }
+        Some(message)
+    }
+}
+
+/// A line prefixed with the time it was received.
+fn line(spans: Vec<Span<'static>>) -> Line<'static> {
+    let mut line = vec![format!("{} ", Timestamp::now()).dark_gray()];
+    line.extend(spans);
+    Line::from(line)
+}
+
+fn error_line(error: &ErrorMessage) -> Line<'static> {
+    let mut spans = vec!["Error: ".red().bold(), error.code.to_string().red()];
+    if !error.message.is_empty() {
+        spans.push(format!(" ({})", error.message).dark_gray());
+    }
+    line(spans)
+}
+
+fn chat_line(from_user: &UserName, content: String) -> Line<'static> {
+    line(vec![
+        Span::styled(
+            format!("{}: ", from_user),
+            Style::default()
+                .fg(user_color(from_user))
+                .add_modifier(Modifier::BOLD),
+        ),
+        content.into(),
+    ])
+}
+
+/// A message sent before we were around to see it, shown with the time it was sent.
+fn replayed_line(message: ChatRecord, style: Style) -> Line<'static> {
+    let edited = match message.edited {
+        Some(_) => " (edited)".dark_gray(),
+        None => Span::raw(""),
+    };
+    Line::from(vec![
+        format!("{} ", message.timestamp).dark_gray(),
+        format!("{}: ", message.from_user).dark_gray(),
+        Span::styled(message.content, style),
+        edited,
+        reactions_span(&message.reactions),
+    ])
+}
+
+fn reactions_span(reactions: &Reactions) -> Span<'static> {
+    if reactions.is_empty() {
+        return Span::raw("");
+    }
+    format!("  {}", reactions).magenta()
+}
+
+/// How the sidebar marks a user who is not simply online.
+pub fn presence_marker(status: Option<&UserStatus>) -> Option<&'static str> {
+    match status.map(|status| status.presence) {
+        Some(Presence::Away) => Some(" (away)"),
+        Some(Presence::Busy) => Some(" (busy)"),
+        Some(Presence::Online) | None => None,
+    }
+}
+
+/// Give every user a stable colour so conversations are easier to follow.
+pub fn user_color(user: &UserName) -> Color {
+    const COLORS: [Color; 6] = [
+        Color::Cyan,
+        Color::Green,
+        Color::Yellow,
+        Color::Magenta,
+        Color::Blue,
+        Color::LightRed,
+    ];
+    let hash = user.user_name().bytes().fold(0usize, |hash, b| {
+        hash.wrapping_mul(31).wrapping_add(b as usize)
+    });
+    COLORS[hash % COLORS.len()]
+}
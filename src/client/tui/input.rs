@@ -0,0 +1,103 @@
+/// A single line text input with cursor movement and a history of submitted lines.
+#[derive(Debug, Default)]
+pub struct Input {
+    text: Vec<char>,
+    /// Cursor position, counted in characters.
+    cursor: usize,
+    history: Vec<String>,
+    /// Position in `history` while browsing it, `None` while editing a new line.
+    history_index: Option<usize>,
+}
+
+impl Input {
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Replace the line with the previous entry in the history.
+    pub fn history_previous(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let index = match self.history_index {
+            Some(index) => index.saturating_sub(1),
+            None => self.history.len() - 1,
+        };
+        self.history_index = Some(index);
+        self.set_text(self.history[index].clone());
+    }
+
+    /// Replace the line with the next entry in the history, or an empty line past the end.
+    pub fn history_next(&mut self) {
+        match self.history_index {
+            Some(index) if index + 1 < self.history.len() => {
+                self.history_index = Some(index + 1);
+                self.set_text(self.history[index + 1].clone());
+            }
+            Some(_) => {
+                self.history_index = None;
+                self.clear();
+            }
+            None => {}
+        }
+    }
+
+    /// Take the current line, adding it to the history.
+    pub fn submit(&mut self) -> String {
+        let line = self.text();
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        self.history_index = None;
+        self.clear();
+        line
+    }
+
+    fn set_text(&mut self, text: String) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+}
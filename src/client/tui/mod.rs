@@ -0,0 +1,119 @@
+mod app;
+mod input;
+mod ui;
+
+use super::{Events, Result, Session};
+use crate::common::messages::ClientMessage;
+use crate::common::{MessageId, UserName};
+use crate::connection::ConnectionError;
+use app::App;
+
+use crossterm::event::{Event, EventStream};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use ratatui::{backend::CrosstermBackend, Terminal};
+use std::io::{stdout, Stdout};
+use tokio::time::{interval, Duration};
+use tracing::error;
+
+/// How often the user and room member lists in the sidebar are refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+type Tui = Terminal<CrosstermBackend<Stdout>>;
+
+/// Run the terminal UI until the user quits or the connection is closed.
+pub(super) async fn run(user: UserName, session: Session, events: Events) -> Result<()> {
+    let mut terminal = setup_terminal()?;
+    let mut app = App::new(user);
+    let result = event_loop(&mut terminal, &mut app, session, events).await;
+    restore_terminal(&mut terminal)?;
+    if let Some(message) = app.exit_message {
+        println!("{}", message);
+    }
+    result
+}
+
+fn setup_terminal() -> Result<Tui> {
+    enable_raw_mode()?;
+    execute!(stdout(), EnterAlternateScreen)?;
+    // Leave the terminal usable if we panic while the UI is up.
+    let panic_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(), LeaveAlternateScreen);
+        panic_hook(info);
+    }));
+    Ok(Terminal::new(CrosstermBackend::new(stdout()))?)
+}
+
+fn restore_terminal(terminal: &mut Tui) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+/// Send a request, resolving to it along with the server's answer.
+fn request(
+    session: &Session,
+    message: ClientMessage,
+) -> BoxFuture<'static, (ClientMessage, Result<Option<MessageId>>)> {
+    let session = session.clone();
+    async move {
+        let result = session.request(message.clone()).await;
+        (message, result)
+    }
+    .boxed()
+}
+
+async fn event_loop(
+    terminal: &mut Tui,
+    app: &mut App,
+    session: Session,
+    mut events: Events,
+) -> Result<()> {
+    let mut input = EventStream::new();
+    let mut refresh = interval(REFRESH_INTERVAL);
+    let mut requests = FuturesUnordered::new();
+
+    while !app.should_quit {
+        terminal.draw(|frame| ui::draw(frame, app))?;
+
+        tokio::select! {
+            Some(event) = input.next() => {
+                if let Event::Key(key) = event? {
+                    match app.handle_key(key) {
+                        // The loop ends right away, make sure the server heard about it first.
+                        Some(message @ ClientMessage::Disconnect) => {
+                            let _ = session.request(message).await;
+                        }
+                        Some(message) => requests.push(request(&session, message)),
+                        None => {}
+                    }
+                }
+            }
+            frame = events.recv() => {
+                match frame {
+                    Some(frame) => app.handle_frame(frame),
+                    None => {
+                        error!("Connection closed");
+                        return Err(ConnectionError::ConnectionClosed.into());
+                    }
+                }
+            }
+            Some((message, result)) = requests.next() => app.handle_answer(message, result),
+            _ = refresh.tick() => {
+                requests.push(request(&session, ClientMessage::ListUsers));
+                for room in app.joined_rooms() {
+                    requests.push(request(&session, ClientMessage::ListRoomUsers(room)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
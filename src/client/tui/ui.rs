@@ -0,0 +1,166 @@
+use super::app::{presence_marker, user_color, App, Conversation};
+
+use ratatui::{
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Tabs, Wrap},
+    Frame,
+};
+
+const SIDEBAR_WIDTH: u16 = 24;
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [main, sidebar] = *Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+        .split(frame.size())
+    else {
+        return;
+    };
+    let [tabs, messages, input] = *Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(3),
+            Constraint::Length(3),
+        ])
+        .split(main)
+    else {
+        return;
+    };
+
+    draw_tabs(frame, app, tabs);
+    draw_messages(frame, app, messages);
+    draw_input(frame, app, input);
+    draw_sidebar(frame, app, sidebar);
+}
+
+fn draw_tabs(frame: &mut Frame, app: &App, area: Rect) {
+    let titles = app.tabs.iter().map(|tab| {
+        if tab.unread {
+            Line::from(format!("{}*", tab.conversation.title())).bold()
+        } else {
+            Line::from(tab.conversation.title())
+        }
+    });
+    let tabs = Tabs::new(titles)
+        .block(Block::default().borders(Borders::ALL).title(" Chat "))
+        .select(app.active)
+        .highlight_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+    frame.render_widget(tabs, area);
+}
+
+fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
+    let tab = app.active_tab();
+    let title = if tab.scroll > 0 {
+        format!(" {} (scrolled, PgDn to return) ", tab.conversation.title())
+    } else {
+        format!(" {} ", tab.conversation.title())
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+
+    let paragraph = Paragraph::new(Text::from(tab.lines.clone())).wrap(Wrap { trim: false });
+    // Lines wrap, so the scroll offset has to be worked out from the rendered height.
+    let height = paragraph.line_count(inner.width);
+    let bottom = height.saturating_sub(inner.height as usize);
+    let offset = bottom.saturating_sub(tab.scroll);
+
+    frame.render_widget(paragraph.block(block).scroll((offset as u16, 0)), area);
+}
+
+fn draw_input(frame: &mut Frame, app: &App, area: Rect) {
+    let typing = app.typing_here();
+    let title = match typing.as_slice() {
+        [] => " Message (Enter send, Tab switch, Ctrl-W close, Ctrl-C quit) ".to_string(),
+        [user] => format!(" {} is typing… ", user),
+        users => format!(
+            " {} are typing… ",
+            users
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+
+    // Scroll the input horizontally so the cursor stays visible.
+    let cursor = app.input.cursor() as u16;
+    let offset = cursor.saturating_sub(inner.width.saturating_sub(1));
+    let input = Paragraph::new(app.input.text())
+        .block(block)
+        .scroll((0, offset));
+    frame.render_widget(input, area);
+    frame.set_cursor(inner.x + cursor - offset, inner.y);
+}
+
+fn draw_sidebar(frame: &mut Frame, app: &App, area: Rect) {
+    let [rooms, users] = *Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .split(area)
+    else {
+        return;
+    };
+
+    let active_room = match &app.active_tab().conversation {
+        Conversation::Room(room) => Some(room),
+        _ => None,
+    };
+
+    let mut room_names = app.joined_rooms();
+    for room in app.room_users.keys() {
+        if !room_names.contains(room) {
+            room_names.push(room.clone());
+        }
+    }
+    let room_items: Vec<ListItem> = room_names
+        .iter()
+        .map(|room| {
+            let members = app.room_users.get(room).map(Vec::len).unwrap_or_default();
+            let item = ListItem::new(format!("#{} ({})", room, members));
+            if Some(room) == active_room {
+                item.yellow().bold()
+            } else {
+                item
+            }
+        })
+        .collect();
+    frame.render_widget(
+        List::new(room_items).block(Block::default().borders(Borders::ALL).title(" Rooms ")),
+        rooms,
+    );
+
+    // In a room tab show who is in the room, everywhere else show everyone online.
+    let (title, user_names) = match active_room {
+        Some(room) => (
+            format!(" #{} ", room),
+            app.room_users.get(room).cloned().unwrap_or_default(),
+        ),
+        None => (" Online ".to_string(), app.online_users.clone()),
+    };
+    let user_items: Vec<ListItem> = user_names
+        .iter()
+        .map(|user| {
+            let mut spans = vec![Span::styled(
+                user.to_string(),
+                Style::default().fg(user_color(user)),
+            )];
+            if let Some(marker) = presence_marker(app.presence.get(user)) {
+                spans.push(Span::styled(marker, Style::default().fg(Color::DarkGray)));
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(user_items).block(Block::default().borders(Borders::ALL).title(title)),
+        users,
+    );
+}
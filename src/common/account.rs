@@ -0,0 +1,214 @@
+use super::messages::Authentication;
+use super::password::{hash_password, verify_password};
+use super::{CommonError, Result, UserName};
+
+use bincode::{config, Decode, Encode};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+use tracing::info;
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Account {
+    password_hash: String,
+}
+
+/// File backed store of registered accounts. The whole store is rewritten on every change, which
+/// is fine for the number of accounts a chat server like this one is expected to hold.
+#[derive(Debug)]
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: HashMap<UserName, Account>,
+}
+
+impl AccountStore {
+    /// Load the store from `path`, starting with an empty store if the file does not exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let accounts = if path.exists() {
+            let data = fs::read(&path)?;
+            let (accounts, _) = bincode::decode_from_slice(&data, config::standard())?;
+            accounts
+        } else {
+            HashMap::new()
+        };
+        info!("Loaded {} accounts from {}", accounts.len(), path.display());
+        Ok(Self { path, accounts })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, user_name: &UserName) -> bool {
+        self.accounts.contains_key(user_name)
+    }
+
+    /// Add an account whose password was hashed already and save the store, unless someone
+    /// registered the name in the meantime.
+    fn insert(&mut self, user_name: &UserName, account: Account) -> Result<()> {
+        if self.contains(user_name) {
+            return Err(CommonError::AccountExists(user_name.clone()));
+        }
+        self.accounts.insert(user_name.clone(), account);
+        self.save()
+    }
+
+    /// Write the store to a temporary file first and then move it into place, so a crash while
+    /// writing never leaves a truncated store behind.
+    pub fn save(&self) -> Result<()> {
+        let data = bincode::encode_to_vec(&self.accounts, config::standard())?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// The account store, shared between the user processor and the blocking tasks checking
+/// passwords.
+#[derive(Debug, Clone)]
+pub struct SharedAccountStore(Arc<Mutex<AccountStore>>);
+
+impl SharedAccountStore {
+    pub fn new(accounts: AccountStore) -> Self {
+        Self(Arc::new(Mutex::new(accounts)))
+    }
+
+    pub fn contains(&self, user_name: &UserName) -> bool {
+        self.lock().contains(user_name)
+    }
+
+    /// Check the credentials, registering a new account if asked to. Hashing is slow on purpose
+    /// and registering writes the store to disk, so this blocks and is meant to be run with
+    /// [`spawn_blocking`](tokio::task::spawn_blocking). The store is only locked to look up or
+    /// add the account, not while hashing.
+    pub fn authenticate(&self, user_name: &UserName, authentication: Authentication) -> Result<()> {
+        match authentication {
+            Authentication::Login { password } => {
+                let account = self
+                    .lock()
+                    .accounts
+                    .get(user_name)
+                    .cloned()
+                    .ok_or(CommonError::InvalidCredentials)?;
+                verify_password(&password, &account.password_hash)
+            }
+            Authentication::Register { password } => {
+                if self.contains(user_name) {
+                    return Err(CommonError::AccountExists(user_name.clone()));
+                }
+                let account = Account {
+                    password_hash: hash_password(&password)?,
+                };
+                self.lock().insert(user_name, account)
+            }
+        }
+    }
+
+    // The store is never left half-changed, so a panic elsewhere while it was locked is no reason
+    // to stop using it.
+    fn lock(&self) -> std::sync::MutexGuard<'_, AccountStore> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(password: &str) -> Authentication {
+        Authentication::Login {
+            password: password.to_string(),
+        }
+    }
+
+    fn register(password: &str) -> Authentication {
+        Authentication::Register {
+            password: password.to_string(),
+        }
+    }
+
+    fn open(dir: &tempfile::TempDir) -> SharedAccountStore {
+        SharedAccountStore::new(AccountStore::open(dir.path().join("accounts.bin")).unwrap())
+    }
+
+    #[test]
+    fn register_then_login() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = open(&dir);
+        let alice = UserName::new("alice");
+
+        accounts
+            .authenticate(&alice, register("correct horse"))
+            .unwrap();
+        assert!(accounts.contains(&alice));
+        accounts
+            .authenticate(&alice, login("correct horse"))
+            .unwrap();
+        assert!(matches!(
+            accounts.authenticate(&alice, login("wrong horse")),
+            Err(CommonError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn unknown_user_can_not_log_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = open(&dir);
+
+        assert!(matches!(
+            accounts.authenticate(&UserName::new("bob"), login("correct horse")),
+            Err(CommonError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn names_are_registered_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = open(&dir);
+        let alice = UserName::new("alice");
+
+        accounts
+            .authenticate(&alice, register("correct horse"))
+            .unwrap();
+        assert!(matches!(
+            accounts.authenticate(&UserName::new("Alice"), register("another horse")),
+            Err(CommonError::AccountExists(_))
+        ));
+        accounts
+            .authenticate(&alice, login("correct horse"))
+            .unwrap();
+    }
+
+    #[test]
+    fn short_passwords_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = open(&dir);
+        let alice = UserName::new("alice");
+
+        assert!(matches!(
+            accounts.authenticate(&alice, register("short")),
+            Err(CommonError::PasswordTooShort(_))
+        ));
+        assert!(!accounts.contains(&alice));
+    }
+
+    #[test]
+    fn accounts_are_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = UserName::new("alice");
+        open(&dir)
+            .authenticate(&alice, register("correct horse"))
+            .unwrap();
+
+        let accounts = open(&dir);
+        assert!(accounts.contains(&alice));
+        accounts
+            .authenticate(&alice, login("correct horse"))
+            .unwrap();
+    }
+}
@@ -1,24 +1,77 @@
 pub type Result<T> = std::result::Result<T, CommonError>;
 
 use super::{
-    messages::{ServerMessage, UserMessage},
-    RoomName, User, UserName,
+    format_duration,
+    messages::{ErrorCode, ErrorMessage, HistoryMessage, ServerMessage, UserMessage},
+    InvalidUserName, MessageId, RoomName, RoomPermission, Timestamp, User, UserName,
 };
+use std::{net::IpAddr, time::Duration};
 
 #[derive(Debug, derive_more::From)]
 pub enum CommonError {
     UserExists(UserName),
+    InvalidUserName(InvalidUserName),
     UserInRoom(User),
     UserNotExists(UserName),
-    UserNotInRoom(User),
+    UserNotInRoom(UserName),
     RoomExists(RoomName),
-    NoUsersInRoom,
-    RoomMessageNotSent,
+    /// The name can't be given to a room, for the reason given.
+    InvalidRoomName(String),
     RoomNotFound(RoomName),
+    AccountExists(UserName),
+    InvalidCredentials,
+    PasswordTooShort(usize),
+    /// The user's role in the room does not allow this.
+    PermissionDenied(RoomPermission),
+    /// The owner's role only changes by handing the room over to someone else.
+    OwnerRoleChange(RoomName),
+    /// Moderators may only act on users below their own role.
+    Outranked(UserName),
+    /// Banned until the given time, or for good.
+    Banned(RoomName, Option<Timestamp>),
+    /// Muted until the given time, or for good.
+    Muted(RoomName, Option<Timestamp>),
+    UserNotBanned(UserName),
+    UserNotMuted(UserName),
+    /// The user or their address is banned from the server, with the reason if one was given.
+    BannedFromServer(Option<String>),
+    AddressNotBanned(IpAddr),
+    /// The room is password-protected and the password given, if any, is wrong.
+    WrongRoomPassword(RoomName),
+    /// The room is invite-only and the user was not invited.
+    NotInvited(RoomName),
+    UserNotInvited(UserName),
+    /// The user's offline messages are at the limit, nothing more is kept for them.
+    MailboxFull(UserName),
+    /// No message with this ID is kept, or it is too old to be changed.
+    MessageNotFound(MessageId),
+    /// Only the sender of a message may edit it, or delete it outside of rooms they moderate.
+    NotMessageSender(MessageId),
+    /// Senders may only change their messages for this long, if at all.
+    EditWindowPassed(Option<Duration>),
+    /// The reaction is not an emoji or shortcode, for the reason given.
+    InvalidReaction(String),
+    /// The message already has [`MAX_REACTIONS`](super::MAX_REACTIONS) different reactions.
+    TooManyReactions(MessageId),
+    #[from]
+    Io(std::io::Error),
+    #[from]
+    BincodeDecode(bincode::error::DecodeError),
+    #[from]
+    BincodeEncode(bincode::error::EncodeError),
+    #[from]
+    PasswordHash(argon2::password_hash::Error),
+    /// A blocking task, such as checking a password, panicked or was cancelled.
+    #[from]
+    Join(tokio::task::JoinError),
     #[from]
     SendUserProcess(tokio::sync::mpsc::error::SendError<UserMessage>),
+    /// The user processor dropped a request without answering it.
+    #[from]
+    ReceiveUserProcess(tokio::sync::oneshot::error::RecvError),
+    SendUserProcessBroadcast(Box<tokio::sync::mpsc::error::SendError<ServerMessage>>),
     #[from]
-    SendUserProcessBroadcast(tokio::sync::mpsc::error::SendError<ServerMessage>),
+    SendHistoryProcess(tokio::sync::mpsc::error::SendError<HistoryMessage>),
 }
 
 //Error boilerplate
@@ -29,3 +82,178 @@ impl core::fmt::Display for CommonError {
 }
 
 impl std::error::Error for CommonError {}
+
+// Server messages are large enough to bloat every `Result` if they are not boxed.
+impl From<tokio::sync::mpsc::error::SendError<ServerMessage>> for CommonError {
+    fn from(e: tokio::sync::mpsc::error::SendError<ServerMessage>) -> Self {
+        CommonError::SendUserProcessBroadcast(Box::new(e))
+    }
+}
+
+/// What the client is told. Failures internal to the server are not detailed.
+impl From<&CommonError> for ErrorMessage {
+    fn from(e: &CommonError) -> Self {
+        match e {
+            CommonError::UserExists(user) => ErrorMessage::new(
+                ErrorCode::UserExists,
+                format!("{} is already connected", user),
+            ),
+            CommonError::InvalidUserName(reason) => {
+                ErrorMessage::new(ErrorCode::InvalidUserName, reason.to_string())
+            }
+            CommonError::UserInRoom(user) => ErrorMessage::new(
+                ErrorCode::AlreadyInRoom,
+                format!("{} is already in the room", user),
+            ),
+            CommonError::UserNotExists(user) => {
+                ErrorMessage::new(ErrorCode::UserNotFound, format!("{} is not online", user))
+            }
+            CommonError::UserNotInRoom(user) => {
+                ErrorMessage::new(ErrorCode::NotInRoom, format!("{} is not in the room", user))
+            }
+            CommonError::RoomExists(room) => ErrorMessage::new(
+                ErrorCode::RoomExists,
+                format!("a room named {} already exists", room),
+            ),
+            CommonError::InvalidRoomName(reason) => {
+                ErrorMessage::new(ErrorCode::InvalidRoomName, reason.clone())
+            }
+            CommonError::RoomNotFound(room) => ErrorMessage::new(
+                ErrorCode::RoomNotFound,
+                format!("there is no room named {}", room),
+            ),
+            CommonError::AccountExists(user) => ErrorMessage::new(
+                ErrorCode::AccountExists,
+                format!("an account named {} already exists", user),
+            ),
+            CommonError::InvalidCredentials => ErrorMessage::new(ErrorCode::InvalidCredentials, ""),
+            CommonError::PasswordTooShort(min) => ErrorMessage::new(
+                ErrorCode::PasswordTooShort,
+                format!("passwords must be at least {} characters long", min),
+            ),
+            CommonError::PermissionDenied(permission) => ErrorMessage::new(
+                ErrorCode::PermissionDenied,
+                format!(
+                    "only a room {} can {}",
+                    permission.required_role(),
+                    permission
+                ),
+            ),
+            CommonError::OwnerRoleChange(room) => ErrorMessage::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "the owner of {} has to make someone else the owner first",
+                    room
+                ),
+            ),
+            CommonError::Outranked(user) => ErrorMessage::new(
+                ErrorCode::PermissionDenied,
+                format!("{} has a role at least as high as yours", user),
+            ),
+            CommonError::Banned(room, until) => ErrorMessage::new(
+                ErrorCode::Banned,
+                format!("you are banned from {} {}", room, time_left(until)),
+            ),
+            CommonError::Muted(room, until) => ErrorMessage::new(
+                ErrorCode::Muted,
+                format!("you are muted in {} {}", room, time_left(until)),
+            ),
+            CommonError::UserNotBanned(user) => {
+                ErrorMessage::new(ErrorCode::InvalidRequest, format!("{} is not banned", user))
+            }
+            CommonError::UserNotMuted(user) => {
+                ErrorMessage::new(ErrorCode::InvalidRequest, format!("{} is not muted", user))
+            }
+            CommonError::WrongRoomPassword(room) => ErrorMessage::new(
+                ErrorCode::WrongRoomPassword,
+                format!("{} is password-protected", room),
+            ),
+            CommonError::NotInvited(room) => {
+                ErrorMessage::new(ErrorCode::NotInvited, format!("{} is invite-only", room))
+            }
+            CommonError::UserNotInvited(user) => ErrorMessage::new(
+                ErrorCode::InvalidRequest,
+                format!("{} is not invited", user),
+            ),
+            CommonError::MailboxFull(user) => ErrorMessage::new(
+                ErrorCode::MailboxFull,
+                format!("{} has too many messages waiting already", user),
+            ),
+            CommonError::MessageNotFound(id) => ErrorMessage::new(
+                ErrorCode::MessageNotFound,
+                format!("there is no message {}, or it is too old to change", id),
+            ),
+            CommonError::NotMessageSender(id) => ErrorMessage::new(
+                ErrorCode::PermissionDenied,
+                format!("{} was sent by someone else", id),
+            ),
+            CommonError::EditWindowPassed(window) => ErrorMessage::new(
+                ErrorCode::PermissionDenied,
+                match window {
+                    Some(window) => format!(
+                        "messages can only be changed for {} after sending them",
+                        format_duration(*window)
+                    ),
+                    None => "messages can't be changed once sent".to_string(),
+                },
+            ),
+            CommonError::InvalidReaction(reason) => {
+                ErrorMessage::new(ErrorCode::InvalidReaction, reason.clone())
+            }
+            CommonError::TooManyReactions(id) => ErrorMessage::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "{} has as many different reactions as a message can have",
+                    id
+                ),
+            ),
+            CommonError::BannedFromServer(reason) => {
+                ErrorMessage::new(ErrorCode::Banned, reason.clone().unwrap_or_default())
+            }
+            CommonError::AddressNotBanned(address) => ErrorMessage::new(
+                ErrorCode::InvalidRequest,
+                format!("{} is not banned", address),
+            ),
+            CommonError::Io(_)
+            | CommonError::BincodeDecode(_)
+            | CommonError::BincodeEncode(_)
+            | CommonError::PasswordHash(_)
+            | CommonError::Join(_)
+            | CommonError::SendUserProcess(_)
+            | CommonError::ReceiveUserProcess(_)
+            | CommonError::SendUserProcessBroadcast(_)
+            | CommonError::SendHistoryProcess(_) => ErrorMessage::new(ErrorCode::Internal, ""),
+        }
+    }
+}
+
+fn time_left(until: &Option<Timestamp>) -> String {
+    match until {
+        Some(until) => format!("for another {}", format_duration(until.remaining())),
+        None => "for good".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_told_by_code_without_internals() {
+        let (user, _user_rx) = User::new(UserName::new("alice"), 1);
+        let error = ErrorMessage::from(&CommonError::UserInRoom(user));
+        assert_eq!(error.code, ErrorCode::AlreadyInRoom);
+        assert_eq!(error.message, "alice is already in the room");
+        assert_eq!(error.request, None);
+
+        let banned = CommonError::Banned(RoomName::new("lobby"), None);
+        let error = ErrorMessage::from(&banned);
+        assert_eq!(error.code, ErrorCode::Banned);
+        assert_eq!(error.message, "you are banned from lobby for good");
+
+        let io = std::io::Error::other("disk on fire");
+        let error = ErrorMessage::from(&CommonError::Io(io));
+        assert_eq!(error.code, ErrorCode::Internal);
+        assert!(error.message.is_empty());
+    }
+}
@@ -0,0 +1,681 @@
+use super::{CommonError, Reaction, Reactions, Result, RoomName, Timestamp, UserName};
+
+use bincode::{config, Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display, Formatter},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// The next ID handed out by [`MessageId::next`].
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies a chat message. IDs are handed out by the server in increasing order, and carry on
+/// after the highest one in the history log when it restarts.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    pub fn next() -> Self {
+        Self(NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Make sure IDs handed out from now on are higher than `id`.
+    fn seen(id: MessageId) {
+        NEXT_MESSAGE_ID.fetch_max(id.0 + 1, Ordering::Relaxed);
+    }
+}
+
+impl Display for MessageId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Parses IDs as they are displayed, with or without the `#`.
+impl FromStr for MessageId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.strip_prefix('#').unwrap_or(s).parse().map(Self)
+    }
+}
+
+/// Where a chat message was sent to.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum ChatTarget {
+    Global,
+    Room(RoomName),
+    Private(UserName),
+}
+
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub struct ChatRecord {
+    pub id: MessageId,
+    pub timestamp: Timestamp,
+    pub from_user: UserName,
+    pub target: ChatTarget,
+    pub content: String,
+    /// When the sender last edited the message.
+    pub edited: Option<Timestamp>,
+    pub reactions: Reactions,
+}
+
+impl ChatRecord {
+    /// A new message, given the next ID.
+    pub fn new(from_user: UserName, target: ChatTarget, content: impl Into<String>) -> Self {
+        Self {
+            id: MessageId::next(),
+            timestamp: Timestamp::now(),
+            from_user,
+            target,
+            content: content.into(),
+            edited: None,
+            reactions: Reactions::default(),
+        }
+    }
+
+    /// Replace the content, as edited by the sender.
+    pub fn edit(&mut self, content: impl Into<String>) {
+        self.content = content.into();
+        self.edited = Some(Timestamp::now());
+    }
+
+    /// Whether `user` sent the message, less than `window` ago, and so may still edit or delete
+    /// it. Senders can't change their messages at all without a window.
+    pub fn check_sender(&self, user: &UserName, window: Option<Duration>) -> Result<()> {
+        if self.from_user != *user {
+            return Err(CommonError::NotMessageSender(self.id));
+        }
+        match window {
+            Some(window) if self.timestamp.elapsed() <= window => Ok(()),
+            _ => Err(CommonError::EditWindowPassed(window)),
+        }
+    }
+}
+
+/// An entry of the history log. Edits, deletions and reactions are appended like new messages,
+/// and applied to the message they refer to when the log is read back.
+#[derive(Encode, Decode)]
+enum LogEntry {
+    Message(ChatRecord),
+    Edited {
+        id: MessageId,
+        content: String,
+        at: Timestamp,
+    },
+    Deleted(MessageId),
+    Reacted {
+        id: MessageId,
+        user: UserName,
+        reaction: Reaction,
+        added: bool,
+    },
+}
+
+impl LogEntry {
+    /// The message the entry is or refers to.
+    fn id(&self) -> MessageId {
+        match self {
+            LogEntry::Message(record) => record.id,
+            LogEntry::Edited { id, .. } | LogEntry::Deleted(id) | LogEntry::Reacted { id, .. } => {
+                *id
+            }
+        }
+    }
+
+    /// Apply an edit or a reaction to the message it refers to.
+    fn amend(self, record: &mut ChatRecord) {
+        match self {
+            LogEntry::Edited { content, at, .. } => {
+                record.content = content;
+                record.edited = Some(at);
+            }
+            LogEntry::Reacted {
+                user,
+                reaction,
+                added,
+                ..
+            } => {
+                if added {
+                    record.reactions.add(reaction, user);
+                } else {
+                    record.reactions.remove(&reaction, &user);
+                }
+            }
+            LogEntry::Message(_) | LogEntry::Deleted(_) => {}
+        }
+    }
+}
+
+/// Write `entry` to `log`, prefixed with its length.
+fn write_entry(log: &mut impl Write, entry: &LogEntry) -> Result<()> {
+    let data = bincode::encode_to_vec(entry, config::standard())?;
+    log.write_all(&(data.len() as u32).to_le_bytes())?;
+    log.write_all(&data)?;
+    Ok(())
+}
+
+/// A message kept in memory, and why.
+struct Kept {
+    record: ChatRecord,
+    /// Among the last messages of its room, which are replayed to users joining it.
+    replayed: bool,
+    /// Sent less than `keep_for` ago, and among the last `max_kept` such messages.
+    recent: bool,
+}
+
+/// Append-only log of every chat message. Entries are written with the same length prefixed
+/// bincode encoding used for frames on the wire. The last `replay_limit` messages of each room are
+/// kept in memory so they can be replayed to users joining the room, and the last `max_kept`
+/// messages sent less than `keep_for` ago, so that edits, deletions and reactions can be checked
+/// against them.
+///
+/// Edits, deletions and reactions are appended as entries of their own. Each time the store is
+/// opened they are folded into the messages they refer to, and the log is rewritten with one entry
+/// per message that was not deleted, so it only grows with the messages themselves.
+pub struct HistoryStore {
+    path: PathBuf,
+    log: BufWriter<File>,
+    messages: HashMap<MessageId, Kept>,
+    /// The messages of each room that are replayed, oldest first.
+    rooms: HashMap<RoomName, VecDeque<MessageId>>,
+    replay_limit: usize,
+    /// Messages sent less than `keep_for` ago, oldest first. Deleted ones are skipped.
+    recent: VecDeque<MessageId>,
+    keep_for: Option<Duration>,
+    max_kept: usize,
+}
+
+impl HistoryStore {
+    pub fn open(
+        path: impl Into<PathBuf>,
+        replay_limit: usize,
+        keep_for: Option<Duration>,
+        max_kept: usize,
+    ) -> Result<Self> {
+        let path = path.into();
+        let mut store = Self {
+            log: BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?),
+            path,
+            messages: HashMap::new(),
+            rooms: HashMap::new(),
+            replay_limit,
+            recent: VecDeque::new(),
+            keep_for,
+            max_kept,
+        };
+        let records = store.load()?;
+        info!(
+            "Loaded {} history records from {}",
+            records,
+            store.path.display()
+        );
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the log back, skipping entries that can't be decoded, and compact it. A partly written
+    /// entry at the end is cut off, so that entries appended from now on can be read back as well.
+    fn load(&mut self) -> Result<usize> {
+        let mut data = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut data)?;
+
+        // Every message in the log, in order, and where to find it. `None` once deleted.
+        let mut messages: Vec<Option<ChatRecord>> = Vec::new();
+        let mut positions = HashMap::new();
+        let (mut changes, mut corrupt) = (0, 0);
+        let mut offset = 0;
+        while offset + 4 <= data.len() {
+            let size = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            let start = offset + 4;
+            if start + size > data.len() {
+                break;
+            }
+            let entry = &data[start..start + size];
+            offset = start + size;
+            let entry = match bincode::decode_from_slice(entry, config::standard()) {
+                Ok((entry, _)) => entry,
+                Err(e) => {
+                    warn!(
+                        "Skipping corrupt history entry at byte {}: {}",
+                        start - 4,
+                        e
+                    );
+                    corrupt += 1;
+                    continue;
+                }
+            };
+            match entry {
+                LogEntry::Message(record) => {
+                    MessageId::seen(record.id);
+                    positions.insert(record.id, messages.len());
+                    messages.push(Some(record));
+                }
+                entry => {
+                    changes += 1;
+                    let Some(&at) = positions.get(&entry.id()) else {
+                        continue;
+                    };
+                    if let LogEntry::Deleted(_) = entry {
+                        messages[at] = None;
+                    } else if let Some(record) = &mut messages[at] {
+                        entry.amend(record);
+                    }
+                }
+            }
+        }
+        let messages: Vec<ChatRecord> = messages.into_iter().flatten().collect();
+
+        if changes > 0 && corrupt == 0 {
+            info!("Compacting {} changes into the history log", changes);
+            self.compact(&messages)?;
+        } else if offset != data.len() {
+            // Most likely the server stopped while writing the last record.
+            warn!(
+                "Cutting off {} trailing bytes of the history log",
+                data.len() - offset
+            );
+            self.log.flush()?;
+            self.log.get_ref().set_len(offset as u64)?;
+        }
+        if changes > 0 && corrupt > 0 {
+            // Rewriting the log would lose what is left of the corrupt entries.
+            warn!("Not compacting the history log as it has corrupt entries");
+        }
+
+        let records = messages.len();
+        for record in messages {
+            self.keep(record);
+        }
+        Ok(records)
+    }
+
+    /// Replace the log with one holding just `messages`, through a temporary file so that the log
+    /// is never left half written.
+    fn compact(&mut self, messages: &[ChatRecord]) -> Result<()> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        let mut log = BufWriter::new(File::create(&path)?);
+        for record in messages {
+            write_entry(&mut log, &LogEntry::Message(record.clone()))?;
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        fs::rename(&path, &self.path)?;
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+
+    /// Keep a new message in memory for as long as it is replayed or recent.
+    fn keep(&mut self, record: ChatRecord) {
+        let id = record.id;
+        let room = match &record.target {
+            ChatTarget::Room(room) if self.replay_limit > 0 => Some(room.clone()),
+            _ => None,
+        };
+        let recent = self.keep_for.is_some() && self.max_kept > 0;
+        if room.is_none() && !recent {
+            return;
+        }
+        self.messages.insert(
+            id,
+            Kept {
+                record,
+                replayed: room.is_some(),
+                recent,
+            },
+        );
+        if let Some(room) = room {
+            let replayed = self.rooms.entry(room).or_default();
+            replayed.push_back(id);
+            let dropped = if replayed.len() > self.replay_limit {
+                replayed.pop_front()
+            } else {
+                None
+            };
+            if let Some(dropped) = dropped {
+                self.let_go(dropped, |kept| kept.replayed = false);
+            }
+        }
+        if recent {
+            self.recent.push_back(id);
+            self.expire_recent();
+        }
+    }
+
+    /// Stop keeping recent messages once they are older than `keep_for`, or once there are more
+    /// than `max_kept` of them.
+    fn expire_recent(&mut self) {
+        let Some(window) = self.keep_for else {
+            return;
+        };
+        while let Some(&id) = self.recent.front() {
+            let expired = self.recent.len() > self.max_kept
+                || self
+                    .messages
+                    .get(&id)
+                    .is_none_or(|kept| kept.record.timestamp.elapsed() > window);
+            if !expired {
+                break;
+            }
+            self.recent.pop_front();
+            self.let_go(id, |kept| kept.recent = false);
+        }
+    }
+
+    /// Forget one reason to keep a message, and the message itself if there is no other.
+    fn let_go(&mut self, id: MessageId, forget: impl FnOnce(&mut Kept)) {
+        if let Some(kept) = self.messages.get_mut(&id) {
+            forget(kept);
+            if !kept.replayed && !kept.recent {
+                self.messages.remove(&id);
+            }
+        }
+    }
+
+    fn apply(&mut self, entry: LogEntry) {
+        match entry {
+            LogEntry::Message(record) => self.keep(record),
+            LogEntry::Deleted(id) => {
+                let Some(kept) = self.messages.remove(&id) else {
+                    return;
+                };
+                if let (true, ChatTarget::Room(room)) = (kept.replayed, &kept.record.target) {
+                    if let Some(replayed) = self.rooms.get_mut(room) {
+                        replayed.retain(|replayed| *replayed != id);
+                    }
+                }
+            }
+            entry => {
+                if let Some(kept) = self.messages.get_mut(&entry.id()) {
+                    entry.amend(&mut kept.record);
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, entry: LogEntry) -> Result<()> {
+        write_entry(&mut self.log, &entry)?;
+        self.log.flush()?;
+        self.apply(entry);
+        Ok(())
+    }
+
+    pub fn record(&mut self, record: ChatRecord) -> Result<()> {
+        self.append(LogEntry::Message(record))
+    }
+
+    /// Replace the content of the message with the same ID as `record`, which was edited.
+    pub fn edit(&mut self, record: ChatRecord) -> Result<()> {
+        self.append(LogEntry::Edited {
+            id: record.id,
+            content: record.content,
+            at: record.edited.unwrap_or_else(Timestamp::now),
+        })
+    }
+
+    pub fn delete(&mut self, id: MessageId) -> Result<()> {
+        self.append(LogEntry::Deleted(id))
+    }
+
+    /// Add the reaction of `user` to a message, or take it back, returning the message with its
+    /// reactions as they are now. `None` if the message is no longer kept in memory.
+    pub fn react(
+        &mut self,
+        id: MessageId,
+        user: UserName,
+        reaction: Reaction,
+        added: bool,
+    ) -> Result<Option<ChatRecord>> {
+        let Some(record) = self.find(id) else {
+            return Ok(None);
+        };
+        // Adding a reaction twice or taking back one never added changes nothing.
+        if record.reactions.contains(&reaction, &user) != added {
+            self.append(LogEntry::Reacted {
+                id,
+                user,
+                reaction,
+                added,
+            })?;
+        }
+        Ok(self.find(id))
+    }
+
+    /// The message with this ID, if it is still kept in memory.
+    pub fn find(&self, id: MessageId) -> Option<ChatRecord> {
+        self.messages.get(&id).map(|kept| kept.record.clone())
+    }
+
+    /// Make sure everything recorded so far has reached the disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.log.flush()?;
+        self.log.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// The most recent messages sent to `room`, oldest first.
+    pub fn room_history(&self, room: &RoomName) -> Vec<ChatRecord> {
+        self.rooms
+            .get(room)
+            .map(|replayed| replayed.iter().filter_map(|id| self.find(*id)).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room() -> RoomName {
+        RoomName::new("lobby")
+    }
+
+    fn message(content: &str) -> ChatRecord {
+        ChatRecord::new(UserName::new("alice"), ChatTarget::Room(room()), content)
+    }
+
+    fn contents(store: &HistoryStore) -> Vec<String> {
+        store
+            .room_history(&room())
+            .into_iter()
+            .map(|record| record.content)
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_replayed_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let mut store = HistoryStore::open(&path, 2, None, 100).unwrap();
+        for content in ["one", "two", "three"] {
+            store.record(message(content)).unwrap();
+        }
+        store
+            .record(ChatRecord::new(
+                UserName::new("bob"),
+                ChatTarget::Global,
+                "elsewhere",
+            ))
+            .unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(&path, 2, None, 100).unwrap();
+        assert_eq!(contents(&store), ["two", "three"]);
+    }
+
+    #[test]
+    fn ids_carry_on_after_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let recorded = message("one");
+        let id = recorded.id;
+        HistoryStore::open(&path, 10, None, 100)
+            .unwrap()
+            .record(recorded)
+            .unwrap();
+
+        HistoryStore::open(&path, 10, None, 100).unwrap();
+        assert!(MessageId::next() > id);
+    }
+
+    #[test]
+    fn corrupt_entries_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let mut store = HistoryStore::open(&path, 10, None, 100).unwrap();
+        for content in ["one", "two", "three"] {
+            store.record(message(content)).unwrap();
+        }
+        drop(store);
+
+        // Make the second message invalid UTF-8, and leave half an entry at the end.
+        let mut data = std::fs::read(&path).unwrap();
+        let at = data.windows(3).position(|bytes| bytes == b"two").unwrap();
+        data[at] = 0xff;
+        data.extend_from_slice(&[64, 0, 0, 0, 1, 2, 3]);
+        std::fs::write(&path, &data).unwrap();
+
+        let mut store = HistoryStore::open(&path, 10, None, 100).unwrap();
+        assert_eq!(contents(&store), ["one", "three"]);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len() as usize,
+            data.len() - 7
+        );
+        store.record(message("four")).unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(&path, 10, None, 100).unwrap();
+        assert_eq!(contents(&store), ["one", "three", "four"]);
+    }
+
+    #[test]
+    fn edits_and_deletions_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let keep_for = Some(Duration::from_secs(60));
+        let mut store = HistoryStore::open(&path, 10, keep_for, 100).unwrap();
+        let mut edited = message("one");
+        let deleted = message("two");
+        let global = ChatRecord::new(UserName::new("bob"), ChatTarget::Global, "hello");
+        for record in [&edited, &deleted, &global] {
+            store.record(record.clone()).unwrap();
+        }
+        edited.edit("one, edited");
+        store.edit(edited.clone()).unwrap();
+        store.delete(deleted.id).unwrap();
+        let mut global_edited = global.clone();
+        global_edited.edit("hello again");
+        store.edit(global_edited).unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(&path, 10, keep_for, 100).unwrap();
+        assert_eq!(contents(&store), ["one, edited"]);
+        let replayed = store.find(edited.id).unwrap();
+        assert_eq!(replayed.edited, edited.edited);
+        assert!(store.find(deleted.id).is_none());
+        assert_eq!(store.find(global.id).unwrap().content, "hello again");
+    }
+
+    #[test]
+    fn reactions_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let mut store = HistoryStore::open(&path, 10, None, 100).unwrap();
+        let record = message("one");
+        let id = record.id;
+        store.record(record).unwrap();
+        let (bob, tada) = (UserName::new("bob"), Reaction::new(":tada:"));
+        store.react(id, bob.clone(), tada.clone(), true).unwrap();
+        store.react(id, bob.clone(), tada.clone(), true).unwrap();
+        store
+            .react(id, bob.clone(), Reaction::new("👍"), true)
+            .unwrap();
+        let reacted = store
+            .react(id, bob.clone(), Reaction::new("👍"), false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reacted.reactions.to_string(), ":tada: 1");
+        assert!(store
+            .react(MessageId::next(), bob.clone(), tada.clone(), true)
+            .unwrap()
+            .is_none());
+        drop(store);
+
+        let store = HistoryStore::open(&path, 10, None, 100).unwrap();
+        let replayed = store.find(id).unwrap();
+        assert_eq!(replayed.reactions.to_string(), ":tada: 1");
+        assert!(replayed.reactions.contains(&tada, &bob));
+    }
+
+    #[test]
+    fn changes_are_compacted_into_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let keep_for = Some(Duration::from_secs(60));
+        let mut store = HistoryStore::open(&path, 10, keep_for, 100).unwrap();
+        let mut edited = message("one");
+        let deleted = message("two");
+        store.record(edited.clone()).unwrap();
+        store.record(deleted.clone()).unwrap();
+        edited.edit("one, edited");
+        store.edit(edited.clone()).unwrap();
+        store.delete(deleted.id).unwrap();
+        let bob = UserName::new("bob");
+        store
+            .react(edited.id, bob.clone(), Reaction::new(":tada:"), true)
+            .unwrap();
+        drop(store);
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        let mut store = HistoryStore::open(&path, 10, keep_for, 100).unwrap();
+        let after = std::fs::metadata(&path).unwrap().len();
+        assert!(after < before);
+        store.record(message("three")).unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(&path, 10, keep_for, 100).unwrap();
+        assert_eq!(contents(&store), ["one, edited", "three"]);
+        let replayed = store.find(edited.id).unwrap();
+        assert_eq!(replayed.edited, edited.edited);
+        assert_eq!(replayed.reactions.to_string(), ":tada: 1");
+        assert!(store.find(deleted.id).is_none());
+    }
+
+    #[test]
+    fn recent_messages_are_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.log");
+        let keep_for = Some(Duration::from_secs(60));
+        let mut store = HistoryStore::open(&path, 1, keep_for, 2).unwrap();
+        let global: Vec<_> = ["one", "two", "three"]
+            .into_iter()
+            .map(|content| ChatRecord::new(UserName::new("bob"), ChatTarget::Global, content))
+            .collect();
+        let room = message("lobby");
+        store.record(room.clone()).unwrap();
+        for record in &global {
+            store.record(record.clone()).unwrap();
+        }
+
+        // The room message is still replayed, the oldest global one no longer kept.
+        assert_eq!(store.find(room.id).unwrap().content, "lobby");
+        assert!(store.find(global[0].id).is_none());
+        assert_eq!(store.find(global[2].id).unwrap().content, "three");
+        assert_eq!(store.messages.len(), 3);
+    }
+}
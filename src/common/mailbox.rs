@@ -0,0 +1,190 @@
+use super::{ChatRecord, CommonError, MessageId, Result, UserName};
+
+use bincode::config;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+type Mailboxes = HashMap<UserName, VecDeque<ChatRecord>>;
+
+/// File backed store of the private messages sent to registered users while they were offline,
+/// kept until they next log in. Like the account store it is rewritten on every change.
+#[derive(Debug)]
+pub struct MailboxStore {
+    path: PathBuf,
+    mailboxes: Mailboxes,
+    /// Messages kept for each user, further messages are refused.
+    capacity: usize,
+}
+
+impl MailboxStore {
+    /// Load the store from `path`, starting with an empty store if the file does not exist yet.
+    pub fn open(path: impl Into<PathBuf>, capacity: usize) -> Result<Self> {
+        let path = path.into();
+        let mailboxes = if path.exists() {
+            let data = fs::read(&path)?;
+            let (mailboxes, _) = bincode::decode_from_slice(&data, config::standard())?;
+            mailboxes
+        } else {
+            HashMap::new()
+        };
+        info!(
+            "Loaded {} queued messages from {}",
+            mailboxes.values().map(VecDeque::len).sum::<usize>(),
+            path.display()
+        );
+        Ok(Self {
+            path,
+            mailboxes,
+            capacity,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keep `record` for `user_name` until they log in.
+    pub fn push(&mut self, user_name: &UserName, record: ChatRecord) -> Result<()> {
+        let mailbox = self.mailboxes.entry(user_name.clone()).or_default();
+        if mailbox.len() >= self.capacity {
+            return Err(CommonError::MailboxFull(user_name.clone()));
+        }
+        mailbox.push_back(record);
+        self.save()
+    }
+
+    /// The messages kept for `user_name`, oldest first.
+    pub fn messages(&self, user_name: &UserName) -> Vec<ChatRecord> {
+        self.mailboxes
+            .get(user_name)
+            .map(|mailbox| mailbox.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Replace the message kept for `user_name` with the same ID as `record`, if there is one.
+    pub fn replace(&mut self, user_name: &UserName, record: &ChatRecord) -> Result<()> {
+        let Some(message) = self
+            .mailboxes
+            .get_mut(user_name)
+            .and_then(|mailbox| mailbox.iter_mut().find(|message| message.id == record.id))
+        else {
+            return Ok(());
+        };
+        *message = record.clone();
+        self.save()
+    }
+
+    /// Drop the message kept for `user_name` with this ID, if there is one.
+    pub fn remove(&mut self, user_name: &UserName, id: MessageId) -> Result<()> {
+        let Some(mailbox) = self.mailboxes.get_mut(user_name) else {
+            return Ok(());
+        };
+        let len = mailbox.len();
+        mailbox.retain(|message| message.id != id);
+        if mailbox.len() == len {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Empty the mailbox of `user_name` once its messages were delivered.
+    pub fn clear(&mut self, user_name: &UserName) -> Result<()> {
+        if self.mailboxes.remove(user_name).is_none() {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Write the store to a temporary file first and then move it into place, so a crash while
+    /// writing never leaves a truncated store behind.
+    pub fn save(&self) -> Result<()> {
+        let data = bincode::encode_to_vec(&self.mailboxes, config::standard())?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ChatTarget;
+
+    fn bob() -> UserName {
+        UserName::new("bob")
+    }
+
+    fn message(content: &str) -> ChatRecord {
+        ChatRecord::new(UserName::new("alice"), ChatTarget::Private(bob()), content)
+    }
+
+    fn contents(store: &MailboxStore, user_name: &UserName) -> Vec<String> {
+        store
+            .messages(user_name)
+            .into_iter()
+            .map(|record| record.content)
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_kept_until_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mailboxes.bin");
+        let mut store = MailboxStore::open(&path, 10).unwrap();
+        for content in ["one", "two"] {
+            store.push(&bob(), message(content)).unwrap();
+        }
+        drop(store);
+
+        let mut store = MailboxStore::open(&path, 10).unwrap();
+        assert_eq!(contents(&store, &bob()), ["one", "two"]);
+        store.clear(&bob()).unwrap();
+        drop(store);
+
+        let store = MailboxStore::open(&path, 10).unwrap();
+        assert!(store.messages(&bob()).is_empty());
+    }
+
+    #[test]
+    fn full_mailboxes_refuse_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = MailboxStore::open(dir.path().join("mailboxes.bin"), 2).unwrap();
+        store.push(&bob(), message("one")).unwrap();
+        store.push(&bob(), message("two")).unwrap();
+
+        let result = store.push(&bob(), message("three"));
+        assert!(matches!(result, Err(CommonError::MailboxFull(user)) if user == bob()));
+        assert_eq!(contents(&store, &bob()), ["one", "two"]);
+
+        let carol = UserName::new("carol");
+        store.push(&carol, message("one")).unwrap();
+        assert_eq!(contents(&store, &carol), ["one"]);
+    }
+
+    #[test]
+    fn messages_are_edited_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mailboxes.bin");
+        let mut store = MailboxStore::open(&path, 10).unwrap();
+        let first = message("one");
+        let second = message("two");
+        store.push(&bob(), first.clone()).unwrap();
+        store.push(&bob(), second.clone()).unwrap();
+
+        let mut edited = first.clone();
+        edited.content = "one, edited".to_string();
+        store.replace(&bob(), &edited).unwrap();
+        store.remove(&bob(), second.id).unwrap();
+        drop(store);
+
+        let store = MailboxStore::open(&path, 10).unwrap();
+        let messages = store.messages(&bob());
+        assert_eq!(contents(&store, &bob()), ["one, edited"]);
+        assert_eq!(messages[0].id, first.id);
+    }
+}
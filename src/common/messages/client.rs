@@ -1,15 +1,25 @@
-use crate::{common::RoomName, connection::FrameType};
+use crate::{
+    common::{
+        ChatTarget, MessageId, Presence, Reaction, RoomMode, RoomName, RoomPassword, RoomRole,
+    },
+    connection::FrameType,
+};
 
 use crate::common::UserName;
 
 use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug, Display, Formatter};
+use std::net::IpAddr;
 
 /// Messages sent by the client to the server
-#[derive(Debug, Clone, Decode, Encode)]
+#[derive(Debug, Clone, Decode, Encode, Serialize, Deserialize)]
 pub enum ClientMessage {
     GlobalChatMessage(String),
-    PrivateMessage { to_user: UserName, content: String },
+    PrivateMessage {
+        to_user: UserName,
+        content: String,
+    },
     Ping(u16),
     ListUsers,
     Disconnect,
@@ -18,10 +28,231 @@ pub enum ClientMessage {
     LeaveRoom(RoomName),
     ListRooms,
     ListRoomUsers(RoomName),
-    RoomMessage { room: RoomName, content: String },
+    RoomMessage {
+        room: RoomName,
+        content: String,
+    },
+    /// Change the role of a user in a room. Only the room owner may do this.
+    SetRoomRole {
+        room: RoomName,
+        user: UserName,
+        role: RoomRole,
+    },
+    ListRoomRoles(RoomName),
+    /// Remove a user from a room. Moderators may only act on users below their own role.
+    KickUser {
+        room: RoomName,
+        user: UserName,
+        reason: Option<String>,
+    },
+    /// Ban a user from a room, removing them if they are in it. Bans without a duration are
+    /// permanent.
+    BanUser {
+        room: RoomName,
+        user: UserName,
+        duration_secs: Option<u64>,
+        reason: Option<String>,
+    },
+    UnbanUser {
+        room: RoomName,
+        user: UserName,
+    },
+    /// Stop a user from sending messages to a room. Mutes without a duration are permanent.
+    MuteUser {
+        room: RoomName,
+        user: UserName,
+        duration_secs: Option<u64>,
+    },
+    UnmuteUser {
+        room: RoomName,
+        user: UserName,
+    },
+    /// Create a room that is not public, see [`RoomMode`].
+    CreateRoomWithMode {
+        room: RoomName,
+        mode: RoomMode,
+    },
+    /// Join a password-protected room.
+    JoinRoomWithPassword {
+        room: RoomName,
+        password: RoomPassword,
+    },
+    /// Change who may find and join a room. Only the room owner may do this.
+    SetRoomMode {
+        room: RoomName,
+        mode: RoomMode,
+    },
+    /// Let a user join a room whatever its mode. Only the room owner may do this.
+    InviteToRoom {
+        room: RoomName,
+        user: UserName,
+    },
+    UninviteFromRoom {
+        room: RoomName,
+        user: UserName,
+    },
+    /// Change the topic of a room, or clear it with `None`. Moderators and the owner may do this.
+    SetRoomTopic {
+        room: RoomName,
+        topic: Option<String>,
+    },
+    /// Change the description of a room, or clear it with `None`. Only the room owner may do
+    /// this.
+    SetRoomDescription {
+        room: RoomName,
+        description: Option<String>,
+    },
+    /// Delete a room, removing everyone from it. Only the room owner may do this.
+    DeleteRoom(RoomName),
+    /// A command acting on the whole server, only accepted from server admins.
+    Admin(AdminCommand),
+    /// Go by another name from now on, in every room the user is in.
+    ChangeNick(UserName),
+    /// Tell others whether the user is around, with optional status text. Clears the status text
+    /// when it is `None`.
+    SetPresence {
+        presence: Presence,
+        text: Option<String>,
+    },
+    /// The user started or stopped typing in a room or private conversation. Clients send
+    /// `typing: true` again every few seconds while the user keeps typing, the server stops
+    /// showing it after [`TYPING_TIMEOUT`] otherwise.
+    ///
+    /// [`TYPING_TIMEOUT`]: crate::common::TYPING_TIMEOUT
+    Typing {
+        target: ChatTarget,
+        typing: bool,
+    },
+    /// Replace the content of a message. Only its sender may do this, for a while after sending
+    /// it.
+    EditMessage {
+        id: MessageId,
+        content: String,
+    },
+    /// Delete a message. Its sender may do this for a while after sending it, and moderators of
+    /// a room at any time for messages sent to the room.
+    DeleteMessage(MessageId),
+    /// React to a message with an emoji or a `:shortcode:`. Anyone who got the message may.
+    AddReaction {
+        id: MessageId,
+        reaction: Reaction,
+    },
+    /// Take back a reaction added earlier.
+    RemoveReaction {
+        id: MessageId,
+        reaction: Reaction,
+    },
 }
 
-impl FrameType for ClientMessage {}
+/// Commands reserved to the admins listed in the server config.
+#[derive(Debug, Clone, Decode, Encode, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Close the connection of a user.
+    Disconnect {
+        user: UserName,
+        reason: Option<String>,
+    },
+    /// Keep a user from connecting, disconnecting them if they are online.
+    BanUser {
+        user: UserName,
+        reason: Option<String>,
+    },
+    UnbanUser(UserName),
+    /// Keep clients from connecting from an address, disconnecting those already connected.
+    BanIp(IpAddr),
+    UnbanIp(IpAddr),
+    /// Delete any room, whoever owns it.
+    DeleteRoom(RoomName),
+    /// Send a message to everyone connected.
+    Announce(String),
+}
+
+impl Display for AdminCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminCommand::Disconnect { user, .. } => write!(f, "Disconnecting {}", user),
+            AdminCommand::BanUser { user, .. } => write!(f, "Banning {}", user),
+            AdminCommand::UnbanUser(user) => write!(f, "Unbanning {}", user),
+            AdminCommand::BanIp(ip) => write!(f, "Banning {}", ip),
+            AdminCommand::UnbanIp(ip) => write!(f, "Unbanning {}", ip),
+            AdminCommand::DeleteRoom(room) => write!(f, "Deleting room: {}", room),
+            AdminCommand::Announce(content) => write!(f, "Announcing: {}", content),
+        }
+    }
+}
+
+/// Chosen by the client for each request and echoed back in the [`ServerInternal::Ack`] or
+/// [`ErrorMessage`] answering it.
+///
+/// [`ServerInternal::Ack`]: super::ServerInternal::Ack
+/// [`ErrorMessage`]: super::ErrorMessage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Decode, Encode, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RequestId(u32);
+
+impl RequestId {
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A [`ClientMessage`] tagged with the ID its answer will carry. This is the frame clients send
+/// once the handshake is done.
+#[derive(Debug, Clone, Decode, Encode, Serialize, Deserialize)]
+pub struct ClientRequest {
+    pub id: RequestId,
+    pub message: ClientMessage,
+}
+
+impl FrameType for ClientRequest {}
+
+impl Display for ClientRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.id, self.message)
+    }
+}
+
+impl ClientMessage {
+    /// The text typed by the user, for the messages carrying any.
+    pub fn content(&self) -> Option<&str> {
+        match self {
+            ClientMessage::GlobalChatMessage(content)
+            | ClientMessage::PrivateMessage { content, .. }
+            | ClientMessage::RoomMessage { content, .. }
+            | ClientMessage::EditMessage { content, .. }
+            | ClientMessage::Admin(AdminCommand::Announce(content)) => Some(content),
+            ClientMessage::SetRoomTopic { topic, .. } => topic.as_deref(),
+            ClientMessage::SetRoomDescription { description, .. } => description.as_deref(),
+            ClientMessage::SetPresence { text, .. } => text.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether sending this shows the user is at the keyboard. Listings and pings are left out
+    /// as clients send them on their own, e.g. to keep their user lists up to date.
+    pub fn is_activity(&self) -> bool {
+        !matches!(
+            self,
+            ClientMessage::Ping(_)
+                | ClientMessage::ListUsers
+                | ClientMessage::ListRooms
+                | ClientMessage::ListRoomUsers(_)
+                | ClientMessage::ListRoomRoles(_)
+                | ClientMessage::Disconnect
+                | ClientMessage::Typing { typing: false, .. }
+        )
+    }
+}
 
 impl Display for ClientMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -43,6 +274,70 @@ impl Display for ClientMessage {
             ClientMessage::RoomMessage { room, content } => {
                 write!(f, "Room message to {}: {}", room, content)
             }
+            ClientMessage::SetRoomRole { room, user, role } => {
+                write!(f, "Making {} a {} of room: {}", user, role, room)
+            }
+            ClientMessage::ListRoomRoles(room) => write!(f, "Listing roles in room: {}", room),
+            ClientMessage::KickUser { room, user, .. } => {
+                write!(f, "Kicking {} from room: {}", user, room)
+            }
+            ClientMessage::BanUser { room, user, .. } => {
+                write!(f, "Banning {} from room: {}", user, room)
+            }
+            ClientMessage::UnbanUser { room, user } => {
+                write!(f, "Unbanning {} from room: {}", user, room)
+            }
+            ClientMessage::MuteUser { room, user, .. } => {
+                write!(f, "Muting {} in room: {}", user, room)
+            }
+            ClientMessage::UnmuteUser { room, user } => {
+                write!(f, "Unmuting {} in room: {}", user, room)
+            }
+            ClientMessage::CreateRoomWithMode { room, mode } => {
+                write!(f, "Creating {} room: {}", mode, room)
+            }
+            ClientMessage::JoinRoomWithPassword { room, .. } => {
+                write!(f, "Joining room: {}", room)
+            }
+            ClientMessage::SetRoomMode { room, mode } => {
+                write!(f, "Making room {} {}", room, mode)
+            }
+            ClientMessage::InviteToRoom { room, user } => {
+                write!(f, "Inviting {} to room: {}", user, room)
+            }
+            ClientMessage::UninviteFromRoom { room, user } => {
+                write!(f, "Uninviting {} from room: {}", user, room)
+            }
+            ClientMessage::SetRoomTopic { room, .. } => {
+                write!(f, "Changing the topic of room: {}", room)
+            }
+            ClientMessage::SetRoomDescription { room, .. } => {
+                write!(f, "Changing the description of room: {}", room)
+            }
+            ClientMessage::DeleteRoom(room) => write!(f, "Deleting room: {}", room),
+            ClientMessage::Admin(command) => write!(f, "{}", command),
+            ClientMessage::ChangeNick(user) => write!(f, "Changing name to: {}", user),
+            ClientMessage::Typing { target, typing } => {
+                let action = if *typing { "Typing" } else { "Stopped typing" };
+                match target {
+                    ChatTarget::Global => write!(f, "{}", action),
+                    ChatTarget::Room(room) => write!(f, "{} in room: {}", action, room),
+                    ChatTarget::Private(user) => write!(f, "{} to {}", action, user),
+                }
+            }
+            ClientMessage::SetPresence { presence, .. } => {
+                write!(f, "Setting presence to: {}", presence)
+            }
+            ClientMessage::EditMessage { id, content } => {
+                write!(f, "Editing message {}: {}", id, content)
+            }
+            ClientMessage::DeleteMessage(id) => write!(f, "Deleting message: {}", id),
+            ClientMessage::AddReaction { id, reaction } => {
+                write!(f, "Reacting to message {} with {}", id, reaction)
+            }
+            ClientMessage::RemoveReaction { id, reaction } => {
+                write!(f, "Taking back reaction {} to message {}", reaction, id)
+            }
         }
     }
 }
@@ -0,0 +1,128 @@
+use super::{RequestId, MESSAGE_IDS_VERSION, MIN_PROTOCOL_VERSION, REACTIONS_VERSION};
+
+use bincode::{Decode, Encode};
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// What went wrong, so that clients can react to an error without parsing its message. Codes are
+/// only ever added at the end, so that older clients decode the ones they know alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The request was malformed or makes no sense, e.g. a private message to yourself.
+    InvalidRequest,
+    MessageTooLong,
+    UserNotFound,
+    UserExists,
+    /// The name is too long, has characters user names can't have or is reserved.
+    InvalidUserName,
+    RoomNotFound,
+    RoomExists,
+    /// The name is too long or has characters room names can't have.
+    InvalidRoomName,
+    AlreadyInRoom,
+    NotInRoom,
+    AccountExists,
+    InvalidCredentials,
+    PasswordTooShort,
+    /// The user's role does not allow what they asked for.
+    PermissionDenied,
+    /// The user is banned from the room.
+    Banned,
+    /// The user may not send messages to the room.
+    Muted,
+    /// The room takes a password and the one given was missing or wrong.
+    WrongRoomPassword,
+    /// The room is invite-only and the user was not invited.
+    NotInvited,
+    /// The recipient is offline and no more messages are kept for them until they log in.
+    MailboxFull,
+    /// Something failed on the server, the request may succeed if tried again.
+    Internal,
+    /// The message is unknown, or too old to be edited or deleted.
+    MessageNotFound,
+    /// The reaction is not an emoji or a `:shortcode:`.
+    InvalidReaction,
+}
+
+impl ErrorCode {
+    /// The protocol version that introduced the code.
+    pub fn since(&self) -> u16 {
+        match self {
+            ErrorCode::MessageNotFound => MESSAGE_IDS_VERSION,
+            ErrorCode::InvalidReaction => REACTIONS_VERSION,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ErrorCode::InvalidRequest => "The server did not understand that request",
+            ErrorCode::MessageTooLong => "That message is too long",
+            ErrorCode::UserNotFound => "No such user is online",
+            ErrorCode::UserExists => "That user is already connected",
+            ErrorCode::InvalidUserName => "That is not a valid user name",
+            ErrorCode::RoomNotFound => "No such room",
+            ErrorCode::RoomExists => "That room already exists",
+            ErrorCode::InvalidRoomName => "That is not a valid room name",
+            ErrorCode::AlreadyInRoom => "You are already in that room",
+            ErrorCode::NotInRoom => "You are not in that room",
+            ErrorCode::AccountExists => "That username is already registered",
+            ErrorCode::InvalidCredentials => "Invalid username or password",
+            ErrorCode::PasswordTooShort => "That password is too short",
+            ErrorCode::PermissionDenied => "You are not allowed to do that",
+            ErrorCode::Banned => "You are banned from that room",
+            ErrorCode::Muted => "You are muted in that room",
+            ErrorCode::WrongRoomPassword => "Wrong password for that room",
+            ErrorCode::NotInvited => "You need an invitation to join that room",
+            ErrorCode::MailboxFull => "That user has too many messages waiting",
+            ErrorCode::MessageNotFound => "No such message",
+            ErrorCode::InvalidReaction => "That is not a valid reaction",
+            ErrorCode::Internal => "Something went wrong on the server",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// An error sent to a client, in place of the [`Ack`](super::ServerInternal::Ack) of one of its
+/// requests.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    /// Details for a human, e.g. which room could not be found.
+    pub message: String,
+    /// The request that failed, when the error is the answer to one.
+    pub request: Option<RequestId>,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            request: None,
+        }
+    }
+
+    pub fn for_request(mut self, request: RequestId) -> Self {
+        self.request = Some(request);
+        self
+    }
+}
+
+impl Display for ErrorMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            "Error:".bold().on_dark_red(),
+            self.code.to_string().red()
+        )?;
+        if !self.message.is_empty() {
+            write!(f, " {}", format!("({})", self.message).dark_grey())?;
+        }
+        Ok(())
+    }
+}
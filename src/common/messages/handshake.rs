@@ -1,16 +1,253 @@
-use crate::common::UserName;
+use crate::common::{InvalidUserName, UserName};
 use crate::connection::FrameType;
 
 use bincode::{Decode, Encode};
-use std::fmt::Display;
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
 
-#[derive(Debug, Clone, Encode, Decode)]
-pub struct Handshake(pub UserName);
+/// The newest protocol version this build speaks. Bump this whenever an existing frame changes
+/// shape; purely additive changes are advertised with a new [`Capability`] instead.
+pub const PROTOCOL_VERSION: u16 = 6;
+/// The oldest protocol version the server still speaks. Frames whose shape changed since are sent
+/// to older clients as their version lays them out, and frames they have never heard of are not
+/// sent to them, see [`VersionedFrame`](super::VersionedFrame). Versions before 4 can't be served:
+/// their requests carry no ID to answer, and version 1 handshakes carry no [`Authentication`].
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+/// The first protocol version with message IDs, edits and deletions.
+pub const MESSAGE_IDS_VERSION: u16 = 5;
+/// The first protocol version with reactions.
+pub const REACTIONS_VERSION: u16 = 6;
+
+/// An optional protocol feature. Capabilities are sent as plain strings so that a peer can
+/// advertise features the other side has never heard of without breaking decoding.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Capability(String);
+
+impl Capability {
+    pub const ROOMS: &'static str = "rooms";
+    pub const PRIVATE_MESSAGES: &'static str = "private_messages";
+    pub const PING: &'static str = "ping";
+    pub const HISTORY: &'static str = "history";
+    pub const SHUTDOWN: &'static str = "shutdown";
+    pub const ROOM_ROLES: &'static str = "room_roles";
+    pub const MODERATION: &'static str = "moderation";
+    pub const ADMIN: &'static str = "admin";
+    pub const ROOM_MODES: &'static str = "room_modes";
+    pub const ROOM_INFO: &'static str = "room_info";
+    pub const OFFLINE_MESSAGES: &'static str = "offline_messages";
+    pub const PRESENCE: &'static str = "presence";
+    pub const MESSAGE_EDITS: &'static str = "message_edits";
+    pub const REACTIONS: &'static str = "reactions";
+
+    pub fn new(capability: impl Into<String>) -> Self {
+        Self(capability.into())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// All the capabilities supported by this build.
+    pub fn supported() -> Vec<Self> {
+        [
+            Self::ROOMS,
+            Self::PRIVATE_MESSAGES,
+            Self::PING,
+            Self::HISTORY,
+            Self::SHUTDOWN,
+            Self::ROOM_ROLES,
+            Self::MODERATION,
+            Self::ADMIN,
+            Self::ROOM_MODES,
+            Self::ROOM_INFO,
+            Self::OFFLINE_MESSAGES,
+            Self::PRESENCE,
+            Self::MESSAGE_EDITS,
+            Self::REACTIONS,
+        ]
+        .into_iter()
+        .map(Self::new)
+        .collect()
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// First frame sent by the client. The server decodes it as a whole, so a handshake of another
+/// shape is rejected as [`HandshakeRejection::InvalidHandshake`]; the version range is only
+/// negotiated between builds whose handshakes are laid out alike, as they have been since v2.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub struct Handshake {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: Vec<Capability>,
+    pub user_name: UserName,
+    pub authentication: Authentication,
+}
+
+impl Handshake {
+    /// A client's handshake. The client only decodes frames in their current shape, so it asks
+    /// for the newest version.
+    pub fn new(user_name: impl Into<UserName>, authentication: Authentication) -> Self {
+        Self {
+            min_version: PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capability::supported(),
+            user_name: user_name.into(),
+            authentication,
+        }
+    }
+
+    /// Pick the highest protocol version both sides understand, along with the capabilities
+    /// both sides support.
+    pub fn negotiate(&self) -> Result<(u16, Vec<Capability>), HandshakeRejection> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(HandshakeRejection::UnsupportedVersion {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            });
+        }
+        let supported = Capability::supported();
+        let capabilities = self
+            .capabilities
+            .iter()
+            .filter(|c| supported.contains(c))
+            .cloned()
+            .collect();
+        Ok((version, capabilities))
+    }
+}
 
 impl Display for Handshake {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Handshake: {}", self.0)
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Handshake: {} (protocol v{}-v{})",
+            self.user_name, self.min_version, self.max_version
+        )
     }
 }
 
 impl FrameType for Handshake {}
+
+/// How the client proves who it is during the handshake.
+#[derive(Clone, Encode, Decode, Serialize, Deserialize)]
+pub enum Authentication {
+    /// Log in to an existing account.
+    Login { password: String },
+    /// Create a new account, reserving the username.
+    Register { password: String },
+}
+
+// Passwords must never end up in the logs.
+impl fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Authentication::Login { .. } => write!(f, "Login {{ .. }}"),
+            Authentication::Register { .. } => write!(f, "Register {{ .. }}"),
+        }
+    }
+}
+
+/// The server's answer to a [`Handshake`].
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    Accepted {
+        version: u16,
+        capabilities: Vec<Capability>,
+        user_name: UserName,
+    },
+    Rejected(HandshakeRejection),
+}
+
+impl Display for HandshakeResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeResponse::Accepted {
+                version, user_name, ..
+            } => write!(
+                f,
+                "{} Welcome, {}! (protocol v{})",
+                "Server:".underline_dark_red(),
+                user_name.to_string().green(),
+                version
+            ),
+            HandshakeResponse::Rejected(reason) => write!(
+                f,
+                "{} {}",
+                "Connection rejected:".bold().on_dark_red(),
+                reason.to_string().red()
+            ),
+        }
+    }
+}
+
+impl FrameType for HandshakeResponse {}
+
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub enum HandshakeRejection {
+    UnsupportedVersion {
+        min_version: u16,
+        max_version: u16,
+    },
+    UserExists(UserName),
+    /// The name can't be used, for the reason given.
+    InvalidUserName(InvalidUserName),
+    AccountExists(UserName),
+    InvalidCredentials,
+    PasswordTooShort(usize),
+    InvalidHandshake,
+    ServerError,
+    Timeout,
+    /// The server is serving as many connections as it is configured to.
+    ServerFull,
+    /// The user or the address they connect from is banned from the server.
+    Banned {
+        reason: Option<String>,
+    },
+}
+
+impl Display for HandshakeRejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeRejection::UnsupportedVersion {
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "unsupported protocol version, the server speaks v{} to v{}",
+                min_version, max_version
+            ),
+            HandshakeRejection::UserExists(user) => {
+                write!(f, "{} is already connected", user)
+            }
+            HandshakeRejection::InvalidUserName(reason) => write!(f, "{}", reason),
+            HandshakeRejection::AccountExists(user) => {
+                write!(f, "an account named {} already exists", user)
+            }
+            HandshakeRejection::InvalidCredentials => write!(f, "invalid username or password"),
+            HandshakeRejection::PasswordTooShort(min) => {
+                write!(f, "passwords must be at least {} characters long", min)
+            }
+            HandshakeRejection::InvalidHandshake => write!(f, "invalid handshake"),
+            HandshakeRejection::ServerError => {
+                write!(f, "the server was unable to complete the handshake")
+            }
+            HandshakeRejection::Timeout => write!(f, "handshake timed out"),
+            HandshakeRejection::ServerFull => write!(f, "the server is full, try again later"),
+            HandshakeRejection::Banned { reason } => {
+                write!(f, "you are banned from this server")?;
+                match reason {
+                    Some(reason) => write!(f, " ({})", reason),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
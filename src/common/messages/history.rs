@@ -0,0 +1,30 @@
+use crate::common::{ChatRecord, MessageId, Reaction, Result, RoomName, UserName};
+
+use tokio::sync::oneshot;
+
+// Records are boxed, they are large enough to bloat every error carrying a failed send.
+#[derive(Debug)]
+pub enum HistoryMessage {
+    Record(Box<ChatRecord>),
+    /// The message with the same ID was edited and now reads like this.
+    Edit(Box<ChatRecord>),
+    Delete(MessageId),
+    /// Add the reaction of `user` to a message, or take it back. Answered with the message as it
+    /// is afterwards, or `None` if it is no longer kept.
+    React {
+        id: MessageId,
+        user: UserName,
+        reaction: Reaction,
+        add: bool,
+        sender: oneshot::Sender<Result<Option<ChatRecord>>>,
+    },
+    /// Look up a message that may still be edited or deleted.
+    Find {
+        id: MessageId,
+        sender: oneshot::Sender<Option<ChatRecord>>,
+    },
+    RoomHistory {
+        room_name: RoomName,
+        sender: oneshot::Sender<Vec<ChatRecord>>,
+    },
+}
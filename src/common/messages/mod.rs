@@ -1,13 +1,22 @@
 mod client;
+mod error;
 mod handshake;
+mod history;
 mod process;
 mod room;
 mod server;
 mod user;
+mod versioned;
 
-pub use client::ClientMessage;
-pub use handshake::Handshake;
-pub use process::{ProcessInternal, ProcessMessage, ProcessResponse};
-pub use room::{RoomInternal, RoomMessage};
-pub use server::{ServerInternal, ServerMessage};
+pub use client::{AdminCommand, ClientMessage, ClientRequest, RequestId};
+pub use error::{ErrorCode, ErrorMessage};
+pub use handshake::{
+    Authentication, Capability, Handshake, HandshakeRejection, HandshakeResponse,
+    MESSAGE_IDS_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, REACTIONS_VERSION,
+};
+pub use history::HistoryMessage;
+pub use process::{ProcessInternal, ProcessMessage, ProcessResponse, Reply};
+pub use room::{RoomInternal, RoomMessage, UserEvent};
+pub use server::{Moderation, ServerInternal, ServerMessage};
 pub use user::{UserInternal, UserMessage};
+pub use versioned::VersionedFrame;
@@ -1,13 +1,16 @@
-use super::{ClientMessage, RoomMessage, ServerMessage, UserMessage};
-use crate::common::UserName;
+use super::{ClientMessage, ErrorMessage, RoomMessage, ServerMessage, UserMessage};
+use crate::common::{MessageId, UserName};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug)]
 pub enum ProcessMessage {
     ClientMessage {
         from_user: UserName,
+        /// The account `from_user` logged in with, the same whatever name they go by.
+        login: UserName,
         message: ClientMessage,
+        reply: Reply,
     },
     ServerMessage {
         from_user: UserName,
@@ -16,6 +19,35 @@ pub enum ProcessMessage {
     Internal(ProcessInternal),
 }
 
+/// Answers a client request once it has been handled, travelling along with the request to
+/// whichever processor ends up handling it. A reply dropped without an answer tells the client
+/// the request failed.
+#[derive(Debug)]
+pub struct Reply(oneshot::Sender<Result<Option<MessageId>, ErrorMessage>>);
+
+impl Reply {
+    pub fn new() -> (
+        Self,
+        oneshot::Receiver<Result<Option<MessageId>, ErrorMessage>>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        (Self(tx), rx)
+    }
+
+    pub fn ack(self) {
+        let _ = self.0.send(Ok(None));
+    }
+
+    /// Acknowledge a chat message, telling the sender the ID it was given.
+    pub fn sent(self, id: MessageId) {
+        let _ = self.0.send(Ok(Some(id)));
+    }
+
+    pub fn error(self, error: impl Into<ErrorMessage>) {
+        let _ = self.0.send(Err(error.into()));
+    }
+}
+
 #[derive(Debug)]
 pub enum ProcessInternal {
     UserMessage(UserMessage),
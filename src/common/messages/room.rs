@@ -1,18 +1,140 @@
-use crate::common::{RoomName, UserName};
+use super::Reply;
+use crate::common::{
+    ChatRecord, MessageId, Reaction, RoomInfo, RoomMode, RoomName, RoomPassword, RoomPermission,
+    RoomRole, User, UserName,
+};
+
+use std::time::Duration;
+use tokio::sync::oneshot;
 
 #[derive(Debug)]
 pub struct RoomMessage {
     pub from_user: UserName,
     pub room_name: RoomName,
     pub message: RoomInternal,
+    pub reply: Reply,
 }
 
 #[derive(Debug)]
 pub enum RoomInternal {
-    NewRoom,
-    JoinRoom,
+    NewRoom {
+        mode: RoomMode,
+    },
+    JoinRoom {
+        password: Option<RoomPassword>,
+    },
     LeaveRoom,
     ListRooms,
     ListUsers,
     RoomMessage(String),
+    SetRole {
+        user: UserName,
+        role: RoomRole,
+    },
+    ListRoles,
+    Kick {
+        user: UserName,
+        reason: Option<String>,
+    },
+    /// Ban a user, for good when there is no duration.
+    Ban {
+        user: UserName,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    Unban {
+        user: UserName,
+    },
+    /// Mute a user, for good when there is no duration.
+    Mute {
+        user: UserName,
+        duration: Option<Duration>,
+    },
+    Unmute {
+        user: UserName,
+    },
+    SetMode {
+        mode: RoomMode,
+    },
+    /// Let a user join whatever the mode of the room.
+    Invite {
+        user: UserName,
+    },
+    Uninvite {
+        user: UserName,
+    },
+    /// Clear the topic with `None`.
+    SetTopic {
+        topic: Option<String>,
+    },
+    SetDescription {
+        description: Option<String>,
+    },
+    /// Ask the room how to list it to the sender, answered with `None` if the sender may not
+    /// see it.
+    Listing(oneshot::Sender<Option<RoomInfo>>),
+    /// Delete the room. Forced deletions come from server admins and skip the room's own
+    /// permission check.
+    DeleteRoom {
+        forced: bool,
+    },
+    /// The connection of a member went away. Only the session that joined is removed, the
+    /// same user may have connected again since.
+    Departed(User),
+    /// The member the message is from now goes by the name of this session.
+    Renamed(User),
+    /// The sender started or stopped typing in the room.
+    Typing {
+        typing: bool,
+    },
+    /// The sender edited a message they sent to the room, which now reads like this.
+    EditMessage(ChatRecord),
+    /// Delete a message sent to the room. Those who did not send it, or whose time to change it
+    /// ran out, need to be moderators.
+    DeleteMessage {
+        id: MessageId,
+        by_sender: bool,
+    },
+    /// The sender added a reaction to a message sent to the room, or took it back.
+    React {
+        id: MessageId,
+        reaction: Reaction,
+        add: bool,
+    },
+}
+
+/// What the rooms a user is in need to hear about them, sent by the user processor.
+#[derive(Debug)]
+pub enum UserEvent {
+    /// The user left the server.
+    Departed { user: User, rooms: Vec<RoomName> },
+    /// The user changed their name from `from` to that of `user`.
+    Renamed {
+        from: UserName,
+        user: User,
+        rooms: Vec<RoomName>,
+    },
+}
+
+impl RoomInternal {
+    /// What the sender must be allowed to do in the room for this message to go through.
+    pub fn permission(&self) -> Option<RoomPermission> {
+        match self {
+            RoomInternal::SetRole { .. } => Some(RoomPermission::ManageRoles),
+            RoomInternal::Kick { .. } => Some(RoomPermission::Kick),
+            RoomInternal::Ban { .. } | RoomInternal::Unban { .. } => Some(RoomPermission::Ban),
+            RoomInternal::Mute { .. } | RoomInternal::Unmute { .. } => Some(RoomPermission::Mute),
+            RoomInternal::SetTopic { .. } => Some(RoomPermission::SetTopic),
+            RoomInternal::SetDescription { .. } => Some(RoomPermission::SetDescription),
+            RoomInternal::SetMode { .. } => Some(RoomPermission::SetMode),
+            RoomInternal::Invite { .. } | RoomInternal::Uninvite { .. } => {
+                Some(RoomPermission::Invite)
+            }
+            RoomInternal::DeleteRoom { forced: false } => Some(RoomPermission::DeleteRoom),
+            RoomInternal::DeleteMessage {
+                by_sender: false, ..
+            } => Some(RoomPermission::DeleteMessages),
+            _ => None,
+        }
+    }
 }
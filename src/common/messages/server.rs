@@ -1,8 +1,15 @@
-use crate::common::{RoomName, UserName};
+use super::{
+    ErrorMessage, RequestId, MESSAGE_IDS_VERSION, MIN_PROTOCOL_VERSION, REACTIONS_VERSION,
+};
+use crate::common::{
+    format_duration, ChatRecord, ChatTarget, MessageId, Reactions, RoomInfo, RoomName, Timestamp,
+    UserName, UserStatus,
+};
 use crate::connection::FrameType;
 
 use bincode::{Decode, Encode};
-use crossterm::style::Stylize;
+use crossterm::style::{StyledContent, Stylize};
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug, Display, Formatter};
 
 #[derive(Debug, Clone, Decode, Encode)]
@@ -16,26 +23,67 @@ impl Display for ServerMessage {
         write!(f, "{}: {}", self.from_user, self.content)
     }
 }
-#[derive(Debug, Clone, Decode, Encode)]
+/// A frame sent to the client. Variants are only ever added at the end, so that clients speaking
+/// an older protocol version decode the ones they know alike, see [`VersionedFrame`].
+///
+/// [`VersionedFrame`]: super::VersionedFrame
+#[derive(Debug, Clone, Decode, Encode, Serialize, Deserialize)]
 pub enum ServerInternal {
     ServerMessage(String),
     GlobalChatMessage {
+        id: MessageId,
+        timestamp: Timestamp,
         from_user: UserName,
         content: String,
     },
     ChatMessage(String),
     PrivateMessage {
+        id: MessageId,
+        timestamp: Timestamp,
         from_user: UserName,
         content: String,
     },
+    /// The recipient of a private message is offline. The message is kept for them and delivered
+    /// when they next log in.
+    PrivateMessageQueued {
+        to_user: UserName,
+    },
+    /// The private messages sent to the user while they were offline, oldest first. Sent once
+    /// they log in.
+    OfflineMessages {
+        messages: Vec<ChatRecord>,
+    },
     UserJoined(UserName),
+    /// A user started or stopped typing, in a room or, without one, to the user receiving this.
+    /// Someone who sends a message has stopped typing, no separate notice is sent for that.
+    Typing {
+        user: UserName,
+        room: Option<RoomName>,
+        typing: bool,
+    },
+    /// Everyone connected with their presence, sorted by name.
     UserList {
-        users: Vec<UserName>,
+        users: Vec<UserStatus>,
+    },
+    /// A user's presence or status text changed. Sent to the user and to those sharing a room
+    /// with them.
+    PresenceChanged(UserStatus),
+    /// The request with this ID was handled. Anything it produced, like the [`Pong`] to a
+    /// [`Ping`], is sent before the ack. Requests sending a chat message are told the ID it was
+    /// given, as senders don't get their own global and private messages back.
+    ///
+    /// [`Pong`]: ServerInternal::Pong
+    /// [`Ping`]: super::ClientMessage::Ping
+    Ack {
+        request: RequestId,
+        message: Option<MessageId>,
     },
-    Error(String),
+    Error(ErrorMessage),
     Pong(u16),
     RoomMessage {
         room: RoomName,
+        id: MessageId,
+        timestamp: Timestamp,
         from: UserName,
         content: String,
     },
@@ -43,10 +91,89 @@ pub enum ServerInternal {
         room: RoomName,
         users: Vec<UserName>,
     },
+    /// The rooms the user may see, sorted by name. The answer to listing the rooms.
+    RoomList {
+        rooms: Vec<RoomInfo>,
+    },
+    /// Who besides regular members has a say in a room.
+    RoomRoles {
+        room: RoomName,
+        owner: UserName,
+        moderators: Vec<UserName>,
+    },
+    /// A moderator of a room took action against the user receiving this.
+    Moderated {
+        room: RoomName,
+        by: UserName,
+        action: Moderation,
+    },
+    /// The owner of a room invited the user to it.
+    RoomInvite {
+        room: RoomName,
+        by: UserName,
+    },
+    /// The room was deleted, by its owner or a server admin.
+    RoomDeleted {
+        room: RoomName,
+    },
+    /// A message from a server admin to everyone connected.
+    Announcement {
+        from_user: UserName,
+        content: String,
+    },
+    /// A user changed their name. Sent to everyone, and to the user themselves first, whose
+    /// connection goes by the new name from then on.
+    NickChanged {
+        from: UserName,
+        to: UserName,
+    },
+    /// A server admin closed the connection. This is the last frame sent before it is closed.
+    Disconnected {
+        reason: Option<String>,
+    },
+    /// The most recent messages of a room, sent to a user when they join it.
+    RoomHistory {
+        room: RoomName,
+        messages: Vec<ChatRecord>,
+    },
+    /// The server is going away. This is the last frame sent before the connection is closed.
+    ServerShutdown {
+        reason: Option<String>,
+    },
+    /// A message was edited by its sender, and now reads like this. Sent to everyone who got the
+    /// message, the sender included.
+    MessageEdited(Box<ChatRecord>),
+    /// A message was deleted, by its sender or a moderator of the room it was sent to. Sent to
+    /// everyone who got the message, the sender included.
+    MessageDeleted {
+        id: MessageId,
+        target: ChatTarget,
+        by: UserName,
+    },
+    /// Someone added or took back a reaction to a message, which now has these reactions. Sent to
+    /// everyone who got the message.
+    ReactionsChanged {
+        id: MessageId,
+        target: ChatTarget,
+        reactions: Reactions,
+    },
 }
 
 impl FrameType for ServerInternal {}
 
+impl ServerInternal {
+    /// The protocol version that introduced the frame, older clients are not sent it.
+    pub fn since(&self) -> u16 {
+        match self {
+            ServerInternal::MessageEdited(_) | ServerInternal::MessageDeleted { .. } => {
+                MESSAGE_IDS_VERSION
+            }
+            ServerInternal::ReactionsChanged { .. } => REACTIONS_VERSION,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
 impl Display for ServerInternal {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -61,32 +188,75 @@ impl Display for ServerInternal {
             ServerInternal::ChatMessage(content) => {
                 write!(f, "{}", content)
             }
-            ServerInternal::GlobalChatMessage { from_user, content } => {
+            ServerInternal::GlobalChatMessage {
+                id,
+                from_user,
+                content,
+                ..
+            } => {
                 write!(
                     f,
-                    "{} {:<10}: {}",
-                    "[Global]".dark_green(),
+                    "{} {:<10}: {} {}",
+                    tag(&ChatTarget::Global),
                     from_user.to_string().green(),
-                    content.as_str().green()
+                    content.as_str().green(),
+                    id.to_string().dark_grey()
                 )
             }
-            ServerInternal::PrivateMessage { from_user, content } => {
+            ServerInternal::PrivateMessage {
+                id,
+                from_user,
+                content,
+                ..
+            } => {
                 write!(
                     f,
-                    "{} {:<10}: {}",
+                    "{} {:<10}: {} {}",
                     "[PrivateMessage]".dark_magenta(),
                     from_user.to_string().magenta(),
-                    content.as_str().grey()
+                    content.as_str().grey(),
+                    id.to_string().dark_grey()
                 )
             }
-            ServerInternal::Error(message) => {
+            ServerInternal::PrivateMessageQueued { to_user } => write!(
+                f,
+                "{} {}",
+                "[PrivateMessage]".dark_magenta(),
+                format!(
+                    "{} is offline, they will get your message when they log in",
+                    to_user
+                )
+                .dark_grey()
+            ),
+            ServerInternal::OfflineMessages { messages } => {
                 write!(
                     f,
                     "{} {}",
-                    "Error:".bold().on_dark_red(),
-                    message.as_str().red()
-                )
+                    "[PrivateMessage]".dark_magenta(),
+                    format!("{} messages while you were away:", messages.len()).dark_grey()
+                )?;
+                for message in messages {
+                    write!(
+                        f,
+                        "\n{} {} {:<10}: {} {}",
+                        "[PrivateMessage]".dark_magenta(),
+                        message.timestamp.to_string().dark_grey(),
+                        message.from_user.to_string().magenta(),
+                        message.content.as_str().grey(),
+                        annotation(message).dark_grey()
+                    )?;
+                }
+                Ok(())
             }
+            ServerInternal::Ack { request, message } => match message {
+                Some(id) => write!(
+                    f,
+                    "{}",
+                    format!("Request {} done, sent as {}", request, id).dark_grey()
+                ),
+                None => write!(f, "{}", format!("Request {} done", request).dark_grey()),
+            },
+            ServerInternal::Error(error) => write!(f, "{}", error),
             ServerInternal::Pong(i) => write!(f, "{}", format!("Pong: {:}", i).yellow()),
             ServerInternal::UserList { users } => {
                 let users = users.iter().map(ToString::to_string).collect::<Vec<_>>();
@@ -96,22 +266,79 @@ impl Display for ServerInternal {
                     write!(f, "{} {}", "[Users]".yellow(), users.join(", "))
                 }
             }
+            ServerInternal::Typing { user, room, typing } => {
+                if let Some(room) = room {
+                    write!(f, "{} ", format!("[{}]", room).to_string().cyan())?;
+                }
+                let action = if *typing {
+                    "is typing…"
+                } else {
+                    "stopped typing"
+                };
+                write!(f, "{}", format!("{} {}", user, action).dark_grey())
+            }
+            ServerInternal::PresenceChanged(status) => {
+                write!(f, "{} {}", "[Presence]".yellow(), status)
+            }
             ServerInternal::UserJoined(user) => {
                 write!(f, "User joined: {}", user.to_string().on_yellow())
             }
             ServerInternal::RoomMessage {
                 room,
+                id,
                 from,
                 content,
+                ..
             } => {
                 write!(
                     f,
-                    "{} {:<10}: {}",
+                    "{} {:<10}: {} {}",
                     format!("[{}]", room).to_string().cyan(),
                     from.to_string().yellow(),
-                    content
+                    content,
+                    id.to_string().dark_grey()
                 )
             }
+            ServerInternal::MessageEdited(message) => {
+                write!(
+                    f,
+                    "{} {} {}: {}",
+                    tag(&message.target),
+                    message.from_user.to_string().yellow(),
+                    format!("edited {}", message.id).dark_grey(),
+                    message.content
+                )
+            }
+            ServerInternal::MessageDeleted { id, target, by } => {
+                write!(
+                    f,
+                    "{} {}",
+                    tag(target),
+                    format!("{} was deleted by {}", id, by).dark_grey()
+                )
+            }
+            ServerInternal::ReactionsChanged {
+                id,
+                target,
+                reactions,
+            } => {
+                if reactions.is_empty() {
+                    write!(
+                        f,
+                        "{} {}",
+                        tag(target),
+                        format!("{} has no reactions any more", id).dark_grey()
+                    )
+                } else {
+                    write!(
+                        f,
+                        "{} {} {}",
+                        tag(target),
+                        format!("Reactions to {}:", id).dark_grey(),
+                        reactions
+                    )
+                }
+            }
             ServerInternal::RoomUsers { room, users } => {
                 let users = users.iter().map(ToString::to_string).collect::<Vec<_>>();
                 write!(
@@ -121,6 +348,182 @@ impl Display for ServerInternal {
                     users.join(", ")
                 )
             }
+            ServerInternal::RoomList { rooms } => {
+                if rooms.is_empty() {
+                    return write!(f, "{}", "No rooms".cyan());
+                }
+                write!(f, "{}", "Rooms:".cyan())?;
+                for room in rooms {
+                    write!(f, "\n  {}", room)?;
+                }
+                Ok(())
+            }
+            ServerInternal::RoomRoles {
+                room,
+                owner,
+                moderators,
+            } => {
+                let moderators = moderators
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "{} Owner: {}, Moderators: {}",
+                    format!("[{}]", room).to_string().cyan(),
+                    owner.to_string().yellow(),
+                    if moderators.is_empty() {
+                        "none".to_string()
+                    } else {
+                        moderators.join(", ")
+                    }
+                )
+            }
+            ServerInternal::Moderated { room, by, action } => {
+                write!(
+                    f,
+                    "{} {}",
+                    format!("[{}]", room).to_string().cyan(),
+                    format!("{} by {}", action, by).bold().red()
+                )
+            }
+            ServerInternal::RoomInvite { room, by } => {
+                write!(
+                    f,
+                    "{} {}",
+                    format!("[{}]", room).to_string().cyan(),
+                    format!("{} invited you to the room", by).bold().green()
+                )
+            }
+            ServerInternal::RoomDeleted { room } => {
+                write!(
+                    f,
+                    "{} {}",
+                    format!("[{}]", room).to_string().cyan(),
+                    "The room was deleted".bold().red()
+                )
+            }
+            ServerInternal::Announcement { from_user, content } => {
+                write!(
+                    f,
+                    "{} {}: {}",
+                    "[Announcement]".bold().on_dark_blue(),
+                    from_user.to_string().blue(),
+                    content.as_str().bold()
+                )
+            }
+            ServerInternal::NickChanged { from, to } => write!(
+                f,
+                "{} {}",
+                "Server:".underline_dark_red(),
+                format!("{} is now known as {}", from, to).red()
+            ),
+            ServerInternal::Disconnected { reason } => {
+                write!(f, "{}", "Disconnected by the server".bold().on_dark_red())?;
+                match reason {
+                    Some(reason) => write!(f, " {}", reason.as_str().red()),
+                    None => Ok(()),
+                }
+            }
+            ServerInternal::ServerShutdown { reason } => {
+                write!(f, "{}", "Server is shutting down".bold().on_dark_red())?;
+                match reason {
+                    Some(reason) => write!(f, " {}", reason.as_str().red()),
+                    None => Ok(()),
+                }
+            }
+            ServerInternal::RoomHistory { room, messages } => {
+                write!(
+                    f,
+                    "{} {}",
+                    format!("[{}]", room).to_string().cyan(),
+                    format!("Last {} messages:", messages.len()).dark_grey()
+                )?;
+                for message in messages {
+                    write!(
+                        f,
+                        "\n{} {} {:<10}: {} {}",
+                        format!("[{}]", room).to_string().cyan(),
+                        message.timestamp.to_string().dark_grey(),
+                        message.from_user.to_string().yellow(),
+                        message.content.as_str().dark_grey(),
+                        annotation(message).dark_grey()
+                    )?;
+                }
+                Ok(())
+            }
         }
     }
 }
+
+/// "[Global]", "[room]" or "[PrivateMessage]", the way messages to `target` are shown.
+fn tag(target: &ChatTarget) -> StyledContent<String> {
+    match target {
+        ChatTarget::Global => "[Global]".to_string().dark_green(),
+        ChatTarget::Room(room) => format!("[{}]", room).cyan(),
+        ChatTarget::Private(_) => "[PrivateMessage]".to_string().dark_magenta(),
+    }
+}
+
+/// The ID of a replayed message, whether it was edited and its reactions.
+fn annotation(message: &ChatRecord) -> String {
+    let mut annotation = message.id.to_string();
+    if message.edited.is_some() {
+        annotation.push_str(" (edited)");
+    }
+    if !message.reactions.is_empty() {
+        annotation.push_str(&format!("  {}", message.reactions));
+    }
+    annotation
+}
+
+/// What a moderator did to a user, as told to that user.
+#[derive(Debug, Clone, Decode, Encode, Serialize, Deserialize)]
+pub enum Moderation {
+    Kicked {
+        reason: Option<String>,
+    },
+    /// Banned until the given time, or for good.
+    Banned {
+        until: Option<Timestamp>,
+        reason: Option<String>,
+    },
+    Unbanned,
+    /// Muted until the given time, or for good.
+    Muted {
+        until: Option<Timestamp>,
+    },
+    Unmuted,
+}
+
+impl Display for Moderation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Moderation::Kicked { .. } => write!(f, "You were kicked")?,
+            Moderation::Banned { until, .. } => {
+                write!(f, "You were banned {}", period(until))?;
+            }
+            Moderation::Unbanned => write!(f, "You were unbanned")?,
+            Moderation::Muted { until } => write!(f, "You were muted {}", period(until))?,
+            Moderation::Unmuted => write!(f, "You were unmuted")?,
+        }
+        match self {
+            Moderation::Kicked {
+                reason: Some(reason),
+            }
+            | Moderation::Banned {
+                reason: Some(reason),
+                ..
+            } => write!(f, " ({})", reason),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// "for 5m" or "permanently", rounded up to the largest unit.
+fn period(until: &Option<Timestamp>) -> String {
+    match until {
+        Some(until) => format!("for {}", format_duration(until.remaining())),
+        None => "permanently".to_string(),
+    }
+}
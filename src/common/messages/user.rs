@@ -1,5 +1,9 @@
-use crate::common::{messages::ServerMessage, Result, User, UserName};
+use crate::common::{
+    messages::{Authentication, Reply, ServerMessage},
+    ChatRecord, MessageId, Presence, Reaction, Result, RoomName, User, UserName,
+};
 
+use std::net::IpAddr;
 use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug)]
@@ -10,10 +14,88 @@ pub struct UserMessage {
 
 #[derive(Debug)]
 pub enum UserInternal {
-    NewUser(oneshot::Sender<Result<mpsc::Receiver<ServerMessage>>>),
-    PrivateMessage { to_user: UserName, content: String },
+    NewUser {
+        authentication: Authentication,
+        /// Where the user connects from, checked against the banned addresses.
+        address: IpAddr,
+        sender: oneshot::Sender<Result<mpsc::Receiver<ServerMessage>>>,
+    },
+    PrivateMessage {
+        to_user: UserName,
+        content: String,
+        reply: Reply,
+    },
+    /// The sender edited a private message they sent, which now reads like this.
+    EditMessage {
+        message: Box<ChatRecord>,
+        reply: Reply,
+    },
+    /// The sender deleted a private message they sent.
+    DeleteMessage {
+        message: Box<ChatRecord>,
+        reply: Reply,
+    },
+    /// The sender added a reaction to a private message they sent or got, or took it back.
+    React {
+        id: MessageId,
+        reaction: Reaction,
+        add: bool,
+        reply: Reply,
+    },
     DisconnectUser,
-    Ping(u16),
+    Ping(u16, Reply),
     GetUser(oneshot::Sender<Result<User>>),
-    ListUsers,
+    ListUsers(Reply),
+    /// Rename the user, checking the new name like the ones given in the handshake.
+    ChangeNick {
+        user_name: UserName,
+        reply: Reply,
+    },
+    SetPresence {
+        presence: Presence,
+        text: Option<String>,
+        reply: Reply,
+    },
+    /// The user started or stopped typing a private message to `to_user`.
+    Typing {
+        to_user: UserName,
+        typing: bool,
+        reply: Reply,
+    },
+    /// Sent by the connection when the user has not done anything for a while.
+    Idle,
+    /// Sent by the connection when a user it reported idle does something again.
+    Active,
+    /// Close the connection of another user, on behalf of an admin.
+    ForceDisconnect {
+        user: UserName,
+        reason: Option<String>,
+        reply: Reply,
+    },
+    Ban {
+        user: UserName,
+        reason: Option<String>,
+        reply: Reply,
+    },
+    Unban {
+        user: UserName,
+        reply: Reply,
+    },
+    BanAddress {
+        address: IpAddr,
+        reply: Reply,
+    },
+    UnbanAddress {
+        address: IpAddr,
+        reply: Reply,
+    },
+    /// Sent by a room when `session` joined it, to be let go of the room on disconnect.
+    JoinedRoom {
+        room: RoomName,
+        session: User,
+    },
+    /// Sent by a room when the user left it or was removed from it.
+    LeftRoom {
+        room: RoomName,
+    },
 }
@@ -0,0 +1,390 @@
+use super::{
+    ErrorCode, ErrorMessage, RequestId, ServerInternal, MESSAGE_IDS_VERSION, PROTOCOL_VERSION,
+};
+use crate::common::{ChatRecord, ChatTarget, MessageId, RoomName, Timestamp, UserName};
+use crate::connection::FrameType;
+
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display, Formatter};
+
+/// A frame sent to a client speaking protocol `version`. Frames whose shape changed since that
+/// version are encoded the way it lays them out, and all others as they are, which older clients
+/// decode alike as variants are only ever added at the end.
+#[derive(Debug, Clone)]
+pub struct VersionedFrame {
+    version: u16,
+    frame: ServerInternal,
+}
+
+impl VersionedFrame {
+    /// `frame` for a client speaking `version`, or `None` if that version has no such frame.
+    /// Error codes it doesn't know are sent as [`ErrorCode::InvalidRequest`], with their message.
+    pub fn new(frame: ServerInternal, version: u16) -> Option<Self> {
+        if frame.since() > version {
+            return None;
+        }
+        let frame = match frame {
+            ServerInternal::Error(error) if error.code.since() > version => {
+                ServerInternal::Error(ErrorMessage {
+                    code: ErrorCode::InvalidRequest,
+                    ..error
+                })
+            }
+            frame => frame,
+        };
+        Some(Self { version, frame })
+    }
+
+    /// The frame as `version` lays it out, if that is not how it is laid out now.
+    fn older_shape(&self) -> Option<OlderFrame> {
+        let version = self.version;
+        let records = |messages: &[ChatRecord]| {
+            messages
+                .iter()
+                .map(|record| OlderRecord::new(record, version))
+                .collect()
+        };
+        let older = match &self.frame {
+            _ if version >= PROTOCOL_VERSION => return None,
+            ServerInternal::OfflineMessages { messages } => OlderFrame::OfflineMessages {
+                messages: records(messages),
+            },
+            ServerInternal::RoomHistory { room, messages } => OlderFrame::RoomHistory {
+                room: room.clone(),
+                messages: records(messages),
+            },
+            ServerInternal::MessageEdited(record) => {
+                OlderFrame::MessageEdited(OlderRecord::new(record, version))
+            }
+            _ if version >= MESSAGE_IDS_VERSION => return None,
+            ServerInternal::GlobalChatMessage {
+                from_user, content, ..
+            } => OlderFrame::GlobalChatMessage {
+                from_user: from_user.clone(),
+                content: content.clone(),
+            },
+            ServerInternal::PrivateMessage {
+                from_user, content, ..
+            } => OlderFrame::PrivateMessage {
+                from_user: from_user.clone(),
+                content: content.clone(),
+            },
+            ServerInternal::Ack { request, .. } => OlderFrame::Ack { request: *request },
+            ServerInternal::RoomMessage {
+                room,
+                from,
+                content,
+                ..
+            } => OlderFrame::RoomMessage {
+                room: room.clone(),
+                from: from.clone(),
+                content: content.clone(),
+            },
+            _ => return None,
+        };
+        Some(older)
+    }
+}
+
+impl Encode for VersionedFrame {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        match self.older_shape() {
+            Some(older) => older.encode(encoder),
+            None => self.frame.encode(encoder),
+        }
+    }
+}
+
+impl Serialize for VersionedFrame {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.older_shape() {
+            Some(older) => older.serialize(serializer),
+            None => self.frame.serialize(serializer),
+        }
+    }
+}
+
+// Frames are only decoded by clients, which speak the current version.
+impl Decode for VersionedFrame {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            frame: ServerInternal::decode(decoder)?,
+        })
+    }
+}
+
+bincode::impl_borrow_decode!(VersionedFrame);
+
+impl<'de> Deserialize<'de> for VersionedFrame {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            frame: ServerInternal::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl Display for VersionedFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.frame)
+    }
+}
+
+impl FrameType for VersionedFrame {}
+
+/// The frames whose shape changed since [`MIN_PROTOCOL_VERSION`], as older versions lay them out.
+/// They are encoded under the index of the same variant of [`ServerInternal`], and serialized
+/// under its name.
+///
+/// [`MIN_PROTOCOL_VERSION`]: super::MIN_PROTOCOL_VERSION
+#[derive(Serialize)]
+enum OlderFrame {
+    GlobalChatMessage {
+        from_user: UserName,
+        content: String,
+    },
+    PrivateMessage {
+        from_user: UserName,
+        content: String,
+    },
+    OfflineMessages {
+        messages: Vec<OlderRecord>,
+    },
+    Ack {
+        request: RequestId,
+    },
+    RoomMessage {
+        room: RoomName,
+        from: UserName,
+        content: String,
+    },
+    RoomHistory {
+        room: RoomName,
+        messages: Vec<OlderRecord>,
+    },
+    MessageEdited(OlderRecord),
+}
+
+impl Encode for OlderFrame {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        match self {
+            OlderFrame::GlobalChatMessage { from_user, content } => {
+                1u32.encode(encoder)?;
+                from_user.encode(encoder)?;
+                content.encode(encoder)
+            }
+            OlderFrame::PrivateMessage { from_user, content } => {
+                3u32.encode(encoder)?;
+                from_user.encode(encoder)?;
+                content.encode(encoder)
+            }
+            OlderFrame::OfflineMessages { messages } => {
+                5u32.encode(encoder)?;
+                messages.encode(encoder)
+            }
+            OlderFrame::Ack { request } => {
+                10u32.encode(encoder)?;
+                request.encode(encoder)
+            }
+            OlderFrame::RoomMessage {
+                room,
+                from,
+                content,
+            } => {
+                13u32.encode(encoder)?;
+                room.encode(encoder)?;
+                from.encode(encoder)?;
+                content.encode(encoder)
+            }
+            OlderFrame::RoomHistory { room, messages } => {
+                23u32.encode(encoder)?;
+                room.encode(encoder)?;
+                messages.encode(encoder)
+            }
+            OlderFrame::MessageEdited(record) => {
+                25u32.encode(encoder)?;
+                record.encode(encoder)
+            }
+        }
+    }
+}
+
+/// A [`ChatRecord`] as older versions lay it out: without an ID before
+/// [`MESSAGE_IDS_VERSION`], and without reactions before [`REACTIONS_VERSION`].
+///
+/// [`REACTIONS_VERSION`]: super::REACTIONS_VERSION
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OlderRecord {
+    WithoutIds {
+        timestamp: Timestamp,
+        from_user: UserName,
+        target: ChatTarget,
+        content: String,
+    },
+    WithoutReactions {
+        id: MessageId,
+        timestamp: Timestamp,
+        from_user: UserName,
+        target: ChatTarget,
+        content: String,
+        edited: Option<Timestamp>,
+    },
+}
+
+impl OlderRecord {
+    fn new(record: &ChatRecord, version: u16) -> Self {
+        let record = record.clone();
+        if version < MESSAGE_IDS_VERSION {
+            OlderRecord::WithoutIds {
+                timestamp: record.timestamp,
+                from_user: record.from_user,
+                target: record.target,
+                content: record.content,
+            }
+        } else {
+            OlderRecord::WithoutReactions {
+                id: record.id,
+                timestamp: record.timestamp,
+                from_user: record.from_user,
+                target: record.target,
+                content: record.content,
+                edited: record.edited,
+            }
+        }
+    }
+}
+
+impl Encode for OlderRecord {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        match self {
+            OlderRecord::WithoutIds {
+                timestamp,
+                from_user,
+                target,
+                content,
+            } => {
+                timestamp.encode(encoder)?;
+                from_user.encode(encoder)?;
+                target.encode(encoder)?;
+                content.encode(encoder)
+            }
+            OlderRecord::WithoutReactions {
+                id,
+                timestamp,
+                from_user,
+                target,
+                content,
+                edited,
+            } => {
+                id.encode(encoder)?;
+                timestamp.encode(encoder)?;
+                from_user.encode(encoder)?;
+                target.encode(encoder)?;
+                content.encode(encoder)?;
+                edited.encode(encoder)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::config;
+
+    fn alice() -> UserName {
+        UserName::new("alice")
+    }
+
+    fn encode(frame: &impl Encode) -> Vec<u8> {
+        bincode::encode_to_vec(frame, config::standard()).unwrap()
+    }
+
+    /// Decode `data` as the variant index of a frame and fields of type `T`, all of it.
+    fn decode<T: Decode>(data: &[u8]) -> (u8, T) {
+        let (fields, read) = bincode::decode_from_slice(&data[1..], config::standard()).unwrap();
+        assert_eq!(read, data.len() - 1);
+        (data[0], fields)
+    }
+
+    #[test]
+    fn current_clients_get_frames_as_they_are() {
+        let frame = ServerInternal::Ack {
+            request: RequestId::new(7),
+            message: Some(MessageId::next()),
+        };
+        let versioned = VersionedFrame::new(frame.clone(), PROTOCOL_VERSION).unwrap();
+        assert_eq!(encode(&versioned), encode(&frame));
+    }
+
+    #[test]
+    fn older_clients_get_frames_as_their_version_lays_them_out() {
+        let frame = ServerInternal::GlobalChatMessage {
+            id: MessageId::next(),
+            timestamp: Timestamp::now(),
+            from_user: alice(),
+            content: "hello".to_string(),
+        };
+        let encoded = encode(&VersionedFrame::new(frame.clone(), 4).unwrap());
+        let (index, (from_user, content)): (_, (UserName, String)) = decode(&encoded);
+        assert_eq!(index, encode(&frame)[0]);
+        assert_eq!((from_user, content.as_str()), (alice(), "hello"));
+        let json = serde_json::to_value(VersionedFrame::new(frame.clone(), 4).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"GlobalChatMessage": {"from_user": "alice", "content": "hello"}})
+        );
+        let versioned = VersionedFrame::new(frame.clone(), MESSAGE_IDS_VERSION).unwrap();
+        assert_eq!(encode(&versioned), encode(&frame));
+
+        let room = RoomName::new("lobby");
+        let record = ChatRecord::new(alice(), ChatTarget::Room(room.clone()), "hi");
+        let frame = ServerInternal::RoomHistory {
+            room: room.clone(),
+            messages: vec![record.clone()],
+        };
+        let encoded = encode(&VersionedFrame::new(frame.clone(), MESSAGE_IDS_VERSION).unwrap());
+        type Record = (
+            MessageId,
+            Timestamp,
+            UserName,
+            ChatTarget,
+            String,
+            Option<Timestamp>,
+        );
+        let (index, (replayed, messages)): (_, (RoomName, Vec<Record>)) = decode(&encoded);
+        assert_eq!(index, encode(&frame)[0]);
+        assert_eq!(replayed, room);
+        assert_eq!(messages[0].0, record.id);
+        assert_eq!(messages[0].4, "hi");
+    }
+
+    #[test]
+    fn older_clients_are_not_sent_what_they_never_heard_of() {
+        let deleted = ServerInternal::MessageDeleted {
+            id: MessageId::next(),
+            target: ChatTarget::Global,
+            by: alice(),
+        };
+        assert!(VersionedFrame::new(deleted.clone(), 4).is_none());
+        assert!(VersionedFrame::new(deleted, MESSAGE_IDS_VERSION).is_some());
+
+        let error = ServerInternal::Error(ErrorMessage::new(ErrorCode::InvalidReaction, "no"));
+        let ServerInternal::Error(error) = VersionedFrame::new(error, MESSAGE_IDS_VERSION)
+            .unwrap()
+            .frame
+        else {
+            unreachable!()
+        };
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert_eq!(error.message, "no");
+    }
+}
@@ -1,10 +1,26 @@
+mod account;
 mod error;
+mod history;
+mod mailbox;
 pub mod messages;
+mod password;
+mod reaction;
 mod room;
+mod timestamp;
+mod typing;
 mod user;
 
+pub use account::{AccountStore, SharedAccountStore};
 pub use error::CommonError;
 use error::Result;
+pub use history::{ChatRecord, ChatTarget, HistoryStore, MessageId};
+pub use mailbox::MailboxStore;
+pub use reaction::{Reaction, ReactionCount, Reactions, MAX_REACTIONS, MAX_REACTION_LENGTH};
 
-pub use room::{RoomManager, RoomName};
-pub use user::{User, UserManager, UserName};
+pub use room::{
+    RoomAccess, RoomInfo, RoomLifecycle, RoomManager, RoomMode, RoomName, RoomPassword,
+    RoomPermission, RoomRole,
+};
+pub use timestamp::{format_duration, Timestamp};
+pub use typing::{TypingTracker, TYPING_TIMEOUT};
+pub use user::{InvalidUserName, Presence, User, UserManager, UserName, UserStatus};
@@ -0,0 +1,27 @@
+use super::{CommonError, Result};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Hash a password with a fresh random salt. The returned string is in PHC format so it carries
+/// the salt and the argon2 parameters along with the hash.
+pub fn hash_password(password: &str) -> Result<String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(CommonError::PasswordTooShort(MIN_PASSWORD_LENGTH));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> Result<()> {
+    let password_hash = PasswordHash::new(password_hash)?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &password_hash)
+        .map_err(|_| CommonError::InvalidCredentials)
+}
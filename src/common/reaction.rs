@@ -0,0 +1,285 @@
+use super::{CommonError, Result, UserName};
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// The longest a reaction can be, in bytes. Enough for emoji made of several characters, like
+/// flags and families, but not for a sentence.
+pub const MAX_REACTION_LENGTH: usize = 32;
+/// The most different reactions a message can have.
+pub const MAX_REACTIONS: usize = 20;
+
+/// A reaction to a message: an emoji, or a `:shortcode:` standing for one. Trimmed, and shortcodes
+/// lowercased, whenever one is made or decoded so that `:Tada:` and `:tada:` count as one.
+#[derive(Debug, Clone, Encode, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(from = "String", into = "String")]
+pub struct Reaction {
+    reaction: String,
+}
+
+impl Reaction {
+    pub fn new(reaction: impl Into<String>) -> Self {
+        let reaction = reaction.into();
+        let reaction = reaction.trim();
+        let reaction = if reaction.starts_with(':') {
+            reaction.to_lowercase()
+        } else {
+            reaction.to_string()
+        };
+        Self { reaction }
+    }
+
+    /// Check that the reaction is an emoji, or a shortcode of letters, digits, '_', '+' and '-'
+    /// between colons, and at most [`MAX_REACTION_LENGTH`] bytes long.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(CommonError::InvalidReaction(reason));
+        if self.reaction.is_empty() {
+            return invalid("reactions can't be empty".to_string());
+        }
+        if self.reaction.len() > MAX_REACTION_LENGTH {
+            return invalid(format!(
+                "reactions can be at most {} bytes long",
+                MAX_REACTION_LENGTH
+            ));
+        }
+        if let Some(name) = self.reaction.strip_prefix(':') {
+            let valid = name.strip_suffix(':').is_some_and(|name| {
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'))
+            });
+            if !valid {
+                return invalid(
+                    "shortcodes look like :thumbsup:, with letters, digits, '_', '+' and '-'"
+                        .to_string(),
+                );
+            }
+            return Ok(());
+        }
+        // Emoji are symbols held together by joiners and modifiers. The only plain characters
+        // they use are the digits, '#' and '*' of keycaps.
+        let keycap = |c: char| c.is_ascii_digit() || c == '#' || c == '*';
+        let plain = |c: char| {
+            c.is_whitespace()
+                || c.is_control()
+                || ((c.is_alphanumeric() || c.is_ascii()) && !keycap(c))
+        };
+        if self.reaction.is_ascii() || self.reaction.chars().any(plain) {
+            return invalid("reactions are an emoji or a :shortcode:".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Decode for Reaction {
+    fn decode<D: bincode::de::Decoder>(
+        decoder: &mut D,
+    ) -> std::result::Result<Self, bincode::error::DecodeError> {
+        Ok(Self::new(String::decode(decoder)?))
+    }
+}
+
+bincode::impl_borrow_decode!(Reaction);
+
+impl Display for Reaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reaction)
+    }
+}
+
+impl From<String> for Reaction {
+    fn from(reaction: String) -> Self {
+        Self::new(reaction)
+    }
+}
+
+impl From<&str> for Reaction {
+    fn from(reaction: &str) -> Self {
+        Self::new(reaction)
+    }
+}
+
+impl From<Reaction> for String {
+    fn from(reaction: Reaction) -> Self {
+        reaction.reaction
+    }
+}
+
+/// A reaction to a message and everyone who added it.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub reaction: Reaction,
+    pub users: Vec<UserName>,
+}
+
+impl ReactionCount {
+    pub fn count(&self) -> usize {
+        self.users.len()
+    }
+}
+
+/// The reactions to a message, in the order they were first added. A user adds each reaction at
+/// most once, and reactions nobody has any more are dropped.
+#[derive(Debug, Clone, Default, Encode, Decode, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Reactions(Vec<ReactionCount>);
+
+impl Reactions {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether `user` added `reaction`.
+    pub fn contains(&self, reaction: &Reaction, user: &UserName) -> bool {
+        self.0
+            .iter()
+            .any(|count| count.reaction == *reaction && count.users.contains(user))
+    }
+
+    /// Whether `reaction` can be added, which it can't when it would be one too many.
+    pub fn has_room_for(&self, reaction: &Reaction) -> bool {
+        self.0.len() < MAX_REACTIONS || self.0.iter().any(|count| count.reaction == *reaction)
+    }
+
+    /// Add the reaction of `user`, returning whether they had not added it yet.
+    pub fn add(&mut self, reaction: Reaction, user: UserName) -> bool {
+        match self.0.iter_mut().find(|count| count.reaction == reaction) {
+            Some(count) if count.users.contains(&user) => false,
+            Some(count) => {
+                count.users.push(user);
+                true
+            }
+            None => {
+                self.0.push(ReactionCount {
+                    reaction,
+                    users: vec![user],
+                });
+                true
+            }
+        }
+    }
+
+    /// Take back the reaction of `user`, returning whether they had added it.
+    pub fn remove(&mut self, reaction: &Reaction, user: &UserName) -> bool {
+        let Some(index) = self.0.iter().position(|count| count.reaction == *reaction) else {
+            return false;
+        };
+        let users = &mut self.0[index].users;
+        let before = users.len();
+        users.retain(|other| other != user);
+        let removed = users.len() != before;
+        if users.is_empty() {
+            self.0.remove(index);
+        }
+        removed
+    }
+}
+
+/// Every reaction with its count, like "👍 2  :tada: 1".
+impl Display for Reactions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, count) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "  ")?;
+            }
+            write!(f, "{} {}", count.reaction, count.count())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> UserName {
+        UserName::new("alice")
+    }
+
+    fn bob() -> UserName {
+        UserName::new("bob")
+    }
+
+    #[test]
+    fn emoji_and_shortcodes_are_valid() {
+        for reaction in [
+            "👍",
+            "👍🏽",
+            "🇫🇷",
+            "👨‍👩‍👧",
+            "1️⃣",
+            ":tada:",
+            ":+1:",
+            ":thumbs_up-2:",
+        ] {
+            assert!(Reaction::new(reaction).validate().is_ok(), "{}", reaction);
+        }
+    }
+
+    #[test]
+    fn other_reactions_are_invalid() {
+        let too_long = format!(":{}:", "a".repeat(MAX_REACTION_LENGTH));
+        for reaction in [
+            "", "   ", "ok", "1", "👍 ok", "héllo", "::", ":tada", ":ta da:", ":tada:!", &too_long,
+        ] {
+            assert!(
+                matches!(
+                    Reaction::new(reaction).validate(),
+                    Err(CommonError::InvalidReaction(_))
+                ),
+                "{}",
+                reaction
+            );
+        }
+    }
+
+    #[test]
+    fn shortcodes_are_normalized() {
+        assert_eq!(Reaction::new(" :Tada: "), Reaction::new(":tada:"));
+        let reaction: Reaction = bincode::decode_from_slice(
+            &bincode::encode_to_vec(":TADA:", bincode::config::standard()).unwrap(),
+            bincode::config::standard(),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(reaction, Reaction::new(":tada:"));
+    }
+
+    #[test]
+    fn users_add_each_reaction_once() {
+        let mut reactions = Reactions::default();
+        assert!(reactions.add(Reaction::new("👍"), alice()));
+        assert!(!reactions.add(Reaction::new("👍"), alice()));
+        assert!(reactions.add(Reaction::new("👍"), bob()));
+        assert!(reactions.add(Reaction::new(":tada:"), alice()));
+        assert!(reactions.contains(&Reaction::new("👍"), &bob()));
+        assert_eq!(reactions.to_string(), "👍 2  :tada: 1");
+    }
+
+    #[test]
+    fn reactions_nobody_has_are_dropped() {
+        let mut reactions = Reactions::default();
+        reactions.add(Reaction::new("👍"), alice());
+        reactions.add(Reaction::new("👍"), bob());
+        assert!(!reactions.remove(&Reaction::new(":tada:"), &alice()));
+        assert!(reactions.remove(&Reaction::new("👍"), &alice()));
+        assert!(!reactions.remove(&Reaction::new("👍"), &alice()));
+        assert_eq!(reactions.to_string(), "👍 1");
+        assert!(reactions.remove(&Reaction::new("👍"), &bob()));
+        assert!(reactions.is_empty());
+    }
+
+    #[test]
+    fn messages_have_room_for_so_many_reactions() {
+        let mut reactions = Reactions::default();
+        for i in 0..MAX_REACTIONS {
+            let reaction = Reaction::new(format!(":r{}:", i));
+            assert!(reactions.has_room_for(&reaction));
+            reactions.add(reaction, alice());
+        }
+        assert!(!reactions.has_room_for(&Reaction::new(":one_more:")));
+        assert!(reactions.has_room_for(&Reaction::new(":r0:")));
+    }
+}
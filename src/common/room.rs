@@ -1,19 +1,29 @@
-use super::messages::{RoomInternal, RoomMessage, ServerInternal, UserInternal, UserMessage};
-use super::User;
+use super::messages::{
+    HistoryMessage, Moderation, RoomInternal, RoomMessage, ServerInternal, UserInternal,
+    UserMessage,
+};
+use super::{format_duration, ChatRecord, ChatTarget, Timestamp, TypingTracker, User};
 use super::{CommonError, Result};
 use crate::common::messages::ServerMessage;
 use crate::common::UserName;
 
 use bincode::{Decode, Encode};
 use futures::stream::{FuturesUnordered, StreamExt};
-use std::collections::HashSet;
-use std::fmt::Display;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::{mpsc, oneshot};
-use tracing::warn;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 
-#[derive(Debug, Clone, Encode, Decode, Eq, PartialEq, Hash)]
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+/// The name of a room, trimmed and lowercased whenever one is made or decoded so that names
+/// differing only in case are the same room.
+#[derive(Debug, Clone, Encode, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(from = "String", into = "String")]
 pub struct RoomName {
     room_name: String,
 }
@@ -21,17 +31,53 @@ pub struct RoomName {
 impl RoomName {
     pub fn new(room_name: impl Into<String>) -> Self {
         Self {
-            room_name: room_name.into(),
+            room_name: room_name.into().trim().to_lowercase(),
         }
     }
 
     pub fn room_name(&self) -> &str {
         &self.room_name
     }
+
+    /// Check that the name can be given to a new room: up to [`MAX_ROOM_NAME_LENGTH`] letters,
+    /// digits, dashes and underscores.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(CommonError::InvalidRoomName(reason));
+        if self.room_name.is_empty() {
+            return invalid("room names can't be empty".to_string());
+        }
+        if self.room_name.chars().count() > MAX_ROOM_NAME_LENGTH {
+            return invalid(format!(
+                "room names can be at most {} characters long",
+                MAX_ROOM_NAME_LENGTH
+            ));
+        }
+        if let Some(c) = self
+            .room_name
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && *c != '-' && *c != '_')
+        {
+            return invalid(format!(
+                "room names can only have letters, digits, '-' and '_', not {:?}",
+                c
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Decode for RoomName {
+    fn decode<D: bincode::de::Decoder>(
+        decoder: &mut D,
+    ) -> std::result::Result<Self, bincode::error::DecodeError> {
+        Ok(Self::new(String::decode(decoder)?))
+    }
 }
 
+bincode::impl_borrow_decode!(RoomName);
+
 impl Display for RoomName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.room_name)
     }
 }
@@ -50,37 +96,295 @@ impl PartialEq<String> for RoomName {
 
 impl From<String> for RoomName {
     fn from(name: String) -> Self {
-        Self { room_name: name }
+        Self::new(name)
     }
 }
 
 impl From<&str> for RoomName {
     fn from(name: &str) -> Self {
-        Self {
-            room_name: name.to_string(),
+        Self::new(name)
+    }
+}
+
+impl From<RoomName> for String {
+    fn from(name: RoomName) -> Self {
+        name.room_name
+    }
+}
+
+/// The password of a password-protected room. Its `Debug` output leaves the password out so
+/// that it never ends up in the logs.
+#[derive(Clone, Encode, Decode, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct RoomPassword(String);
+
+impl RoomPassword {
+    pub fn new(password: impl Into<String>) -> Self {
+        Self(password.into())
+    }
+}
+
+impl Debug for RoomPassword {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "RoomPassword(..)")
+    }
+}
+
+impl From<&str> for RoomPassword {
+    fn from(password: &str) -> Self {
+        Self::new(password)
+    }
+}
+
+/// The kind of a [`RoomMode`], without the password, as shown to clients listing the rooms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]
+pub enum RoomAccess {
+    Public,
+    Password,
+    InviteOnly,
+    Hidden,
+}
+
+impl Display for RoomAccess {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomAccess::Public => write!(f, "public"),
+            RoomAccess::Password => write!(f, "password-protected"),
+            RoomAccess::InviteOnly => write!(f, "invite-only"),
+            RoomAccess::Hidden => write!(f, "hidden"),
+        }
+    }
+}
+
+/// Who may find and join a room. Owners, moderators and invited users may always join.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub enum RoomMode {
+    /// Listed to everyone, anyone may join.
+    #[default]
+    Public,
+    /// Listed to everyone, joining takes the password.
+    Password(RoomPassword),
+    /// Only invited users may join, and only they are shown the room.
+    InviteOnly,
+    /// Anyone who knows the name may join, but only members are shown the room.
+    Hidden,
+}
+
+impl RoomMode {
+    pub fn access(&self) -> RoomAccess {
+        match self {
+            RoomMode::Public => RoomAccess::Public,
+            RoomMode::Password(_) => RoomAccess::Password,
+            RoomMode::InviteOnly => RoomAccess::InviteOnly,
+            RoomMode::Hidden => RoomAccess::Hidden,
         }
     }
+
+    /// Whether the room is shown to everyone, not only to those allowed in.
+    pub fn is_listed(&self) -> bool {
+        matches!(self, RoomMode::Public | RoomMode::Password(_))
+    }
+}
+
+impl Display for RoomMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.access())
+    }
 }
 
+/// What clients are told about a room when listing the rooms.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: RoomName,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub created: Timestamp,
+    pub creator: UserName,
+    pub members: usize,
+    pub access: RoomAccess,
+}
+
+impl Display for RoomInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.access != RoomAccess::Public {
+            write!(f, " ({})", self.access)?;
+        }
+        let plural = if self.members == 1 { "" } else { "s" };
+        write!(
+            f,
+            " - {} member{}, created by {} at {}",
+            self.members, plural, self.creator, self.created
+        )?;
+        if let Some(topic) = &self.topic {
+            write!(f, " - {}", topic)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a user is allowed to do in a room. Roles are tied to accounts rather than connections or
+/// names, so they are kept when a user leaves and later rejoins the room or changes their name.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode, Serialize, Deserialize,
+)]
+pub enum RoomRole {
+    Member,
+    Moderator,
+    /// The user who created the room, unless they handed it over to someone else.
+    Owner,
+}
+
+impl RoomRole {
+    pub fn can(&self, permission: RoomPermission) -> bool {
+        *self >= permission.required_role()
+    }
+}
+
+impl Display for RoomRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomRole::Member => write!(f, "member"),
+            RoomRole::Moderator => write!(f, "moderator"),
+            RoomRole::Owner => write!(f, "owner"),
+        }
+    }
+}
+
+/// Actions in a room that not every member may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoomPermission {
+    ManageRoles,
+    Kick,
+    Ban,
+    Mute,
+    SetTopic,
+    SetDescription,
+    SetMode,
+    Invite,
+    DeleteRoom,
+    /// Delete messages sent to the room by others.
+    DeleteMessages,
+}
+
+impl RoomPermission {
+    /// The lowest role allowed to take this action.
+    pub fn required_role(&self) -> RoomRole {
+        match self {
+            RoomPermission::ManageRoles
+            | RoomPermission::SetDescription
+            | RoomPermission::SetMode
+            | RoomPermission::Invite
+            | RoomPermission::DeleteRoom => RoomRole::Owner,
+            RoomPermission::Kick
+            | RoomPermission::Ban
+            | RoomPermission::Mute
+            | RoomPermission::SetTopic
+            | RoomPermission::DeleteMessages => RoomRole::Moderator,
+        }
+    }
+}
+
+impl Display for RoomPermission {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomPermission::ManageRoles => write!(f, "manage roles"),
+            RoomPermission::Kick => write!(f, "kick users"),
+            RoomPermission::Ban => write!(f, "ban users"),
+            RoomPermission::Mute => write!(f, "mute users"),
+            RoomPermission::SetTopic => write!(f, "change the topic"),
+            RoomPermission::SetDescription => write!(f, "change the description"),
+            RoomPermission::SetMode => write!(f, "change the room mode"),
+            RoomPermission::Invite => write!(f, "invite users"),
+            RoomPermission::DeleteRoom => write!(f, "delete the room"),
+            RoomPermission::DeleteMessages => write!(f, "delete the messages of others"),
+        }
+    }
+}
+
+/// When a room goes away on its own. The default keeps it until it is deleted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoomLifecycle {
+    /// Delete the room as soon as its last member leaves.
+    pub delete_when_empty: bool,
+    /// Delete the room once nobody has joined it or sent anything to it for this long.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// When a ban or mute runs out, `None` if it never does.
+type Expiry = Option<Timestamp>;
+
+fn in_effect(expiry: &Expiry) -> bool {
+    expiry.is_none_or(|until| until > Timestamp::now())
+}
+
+/// " (reason)", or nothing.
+fn because(reason: &Option<String>) -> String {
+    reason
+        .as_ref()
+        .map(|reason| format!(" ({})", reason))
+        .unwrap_or_default()
+}
+
+/// Runs a room. Members are known by the name they go by, while roles, invitations, bans and
+/// mutes are kept by account so that changing names does not get around them.
 pub struct RoomManager {
     room_name: RoomName,
     users: HashSet<User>,
+    /// The account of the owner.
+    owner: UserName,
+    /// Who created the room, which stays the same when the room is handed over.
+    creator: UserName,
+    created: Timestamp,
+    topic: Option<String>,
+    description: Option<String>,
+    moderators: HashSet<UserName>,
+    mode: RoomMode,
+    /// Users allowed in whatever the mode, until they are uninvited.
+    invited: HashSet<UserName>,
+    lifecycle: RoomLifecycle,
+    /// When someone last joined the room or sent a message to it.
+    last_active: Instant,
+    bans: HashMap<UserName, Expiry>,
+    mutes: HashMap<UserName, Expiry>,
+    /// Members typing a message to the room.
+    typing: TypingTracker<UserName>,
     room_rx: mpsc::Receiver<RoomMessage>,
     user_processor_tx: mpsc::Sender<UserMessage>,
+    history_processor_tx: mpsc::Sender<HistoryMessage>,
 }
 
 impl RoomManager {
     pub fn new(
         room_name: impl Into<RoomName>,
+        owner: UserName,
+        mode: RoomMode,
+        lifecycle: RoomLifecycle,
         user_processor_tx: Sender<UserMessage>,
+        history_processor_tx: Sender<HistoryMessage>,
+        queue_size: usize,
     ) -> (Self, mpsc::Sender<RoomMessage>) {
-        let (room_tx, room_rx) = mpsc::channel(32);
+        let (room_tx, room_rx) = mpsc::channel(queue_size);
         (
             Self {
                 room_name: room_name.into(),
                 users: HashSet::new(),
+                creator: owner.clone(),
+                created: Timestamp::now(),
+                topic: None,
+                description: None,
+                owner,
+                moderators: HashSet::new(),
+                mode,
+                invited: HashSet::new(),
+                lifecycle,
+                last_active: Instant::now(),
+                bans: HashMap::new(),
+                mutes: HashMap::new(),
+                typing: TypingTracker::default(),
                 room_rx,
                 user_processor_tx,
+                history_processor_tx,
             },
             room_tx,
         )
@@ -94,7 +398,18 @@ impl RoomManager {
                 message: UserInternal::GetUser(user_info_tx),
             })
             .await?;
-        user_info_rx.await.unwrap()
+        user_info_rx.await?
+    }
+
+    async fn get_room_history(&mut self) -> Result<Vec<ChatRecord>> {
+        let (history_tx, history_rx) = oneshot::channel();
+        self.history_processor_tx
+            .send(HistoryMessage::RoomHistory {
+                room_name: self.room_name.clone(),
+                sender: history_tx,
+            })
+            .await?;
+        Ok(history_rx.await.unwrap_or_default())
     }
 
     pub fn users_in_room(&self) -> bool {
@@ -121,128 +436,1367 @@ impl RoomManager {
         if self.users.remove(user) {
             Ok(())
         } else {
-            Err(CommonError::UserNotInRoom(user.clone()))
+            Err(CommonError::UserNotInRoom(user.user_name().clone()))
+        }
+    }
+
+    /// The account of `user`, who goes by the name of their account if they are not online.
+    async fn login_of(&mut self, user: &UserName) -> Result<UserName> {
+        if let Some(member) = self.find_user(user) {
+            return Ok(member.login().clone());
+        }
+        match self.get_user_info(user.clone()).await {
+            Ok(user) => Ok(user.login().clone()),
+            Err(CommonError::UserNotExists(_)) => Ok(user.clone()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Let `from_user` in, sending them what was said in the room so far and telling the other
+    /// members.
+    async fn join(
+        &mut self,
+        from_user: &UserName,
+        login: &UserName,
+        password: Option<&RoomPassword>,
+    ) -> Result<()> {
+        self.check_banned(login)?;
+        self.check_access(login, password)?;
+        let user = self.get_user_info(from_user.clone()).await?;
+        self.add_user(user.clone())?;
+        self.report_joined(&user).await?;
+        self.last_active = Instant::now();
+        let messages = self.get_room_history().await?;
+        if !messages.is_empty() {
+            user.user_tx()
+                .send(ServerMessage {
+                    from_user: from_user.clone(),
+                    content: ServerInternal::RoomHistory {
+                        room: self.room_name.clone(),
+                        messages,
+                    },
+                })
+                .await?;
+        }
+        let message = format!("{} joined the room", from_user);
+        self.send_room_message(from_user.clone(), message).await
+    }
+
+    async fn leave(&mut self, from_user: &UserName) -> Result<()> {
+        let user = self.get_user_info(from_user.clone()).await?;
+        self.remove_user(&user)?;
+        self.report_left(from_user).await?;
+        let message = format!("{} left the room", from_user);
+        self.announce(from_user, message).await
+    }
+
+    /// Send `content` to `user` alone.
+    async fn send_to_user(&mut self, user: UserName, content: ServerInternal) -> Result<()> {
+        self.get_user_info(user.clone())
+            .await?
+            .user_tx()
+            .send(ServerMessage {
+                from_user: user,
+                content,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Carry the place of a member over to their new name. Their roles and mutes go with their
+    /// account and need no carrying over.
+    async fn rename(&mut self, from: &UserName, user: User) -> Result<()> {
+        let Some(member) = self.find_user(from).filter(|m| m.same_session(&user)) else {
+            return Ok(());
+        };
+        let to = user.user_name().clone();
+        self.users.remove(&member);
+        self.users.insert(user);
+        self.announce(&to, format!("{} is now known as {}", from, to))
+            .await
+    }
+
+    /// Tell the user processor that `session` is in the room, so that it is taken out of the room
+    /// when it disconnects.
+    async fn report_joined(&self, session: &User) -> Result<()> {
+        self.user_processor_tx
+            .send(UserMessage {
+                from_user: session.user_name().clone(),
+                message: UserInternal::JoinedRoom {
+                    room: self.room_name.clone(),
+                    session: session.clone(),
+                },
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn report_left(&self, user: &UserName) -> Result<()> {
+        self.user_processor_tx
+            .send(UserMessage {
+                from_user: user.clone(),
+                message: UserInternal::LeftRoom {
+                    room: self.room_name.clone(),
+                },
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The role of the account `login`.
+    pub fn role_of(&self, login: &UserName) -> RoomRole {
+        if *login == self.owner {
+            RoomRole::Owner
+        } else if self.moderators.contains(login) {
+            RoomRole::Moderator
+        } else {
+            RoomRole::Member
+        }
+    }
+
+    pub fn check_permission(&self, login: &UserName, permission: RoomPermission) -> Result<()> {
+        if self.role_of(login).can(permission) {
+            Ok(())
+        } else {
+            Err(CommonError::PermissionDenied(permission))
+        }
+    }
+
+    /// Give the member `user` a new role. Making someone the owner hands the room over to them,
+    /// the previous owner stays on as a moderator.
+    pub fn set_role(&mut self, user: &UserName, role: RoomRole) -> Result<()> {
+        let login = self
+            .find_user(user)
+            .ok_or_else(|| CommonError::UserNotInRoom(user.clone()))?
+            .login()
+            .clone();
+        if login == self.owner {
+            return Err(CommonError::OwnerRoleChange(self.room_name.clone()));
+        }
+        match role {
+            RoomRole::Member => {
+                self.moderators.remove(&login);
+            }
+            RoomRole::Moderator => {
+                self.moderators.insert(login);
+            }
+            RoomRole::Owner => {
+                self.moderators.remove(&login);
+                let previous = std::mem::replace(&mut self.owner, login);
+                self.moderators.insert(previous);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the account `login` may join given the mode of the room and the password they
+    /// gave, if any.
+    pub fn check_access(&self, login: &UserName, password: Option<&RoomPassword>) -> Result<()> {
+        if self.role_of(login) > RoomRole::Member || self.invited.contains(login) {
+            return Ok(());
+        }
+        match &self.mode {
+            RoomMode::Public | RoomMode::Hidden => Ok(()),
+            RoomMode::Password(expected) if password == Some(expected) => Ok(()),
+            RoomMode::Password(_) => Err(CommonError::WrongRoomPassword(self.room_name.clone())),
+            RoomMode::InviteOnly => Err(CommonError::NotInvited(self.room_name.clone())),
+        }
+    }
+
+    pub fn is_listed(&self) -> bool {
+        self.mode.is_listed()
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            name: self.room_name.clone(),
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            created: self.created,
+            creator: self.creator.clone(),
+            members: self.users.len(),
+            access: self.mode.access(),
+        }
+    }
+
+    /// Change the topic, or clear it with `None`.
+    async fn set_topic(&mut self, by: &UserName, topic: Option<String>) -> Result<()> {
+        let message = match &topic {
+            Some(topic) => format!("{} changed the topic to: {}", by, topic),
+            None => format!("{} cleared the topic", by),
+        };
+        self.topic = topic;
+        self.announce(by, message).await
+    }
+
+    /// Change the description, or clear it with `None`.
+    async fn set_description(&mut self, by: &UserName, description: Option<String>) -> Result<()> {
+        let message = match &description {
+            Some(description) => format!("{} changed the description to: {}", by, description),
+            None => format!("{} cleared the description", by),
+        };
+        self.description = description;
+        self.announce(by, message).await
+    }
+
+    /// Whether the room shows up when `user`, logged in as `login`, lists the rooms.
+    pub fn visible_to(&self, user: &UserName, login: &UserName) -> bool {
+        self.is_listed()
+            || self.role_of(login) > RoomRole::Member
+            || self.invited.contains(login)
+            || self.find_user(user).is_some()
+    }
+
+    async fn set_mode(&mut self, by: &UserName, mode: RoomMode) -> Result<()> {
+        self.mode = mode;
+        let message = format!("{} made the room {}", by, self.mode);
+        self.announce(by, message).await
+    }
+
+    /// Let `user` in whatever the mode, telling them if they are online.
+    async fn invite(&mut self, by: &UserName, user: &UserName) -> Result<()> {
+        match self.get_user_info(user.clone()).await {
+            Ok(invited) => {
+                self.invited.insert(invited.login().clone());
+                let invitation = ServerMessage {
+                    from_user: by.clone(),
+                    content: ServerInternal::RoomInvite {
+                        room: self.room_name.clone(),
+                        by: by.clone(),
+                    },
+                };
+                if let Err(e) = invited.user_tx().send(invitation).await {
+                    warn!("Failed to invite {}: {}", user, e);
+                }
+            }
+            Err(CommonError::UserNotExists(_)) => {
+                self.invited.insert(user.clone());
+                debug!("{} is not online to hear about the invitation", user)
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    async fn uninvite(&mut self, user: &UserName) -> Result<()> {
+        let login = self.login_of(user).await?;
+        if self.invited.remove(&login) {
+            Ok(())
+        } else {
+            Err(CommonError::UserNotInvited(user.clone()))
+        }
+    }
+
+    /// Moderators may only act on users below their own role. `user` is the account of the user
+    /// they act on, who goes by `name`.
+    fn check_outranks(&self, by: &UserName, user: &UserName, name: &UserName) -> Result<()> {
+        if self.role_of(user) >= self.role_of(by) {
+            return Err(CommonError::Outranked(name.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn check_banned(&mut self, login: &UserName) -> Result<()> {
+        match self.bans.get(login) {
+            Some(expiry) if in_effect(expiry) => {
+                Err(CommonError::Banned(self.room_name.clone(), *expiry))
+            }
+            Some(_) => {
+                self.bans.remove(login);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_muted(&mut self, login: &UserName) -> Result<()> {
+        match self.mutes.get(login) {
+            Some(expiry) if in_effect(expiry) => {
+                Err(CommonError::Muted(self.room_name.clone(), *expiry))
+            }
+            Some(_) => {
+                self.mutes.remove(login);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Members may send messages to the room unless they are muted. Banned users are told so,
+    /// rather than that they are not in the room.
+    fn check_can_send(&mut self, user: &UserName, login: &UserName) -> Result<()> {
+        self.check_banned(login)?;
+        if self.find_user(user).is_none() {
+            return Err(CommonError::UserNotInRoom(user.clone()));
+        }
+        self.check_muted(login)
+    }
+
+    fn find_user(&self, user: &UserName) -> Option<User> {
+        self.users.iter().find(|u| u.user_name() == user).cloned()
+    }
+
+    /// Remove `user` from the room, telling them why. The moderator goes by `by` and logged in
+    /// as `by_login`, as in the other moderation commands.
+    async fn kick(
+        &mut self,
+        by: &UserName,
+        by_login: &UserName,
+        user: &UserName,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let kicked = self
+            .find_user(user)
+            .ok_or_else(|| CommonError::UserNotInRoom(user.clone()))?;
+        self.check_outranks(by_login, kicked.login(), user)?;
+        self.users.remove(&kicked);
+        self.report_left(user).await?;
+        let message = format!("{} was kicked by {}{}", user, by, because(&reason));
+        self.notify_moderated(user, by, Moderation::Kicked { reason })
+            .await;
+        self.announce(by, message).await
+    }
+
+    /// Ban `user`, removing them from the room if they are in it.
+    async fn ban(
+        &mut self,
+        by: &UserName,
+        by_login: &UserName,
+        user: &UserName,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let login = self.login_of(user).await?;
+        self.check_outranks(by_login, &login, user)?;
+        let until = duration.map(Timestamp::after);
+        self.bans.insert(login, until);
+        if let Some(banned) = self.find_user(user) {
+            self.users.remove(&banned);
+            self.report_left(user).await?;
+        }
+        let message = format!("{} was banned by {}{}", user, by, because(&reason));
+        self.notify_moderated(user, by, Moderation::Banned { until, reason })
+            .await;
+        self.announce(by, message).await
+    }
+
+    async fn unban(&mut self, by: &UserName, user: &UserName) -> Result<()> {
+        let login = self.login_of(user).await?;
+        match self.bans.remove(&login) {
+            Some(expiry) if in_effect(&expiry) => {
+                self.notify_moderated(user, by, Moderation::Unbanned).await;
+                self.announce(by, format!("{} was unbanned by {}", user, by))
+                    .await
+            }
+            _ => Err(CommonError::UserNotBanned(user.clone())),
         }
     }
 
+    async fn mute(
+        &mut self,
+        by: &UserName,
+        by_login: &UserName,
+        user: &UserName,
+        duration: Option<Duration>,
+    ) -> Result<()> {
+        let login = self.login_of(user).await?;
+        self.check_outranks(by_login, &login, user)?;
+        let until = duration.map(Timestamp::after);
+        self.mutes.insert(login, until);
+        self.notify_moderated(user, by, Moderation::Muted { until })
+            .await;
+        self.announce(by, format!("{} was muted by {}", user, by))
+            .await
+    }
+
+    async fn unmute(&mut self, by: &UserName, user: &UserName) -> Result<()> {
+        let login = self.login_of(user).await?;
+        match self.mutes.remove(&login) {
+            Some(expiry) if in_effect(&expiry) => {
+                self.notify_moderated(user, by, Moderation::Unmuted).await;
+                self.announce(by, format!("{} was unmuted by {}", user, by))
+                    .await
+            }
+            _ => Err(CommonError::UserNotMuted(user.clone())),
+        }
+    }
+
+    /// Tell `user` what a moderator did to them, if they are online.
+    async fn notify_moderated(&mut self, user: &UserName, by: &UserName, action: Moderation) {
+        let Ok(target) = self.get_user_info(user.clone()).await else {
+            debug!("{} is not online to hear about {:?}", user, action);
+            return;
+        };
+        let notification = ServerMessage {
+            from_user: by.clone(),
+            content: ServerInternal::Moderated {
+                room: self.room_name.clone(),
+                by: by.clone(),
+                action,
+            },
+        };
+        if let Err(e) = target.user_tx().send(notification).await {
+            warn!("Failed to notify {}: {}", user, e);
+        }
+    }
+
+    /// Tell everyone in the room, if anyone is left.
+    async fn announce(&self, from_user: &UserName, message: String) -> Result<()> {
+        if !self.users_in_room() {
+            return Ok(());
+        }
+        self.send_room_message(from_user.clone(), message).await
+    }
+
+    /// The accounts of the moderators of the room, sorted by name.
+    pub fn list_moderators(&self) -> Vec<UserName> {
+        let mut moderators: Vec<_> = self.moderators.iter().cloned().collect();
+        moderators.sort_by(|a, b| a.user_name().cmp(b.user_name()));
+        moderators
+    }
+
+    /// Send a notice to every member, as a message from `from_user` that is not recorded.
     pub async fn send_room_message(
         &self,
         from_user: UserName,
         message: impl Into<String>,
     ) -> Result<()> {
-        if self.users.is_empty() {
-            return Err(CommonError::NoUsersInRoom);
-        }
-
-        let message = Arc::new(message.into());
-        let from_user = Arc::new(from_user);
-        let room_name = Arc::new(self.room_name().clone());
-
-        let mut senders = FuturesUnordered::new();
-
-        self.users.iter().for_each(|u| {
-            let message = Arc::clone(&message);
-            let from_user = Arc::clone(&from_user);
-            let room_name = Arc::clone(&room_name);
-            let user = u;
-
-            senders.push(async move {
-                user.user_tx()
-                    .send(ServerMessage {
-                        from_user: (*from_user).clone(),
-                        content: ServerInternal::RoomMessage {
-                            room: (*room_name).clone(),
-                            from: (*from_user).clone(),
-                            content: message.as_ref().clone(),
-                        },
-                    })
-                    .await
-            });
-        });
+        let record = ChatRecord::new(from_user, ChatTarget::Room(self.room_name.clone()), message);
+        self.send_record(record).await
+    }
+
+    /// Send a message to every member, with the ID and time it was given.
+    async fn send_record(&self, record: ChatRecord) -> Result<()> {
+        let content = ServerInternal::RoomMessage {
+            room: self.room_name.clone(),
+            id: record.id,
+            timestamp: record.timestamp,
+            from: record.from_user.clone(),
+            content: record.content,
+        };
+        self.send_to_members(record.from_user, content).await
+    }
+
+    /// Send `content` to every member at once. An empty room has nobody to tell.
+    async fn send_to_members(&self, from_user: UserName, content: ServerInternal) -> Result<()> {
+        let message = ServerMessage { from_user, content };
+        let mut senders: FuturesUnordered<_> = self
+            .users
+            .iter()
+            .map(|user| {
+                let message = message.clone();
+                async move { user.user_tx().send(message).await }
+            })
+            .collect();
 
-        let mut errors = Vec::new();
         while let Some(result) = senders.next().await {
+            // Sending only fails once the connection of a member is gone, the room hears of
+            // their departure shortly after.
             if let Err(e) = result {
                 warn!("Failed to send room message: {}", e);
-                errors.push(e);
             }
         }
-        if !errors.is_empty() {
-            Err(CommonError::RoomMessageNotSent)
-        } else {
-            Ok(())
+        Ok(())
+    }
+
+    /// Tell the other members that `user` started or stopped typing. Typing is not worth
+    /// holding up the room for, members whose queue is full miss it.
+    fn send_typing(&self, user: &UserName, typing: bool) {
+        let notice = ServerMessage {
+            from_user: user.clone(),
+            content: ServerInternal::Typing {
+                user: user.clone(),
+                room: Some(self.room_name.clone()),
+                typing,
+            },
+        };
+        for member in self.users.iter().filter(|u| u.user_name() != user) {
+            if let Err(e) = member.user_tx().try_send(notice.clone()) {
+                debug!("Unable to tell {} that {} is typing: {}", member, user, e);
+            }
+        }
+    }
+
+    /// The next message for the room, or `None` once the room has been idle for longer than its
+    /// lifecycle allows. Typing indicators that ran out are cleared in the meantime.
+    async fn next_message(&mut self) -> Option<RoomMessage> {
+        loop {
+            let idle_timeout = self.lifecycle.idle_timeout;
+            let idle_at = idle_timeout.map(|idle_timeout| self.last_active + idle_timeout);
+            let typing_expiry = self.typing.next_expiry();
+            tokio::select! {
+                message = self.room_rx.recv() => return message,
+                _ = tokio::time::sleep_until(idle_at.unwrap_or_else(Instant::now)), if idle_at.is_some() => {
+                    let idle_timeout = idle_timeout.unwrap_or_default();
+                    info!("Room {} has been idle for {}", self.room_name, format_duration(idle_timeout));
+                    self.notify_deleted(&UserName::new("server")).await;
+                    return None;
+                }
+                _ = tokio::time::sleep_until(typing_expiry.unwrap_or_else(Instant::now)), if typing_expiry.is_some() => {
+                    for user in self.typing.expire() {
+                        self.send_typing(&user, false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tell every member that the room is gone.
+    async fn notify_deleted(&self, by: &UserName) {
+        for user in self.users.iter() {
+            // Members that went away in the meantime do not need telling.
+            let _ = user
+                .user_tx()
+                .send(ServerMessage {
+                    from_user: by.clone(),
+                    content: ServerInternal::RoomDeleted {
+                        room: self.room_name.clone(),
+                    },
+                })
+                .await;
+        }
+    }
+
+    /// Stop taking messages, answering those already queued as if the room was gone. The room
+    /// processor drops the room once its queue is closed.
+    fn close(&mut self) {
+        self.room_rx.close();
+        while let Ok(message) = self.room_rx.try_recv() {
+            message
+                .reply
+                .error(&CommonError::RoomNotFound(self.room_name.clone()));
+        }
+    }
+
+    /// Handle the messages sent to the room until it is deleted, goes away on its own or fails.
+    pub async fn run(&mut self) -> Result<()> {
+        let result = self.serve().await;
+        self.close();
+        for user in self.users.iter() {
+            // Only fails once the user processor has stopped, when the server shuts down.
+            let _ = self.report_left(user.user_name()).await;
         }
+        result
     }
 
-    pub async fn run(mut self) -> Result<()> {
+    async fn serve(&mut self) -> Result<()> {
         while let Some(RoomMessage {
             from_user,
             room_name,
             message,
-        }) = self.room_rx.recv().await
+            reply,
+        }) = self.next_message().await
         {
+            let occupied = self.users_in_room();
+            let login = match self.login_of(&from_user).await {
+                Ok(login) => login,
+                Err(e) => {
+                    reply.error(&e);
+                    continue;
+                }
+            };
+            if let Some(permission) = message.permission() {
+                if let Err(e) = self.check_permission(&login, permission) {
+                    warn!("{} may not {} in {}", from_user, permission, room_name);
+                    reply.error(&e);
+                    continue;
+                }
+            }
             match message {
-                RoomInternal::NewRoom | RoomInternal::ListRooms => {
+                RoomInternal::NewRoom { .. } | RoomInternal::ListRooms => {
                     // Do nothing as a new room is created by the room handler
+                    reply.ack();
                 }
-                RoomInternal::JoinRoom => {
-                    let user = self.get_user_info(from_user.clone()).await?;
-
-                    match self.add_user(user.clone()) {
-                        Ok(_) => {
-                            let message = format!("{} joined the room", user.user_name());
-                            // Send room joined message to other users in the room
-                            self.send_room_message(user.user_name().clone(), message)
-                                .await?;
-                        }
+                RoomInternal::JoinRoom { password } => {
+                    match self.join(&from_user, &login, password.as_ref()).await {
+                        Ok(()) => reply.ack(),
                         Err(e) => {
                             warn!("Failed to add user to room: {}", e);
-                            user.user_tx()
-                                .send(ServerMessage {
-                                    from_user,
-                                    content: ServerInternal::Error(e.to_string()),
-                                })
-                                .await?;
+                            reply.error(&e);
                         }
                     }
                 }
-                RoomInternal::LeaveRoom => {
-                    let user = self.get_user_info(from_user.clone()).await?;
-                    match self.remove_user(&user) {
-                        Ok(_) => {
-                            // Send room left message to other users in the room
-                            let message = format!("{} left the room", user.user_name());
-                            self.send_room_message(from_user, message).await?;
-                        }
-                        Err(e) => {
-                            warn!("Failed to remove user from room: {}", e);
-                            user.user_tx()
-                                .send(ServerMessage {
-                                    from_user,
-                                    content: ServerInternal::Error(e.to_string()),
-                                })
-                                .await?;
-                        }
+                RoomInternal::LeaveRoom => match self.leave(&from_user).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => {
+                        warn!("Failed to remove user from room: {}", e);
+                        reply.error(&e);
                     }
-                }
+                },
                 RoomInternal::ListUsers => {
-                    let user = self.get_user_info(from_user.clone()).await?;
-                    let users = self.list_users();
-                    user.user_tx()
-                        .send(ServerMessage {
-                            from_user,
-                            content: ServerInternal::RoomUsers {
-                                room: room_name,
-                                users,
-                            },
+                    let users = ServerInternal::RoomUsers {
+                        room: room_name,
+                        users: self.list_users(),
+                    };
+                    match self.send_to_user(from_user, users).await {
+                        Ok(()) => reply.ack(),
+                        Err(e) => reply.error(&e),
+                    }
+                }
+                RoomInternal::RoomMessage(content) => {
+                    if let Err(e) = self.check_can_send(&from_user, &login) {
+                        reply.error(&e);
+                        continue;
+                    }
+                    let record =
+                        ChatRecord::new(from_user.clone(), ChatTarget::Room(room_name), content);
+                    let id = record.id;
+                    self.history_processor_tx
+                        .send(HistoryMessage::Record(Box::new(record.clone())))
+                        .await?;
+                    self.last_active = Instant::now();
+                    // Members seeing the message know the sender is done typing it.
+                    self.typing.stop(&from_user);
+                    self.send_record(record).await?;
+                    reply.sent(id);
+                }
+                RoomInternal::EditMessage(message) => {
+                    // Editing is as good as sending the message again.
+                    if let Err(e) = self.check_can_send(&from_user, &login) {
+                        reply.error(&e);
+                        continue;
+                    }
+                    info!(
+                        "{} edited message {} in {}",
+                        from_user, message.id, room_name
+                    );
+                    self.history_processor_tx
+                        .send(HistoryMessage::Edit(Box::new(message.clone())))
+                        .await?;
+                    self.send_to_members(
+                        from_user,
+                        ServerInternal::MessageEdited(Box::new(message)),
+                    )
+                    .await?;
+                    reply.ack();
+                }
+                RoomInternal::DeleteMessage { id, .. } => {
+                    info!("{} deleted message {} in {}", from_user, id, room_name);
+                    self.history_processor_tx
+                        .send(HistoryMessage::Delete(id))
+                        .await?;
+                    let deleted = ServerInternal::MessageDeleted {
+                        id,
+                        target: ChatTarget::Room(room_name),
+                        by: from_user.clone(),
+                    };
+                    self.send_to_members(from_user, deleted).await?;
+                    reply.ack();
+                }
+                RoomInternal::React { id, reaction, add } => {
+                    if let Err(e) = self.check_can_send(&from_user, &login) {
+                        reply.error(&e);
+                        continue;
+                    }
+                    let (sender, receiver) = oneshot::channel();
+                    self.history_processor_tx
+                        .send(HistoryMessage::React {
+                            id,
+                            user: from_user.clone(),
+                            reaction,
+                            add,
+                            sender,
                         })
                         .await?;
+                    match receiver.await.unwrap_or(Ok(None)) {
+                        Ok(Some(message)) => {
+                            let changed = ServerInternal::ReactionsChanged {
+                                id,
+                                target: ChatTarget::Room(room_name),
+                                reactions: message.reactions,
+                            };
+                            self.send_to_members(from_user, changed).await?;
+                            reply.ack();
+                        }
+                        Ok(None) => reply.error(&CommonError::MessageNotFound(id)),
+                        Err(e) => reply.error(&e),
+                    }
                 }
-                RoomInternal::RoomMessage(content) => {
-                    self.send_room_message(from_user, content).await?;
+                RoomInternal::Typing { typing } => {
+                    if self.find_user(&from_user).is_none() {
+                        reply.error(&CommonError::UserNotInRoom(from_user));
+                        continue;
+                    }
+                    // Muted members can't send what they are typing, so nobody waits for it.
+                    let changed = if typing && self.check_muted(&login).is_ok() {
+                        self.typing.start(from_user.clone())
+                    } else {
+                        self.typing.stop(&from_user)
+                    };
+                    if changed {
+                        self.send_typing(&from_user, typing);
+                    }
+                    reply.ack();
+                }
+                RoomInternal::SetRole { user, role } => match self.set_role(&user, role) {
+                    Ok(()) => {
+                        reply.ack();
+                        let message = match role {
+                            RoomRole::Member => format!("{} is no longer a moderator", user),
+                            RoomRole::Moderator => format!("{} is now a moderator", user),
+                            RoomRole::Owner => format!("{} is now the owner", user),
+                        };
+                        self.send_room_message(from_user, message).await?;
+                    }
+                    Err(e) => {
+                        warn!("Failed to change the role of {}: {}", user, e);
+                        reply.error(&e);
+                    }
+                },
+                RoomInternal::Kick { user, reason } => {
+                    match self.kick(&from_user, &login, &user, reason).await {
+                        Ok(()) => reply.ack(),
+                        Err(e) => reply.error(&e),
+                    }
+                }
+                RoomInternal::Ban {
+                    user,
+                    duration,
+                    reason,
+                } => match self.ban(&from_user, &login, &user, duration, reason).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::Unban { user } => match self.unban(&from_user, &user).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::Mute { user, duration } => {
+                    match self.mute(&from_user, &login, &user, duration).await {
+                        Ok(()) => reply.ack(),
+                        Err(e) => reply.error(&e),
+                    }
+                }
+                RoomInternal::Unmute { user } => match self.unmute(&from_user, &user).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::ListRoles => {
+                    let roles = ServerInternal::RoomRoles {
+                        room: room_name,
+                        owner: self.owner.clone(),
+                        moderators: self.list_moderators(),
+                    };
+                    match self.send_to_user(from_user, roles).await {
+                        Ok(()) => reply.ack(),
+                        Err(e) => reply.error(&e),
+                    }
+                }
+                RoomInternal::SetMode { mode } => match self.set_mode(&from_user, mode).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::Invite { user } => match self.invite(&from_user, &user).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::Uninvite { user } => match self.uninvite(&user).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::SetTopic { topic } => match self.set_topic(&from_user, topic).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                },
+                RoomInternal::SetDescription { description } => {
+                    match self.set_description(&from_user, description).await {
+                        Ok(()) => reply.ack(),
+                        Err(e) => reply.error(&e),
+                    }
                 }
+                RoomInternal::Listing(sender) => {
+                    let info = self.visible_to(&from_user, &login).then(|| self.info());
+                    let _ = sender.send(info);
+                    reply.ack();
+                }
+                RoomInternal::DeleteRoom { forced } => {
+                    info!(
+                        "Room {} deleted by {}{}",
+                        room_name,
+                        from_user,
+                        if forced { " (admin)" } else { "" }
+                    );
+                    self.notify_deleted(&from_user).await;
+                    reply.ack();
+                    return Ok(());
+                }
+                RoomInternal::Renamed(user) => {
+                    self.rename(&from_user, user).await?;
+                    reply.ack();
+                }
+                RoomInternal::Departed(user) => {
+                    // The user may be back on a new connection that is not in the room.
+                    let member = self
+                        .users
+                        .get(&user)
+                        .is_some_and(|member| member.same_session(&user));
+                    if member {
+                        self.users.remove(&user);
+                        let message = format!("{} left the room (disconnected)", from_user);
+                        self.announce(&from_user, message).await?;
+                    }
+                    reply.ack();
+                }
+            }
+            if occupied && !self.users_in_room() && self.lifecycle.delete_when_empty {
+                info!("Room {} is empty", self.room_name);
+                return Ok(());
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::messages::{ErrorCode, ErrorMessage, Reply};
+    use crate::common::MessageId;
+    use std::sync::{Arc, Mutex};
+
+    /// The connected users, as the user processor knows them.
+    type Online = Arc<Mutex<HashMap<UserName, User>>>;
+
+    /// Run a room owned by `owner` on a task of its own, with stand-ins for the user processor
+    /// answering for the `Online` users and for the history processor knowing no messages.
+    fn spawn_room(owner: &str) -> (mpsc::Sender<RoomMessage>, Online) {
+        spawn_room_with(owner, RoomLifecycle::default())
+    }
+
+    fn spawn_room_with(
+        owner: &str,
+        lifecycle: RoomLifecycle,
+    ) -> (mpsc::Sender<RoomMessage>, Online) {
+        let online = Online::default();
+        let (user_processor_tx, mut user_processor_rx) = mpsc::channel(16);
+        let (history_processor_tx, mut history_processor_rx) = mpsc::channel(16);
+        let (mut room, room_tx) = RoomManager::new(
+            "lobby",
+            UserName::new(owner),
+            RoomMode::Public,
+            lifecycle,
+            user_processor_tx,
+            history_processor_tx,
+            16,
+        );
+        let users = online.clone();
+        tokio::spawn(async move {
+            while let Some(UserMessage { from_user, message }) = user_processor_rx.recv().await {
+                if let UserInternal::GetUser(sender) = message {
+                    let user = users.lock().unwrap().get(&from_user).cloned();
+                    let _ = sender.send(user.ok_or(CommonError::UserNotExists(from_user)));
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(message) = history_processor_rx.recv().await {
+                if let HistoryMessage::RoomHistory { sender, .. } = message {
+                    let _ = sender.send(Vec::new());
+                }
+            }
+        });
+        tokio::spawn(async move { room.run().await });
+        (room_tx, online)
+    }
+
+    /// Connect `name`, logged in as themselves. The receiver has to be kept for the room to be
+    /// able to send them anything.
+    fn connect(online: &Online, name: &str) -> mpsc::Receiver<ServerMessage> {
+        let (user, user_rx) = User::new(UserName::new(name), 64);
+        online.lock().unwrap().insert(UserName::new(name), user);
+        user_rx
+    }
+
+    /// Rename a connected user, telling the room like the room processor does.
+    async fn rename(room_tx: &mpsc::Sender<RoomMessage>, online: &Online, from: &str, to: &str) {
+        let renamed = {
+            let mut online = online.lock().unwrap();
+            let user = online.remove(&UserName::new(from)).unwrap();
+            let renamed = user.renamed(UserName::new(to));
+            online.insert(UserName::new(to), renamed.clone());
+            renamed
+        };
+        request(room_tx, from, RoomInternal::Renamed(renamed))
+            .await
+            .unwrap();
+    }
+
+    /// Disconnect a user, telling the room like the room processor does.
+    async fn disconnect(room_tx: &mpsc::Sender<RoomMessage>, online: &Online, name: &str) {
+        let user = online.lock().unwrap().remove(&UserName::new(name)).unwrap();
+        request(room_tx, name, RoomInternal::Departed(user))
+            .await
+            .unwrap();
+    }
+
+    async fn request(
+        room_tx: &mpsc::Sender<RoomMessage>,
+        from_user: &str,
+        message: RoomInternal,
+    ) -> std::result::Result<Option<MessageId>, ErrorMessage> {
+        let (reply, reply_rx) = Reply::new();
+        room_tx
+            .send(RoomMessage {
+                from_user: UserName::new(from_user),
+                room_name: RoomName::new("lobby"),
+                message,
+                reply,
+            })
+            .await
+            .unwrap();
+        reply_rx.await.unwrap()
+    }
+
+    async fn join(
+        room_tx: &mpsc::Sender<RoomMessage>,
+        user: &str,
+    ) -> std::result::Result<(), ErrorCode> {
+        request(room_tx, user, RoomInternal::JoinRoom { password: None })
+            .await
+            .map(|_| ())
+            .map_err(|e| e.code)
+    }
+
+    /// What `user` sees of the room when listing the rooms, if they see it at all.
+    async fn listing(room_tx: &mpsc::Sender<RoomMessage>, user: &str) -> Option<RoomInfo> {
+        let (sender, receiver) = oneshot::channel();
+        request(room_tx, user, RoomInternal::Listing(sender))
+            .await
+            .unwrap();
+        receiver.await.unwrap()
+    }
+
+    fn kick(user: &str) -> RoomInternal {
+        RoomInternal::Kick {
+            user: UserName::new(user),
+            reason: None,
+        }
+    }
+
+    fn ban(user: &str) -> RoomInternal {
+        RoomInternal::Ban {
+            user: UserName::new(user),
+            duration: None,
+            reason: None,
+        }
+    }
+
+    fn say(content: &str) -> RoomInternal {
+        RoomInternal::RoomMessage(content.to_string())
+    }
+
+    fn set_role(user: &str, role: RoomRole) -> RoomInternal {
+        RoomInternal::SetRole {
+            user: UserName::new(user),
+            role,
+        }
+    }
+
+    /// What the room sent `user` so far.
+    fn received(user_rx: &mut mpsc::Receiver<ServerMessage>) -> Vec<ServerInternal> {
+        let mut received = Vec::new();
+        while let Ok(message) = user_rx.try_recv() {
+            received.push(message.content);
+        }
+        received
+    }
+
+    /// What was said in the room that `user` heard so far.
+    fn heard(user_rx: &mut mpsc::Receiver<ServerMessage>) -> Vec<String> {
+        received(user_rx)
+            .into_iter()
+            .filter_map(|content| match content {
+                ServerInternal::RoomMessage { content, .. } => Some(content),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn room_names_ignore_case_and_are_validated() {
+        assert_eq!(RoomName::new(" Lobby "), RoomName::new("lobby"));
+        let encoded = bincode::encode_to_vec("LOBBY", bincode::config::standard()).unwrap();
+        let (decoded, _): (RoomName, _) =
+            bincode::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+        assert_eq!(decoded, "lobby");
+        let deserialized: RoomName = serde_json::from_str("\"LOBBY\"").unwrap();
+        assert_eq!(deserialized, "lobby");
+
+        assert!(RoomName::new("rust-lang_2").validate().is_ok());
+        let too_long = "x".repeat(MAX_ROOM_NAME_LENGTH + 1);
+        for invalid in ["", "two words", "#rust", &too_long] {
+            let validated = RoomName::new(invalid).validate();
+            assert!(matches!(validated, Err(CommonError::InvalidRoomName(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn bans_and_mutes_follow_renamed_users() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        let _carol = connect(&online, "carol");
+        join(&room_tx, "bob").await.unwrap();
+        join(&room_tx, "carol").await.unwrap();
+
+        request(&room_tx, "alice", ban("bob")).await.unwrap();
+        rename(&room_tx, &online, "bob", "robert").await;
+        assert_eq!(join(&room_tx, "robert").await, Err(ErrorCode::Banned));
+
+        let mute = RoomInternal::Mute {
+            user: UserName::new("carol"),
+            duration: None,
+        };
+        request(&room_tx, "alice", mute).await.unwrap();
+        rename(&room_tx, &online, "carol", "caroline").await;
+        let message = RoomInternal::RoomMessage("hello".to_string());
+        let sent = request(&room_tx, "caroline", message).await;
+        assert_eq!(sent.unwrap_err().code, ErrorCode::Muted);
+    }
+
+    #[tokio::test]
+    async fn roles_follow_renamed_users_and_not_their_old_names() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _carol = connect(&online, "carol");
+        let _dave = connect(&online, "dave");
+        join(&room_tx, "alice").await.unwrap();
+        join(&room_tx, "carol").await.unwrap();
+        join(&room_tx, "dave").await.unwrap();
+        let moderator = set_role("carol", RoomRole::Moderator);
+        request(&room_tx, "alice", moderator).await.unwrap();
+
+        // The owner and the moderator keep their roles under their new names.
+        rename(&room_tx, &online, "alice", "al").await;
+        rename(&room_tx, &online, "carol", "cara").await;
+        let topic = RoomInternal::SetTopic {
+            topic: Some("renamed".to_string()),
+        };
+        request(&room_tx, "al", topic).await.unwrap();
+        request(&room_tx, "cara", kick("dave")).await.unwrap();
+
+        // Whoever takes a name given up later does not get the role that went with it.
+        disconnect(&room_tx, &online, "cara").await;
+        let _new_cara = connect(&online, "cara");
+        join(&room_tx, "cara").await.unwrap();
+        join(&room_tx, "dave").await.unwrap();
+        let kicked = request(&room_tx, "cara", kick("dave")).await;
+        assert_eq!(kicked.unwrap_err().code, ErrorCode::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn roles_decide_what_members_may_do() {
+        let (room_tx, online) = spawn_room("alice");
+        let mut alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        let _carol = connect(&online, "carol");
+        for user in ["alice", "bob", "carol"] {
+            join(&room_tx, user).await.unwrap();
+        }
+
+        let kicked = request(&room_tx, "bob", kick("carol")).await;
+        assert_eq!(kicked.unwrap_err().code, ErrorCode::PermissionDenied);
+        let moderator = set_role("bob", RoomRole::Moderator);
+        request(&room_tx, "alice", moderator).await.unwrap();
+        request(&room_tx, "bob", kick("carol")).await.unwrap();
+
+        // Only the owner gives out roles, and has to hand the room over to give up their own.
+        let owner = request(&room_tx, "bob", set_role("bob", RoomRole::Owner)).await;
+        assert_eq!(owner.unwrap_err().code, ErrorCode::PermissionDenied);
+        let member = request(&room_tx, "alice", set_role("alice", RoomRole::Member)).await;
+        assert_eq!(member.unwrap_err().code, ErrorCode::InvalidRequest);
+
+        // The previous owner stays on as a moderator.
+        let owner = set_role("bob", RoomRole::Owner);
+        request(&room_tx, "alice", owner).await.unwrap();
+        request(&room_tx, "alice", RoomInternal::ListRoles)
+            .await
+            .unwrap();
+        let roles = received(&mut alice)
+            .into_iter()
+            .find_map(|content| match content {
+                ServerInternal::RoomRoles {
+                    owner, moderators, ..
+                } => Some((owner, moderators)),
+                _ => None,
+            });
+        let (owner, moderators) = roles.unwrap();
+        assert_eq!(owner, UserName::new("bob"));
+        assert_eq!(moderators, vec![UserName::new("alice")]);
+    }
+
+    #[tokio::test]
+    async fn moderators_only_act_on_those_below_them() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        let mut carol = connect(&online, "carol");
+        let mut dave = connect(&online, "dave");
+        for user in ["alice", "bob", "carol", "dave"] {
+            join(&room_tx, user).await.unwrap();
+        }
+        for moderator in ["bob", "carol"] {
+            let role = set_role(moderator, RoomRole::Moderator);
+            request(&room_tx, "alice", role).await.unwrap();
+        }
+
+        let kicked = request(&room_tx, "bob", kick("alice")).await;
+        assert_eq!(kicked.unwrap_err().code, ErrorCode::PermissionDenied);
+        let banned = request(&room_tx, "bob", ban("carol")).await;
+        assert_eq!(banned.unwrap_err().code, ErrorCode::PermissionDenied);
+
+        let mute = RoomInternal::Mute {
+            user: UserName::new("dave"),
+            duration: None,
+        };
+        request(&room_tx, "bob", mute).await.unwrap();
+        let sent = request(&room_tx, "dave", say("hello")).await;
+        assert_eq!(sent.unwrap_err().code, ErrorCode::Muted);
+        let unmute = RoomInternal::Unmute {
+            user: UserName::new("dave"),
+        };
+        request(&room_tx, "bob", unmute).await.unwrap();
+        request(&room_tx, "dave", say("hello")).await.unwrap();
+
+        request(&room_tx, "bob", ban("dave")).await.unwrap();
+        assert_eq!(join(&room_tx, "dave").await, Err(ErrorCode::Banned));
+        let unban = RoomInternal::Unban {
+            user: UserName::new("dave"),
+        };
+        request(&room_tx, "bob", unban).await.unwrap();
+        let unbanned = received(&mut dave).into_iter().any(|content| {
+            matches!(
+                content,
+                ServerInternal::Moderated {
+                    action: Moderation::Unbanned,
+                    ..
+                }
+            )
+        });
+        assert!(unbanned);
+        assert!(heard(&mut carol).contains(&"dave was unbanned by bob".to_string()));
+        join(&room_tx, "dave").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bans_and_mutes_run_out() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        join(&room_tx, "bob").await.unwrap();
+
+        let ban = RoomInternal::Ban {
+            user: UserName::new("bob"),
+            duration: Some(Duration::from_millis(50)),
+            reason: None,
+        };
+        request(&room_tx, "alice", ban).await.unwrap();
+        assert_eq!(join(&room_tx, "bob").await, Err(ErrorCode::Banned));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        join(&room_tx, "bob").await.unwrap();
+        let unban = RoomInternal::Unban {
+            user: UserName::new("bob"),
+        };
+        let unbanned = request(&room_tx, "alice", unban).await;
+        assert_eq!(unbanned.unwrap_err().code, ErrorCode::InvalidRequest);
+
+        let mute = RoomInternal::Mute {
+            user: UserName::new("bob"),
+            duration: Some(Duration::from_millis(50)),
+        };
+        request(&room_tx, "alice", mute).await.unwrap();
+        let sent = request(&room_tx, "bob", say("hello")).await;
+        assert_eq!(sent.unwrap_err().code, ErrorCode::Muted);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        request(&room_tx, "bob", say("hello")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn modes_decide_who_may_join() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        let mut carol = connect(&online, "carol");
+        join(&room_tx, "alice").await.unwrap();
+
+        let mode = RoomInternal::SetMode {
+            mode: RoomMode::Password(RoomPassword::new("secret")),
+        };
+        request(&room_tx, "alice", mode).await.unwrap();
+        assert_eq!(
+            join(&room_tx, "bob").await,
+            Err(ErrorCode::WrongRoomPassword)
+        );
+        let join_with = |password: &str| RoomInternal::JoinRoom {
+            password: Some(RoomPassword::new(password)),
+        };
+        let joined = request(&room_tx, "bob", join_with("guess")).await;
+        assert_eq!(joined.unwrap_err().code, ErrorCode::WrongRoomPassword);
+        request(&room_tx, "bob", join_with("secret")).await.unwrap();
+
+        let mode = RoomInternal::SetMode {
+            mode: RoomMode::InviteOnly,
+        };
+        request(&room_tx, "alice", mode).await.unwrap();
+        assert_eq!(join(&room_tx, "carol").await, Err(ErrorCode::NotInvited));
+        let invite = RoomInternal::Invite {
+            user: UserName::new("carol"),
+        };
+        request(&room_tx, "alice", invite).await.unwrap();
+        let alice = UserName::new("alice");
+        let invited = received(&mut carol)
+            .into_iter()
+            .any(|content| matches!(content, ServerInternal::RoomInvite { by, .. } if by == alice));
+        assert!(invited);
+        join(&room_tx, "carol").await.unwrap();
+
+        let uninvite = RoomInternal::Uninvite {
+            user: UserName::new("dave"),
+        };
+        let uninvited = request(&room_tx, "alice", uninvite).await;
+        assert_eq!(uninvited.unwrap_err().code, ErrorCode::InvalidRequest);
+    }
+
+    #[tokio::test]
+    async fn hidden_rooms_are_only_listed_to_those_let_in() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        assert!(listing(&room_tx, "bob").await.is_some());
+
+        let mode = RoomInternal::SetMode {
+            mode: RoomMode::Hidden,
+        };
+        request(&room_tx, "alice", mode).await.unwrap();
+        assert!(listing(&room_tx, "alice").await.is_some());
+        assert!(listing(&room_tx, "bob").await.is_none());
+        let invite = RoomInternal::Invite {
+            user: UserName::new("bob"),
+        };
+        request(&room_tx, "alice", invite).await.unwrap();
+        let info = listing(&room_tx, "bob").await.unwrap();
+        assert_eq!(info.access, RoomAccess::Hidden);
+    }
+
+    #[tokio::test]
+    async fn topics_and_descriptions_are_announced_and_listed() {
+        let (room_tx, online) = spawn_room("alice");
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        let mut carol = connect(&online, "carol");
+        for user in ["alice", "bob", "carol"] {
+            join(&room_tx, user).await.unwrap();
+        }
+        let moderator = set_role("bob", RoomRole::Moderator);
+        request(&room_tx, "alice", moderator).await.unwrap();
+        let topic = |topic: Option<&str>| RoomInternal::SetTopic {
+            topic: topic.map(str::to_string),
+        };
+        let description = |description: &str| RoomInternal::SetDescription {
+            description: Some(description.to_string()),
+        };
+
+        let changed = request(&room_tx, "carol", topic(Some("cats"))).await;
+        assert_eq!(changed.unwrap_err().code, ErrorCode::PermissionDenied);
+        request(&room_tx, "bob", topic(Some("plans")))
+            .await
+            .unwrap();
+        let changed = request(&room_tx, "bob", description("plans")).await;
+        assert_eq!(changed.unwrap_err().code, ErrorCode::PermissionDenied);
+        let changed = request(&room_tx, "alice", description("where plans are made"));
+        changed.await.unwrap();
+
+        let said = heard(&mut carol);
+        assert!(said.contains(&"bob changed the topic to: plans".to_string()));
+        let described = "alice changed the description to: where plans are made";
+        assert!(said.contains(&described.to_string()));
+        let info = listing(&room_tx, "carol").await.unwrap();
+        assert_eq!(info.topic.as_deref(), Some("plans"));
+        assert_eq!(info.description.as_deref(), Some("where plans are made"));
+        assert_eq!(info.creator, UserName::new("alice"));
+        assert_eq!(info.members, 3);
+
+        request(&room_tx, "alice", topic(None)).await.unwrap();
+        assert_eq!(
+            heard(&mut carol),
+            vec!["alice cleared the topic".to_string()]
+        );
+        assert_eq!(listing(&room_tx, "carol").await.unwrap().topic, None);
+    }
+
+    #[tokio::test]
+    async fn rooms_can_go_away_once_empty() {
+        let lifecycle = RoomLifecycle {
+            delete_when_empty: true,
+            idle_timeout: None,
+        };
+        let (room_tx, online) = spawn_room_with("alice", lifecycle);
+        let _alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        join(&room_tx, "alice").await.unwrap();
+        join(&room_tx, "bob").await.unwrap();
+
+        request(&room_tx, "alice", RoomInternal::LeaveRoom)
+            .await
+            .unwrap();
+        assert!(!room_tx.is_closed());
+        request(&room_tx, "bob", RoomInternal::LeaveRoom)
+            .await
+            .unwrap();
+        room_tx.closed().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_rooms_go_away() {
+        let idle_timeout = Duration::from_secs(60);
+        let lifecycle = RoomLifecycle {
+            delete_when_empty: false,
+            idle_timeout: Some(idle_timeout),
+        };
+        let (room_tx, online) = spawn_room_with("alice", lifecycle);
+        let mut bob = connect(&online, "bob");
+        join(&room_tx, "bob").await.unwrap();
+
+        // Messages keep the room around, listing it does not.
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        request(&room_tx, "bob", say("still here")).await.unwrap();
+        let active = Instant::now();
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        listing(&room_tx, "bob").await.unwrap();
+        room_tx.closed().await;
+        assert!(active.elapsed() >= idle_timeout);
+        let deleted = received(&mut bob)
+            .into_iter()
+            .any(|content| matches!(content, ServerInternal::RoomDeleted { .. }));
+        assert!(deleted);
+    }
+
+    #[tokio::test]
+    async fn members_who_disconnect_leave_the_room() {
+        let (room_tx, online) = spawn_room("alice");
+        let mut alice = connect(&online, "alice");
+        let _bob = connect(&online, "bob");
+        join(&room_tx, "alice").await.unwrap();
+        join(&room_tx, "bob").await.unwrap();
+        // A connection of bob's that is gone already and never was in the room.
+        let (stale, _stale_rx) = User::new(UserName::new("bob"), 1);
+        request(&room_tx, "bob", RoomInternal::Departed(stale))
+            .await
+            .unwrap();
+        received(&mut alice);
+
+        disconnect(&room_tx, &online, "bob").await;
+        let said = heard(&mut alice);
+        assert_eq!(said, vec!["bob left the room (disconnected)".to_string()]);
+        request(&room_tx, "alice", RoomInternal::ListUsers)
+            .await
+            .unwrap();
+        let users = received(&mut alice)
+            .into_iter()
+            .find_map(|content| match content {
+                ServerInternal::RoomUsers { users, .. } => Some(users),
+                _ => None,
+            });
+        assert_eq!(users.unwrap(), vec![UserName::new("alice")]);
+    }
+}
@@ -0,0 +1,63 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the unix epoch.
+#[derive(
+    Debug, Clone, Copy, Encode, Decode, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Self(millis)
+    }
+
+    /// The time `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        Self(Self::now().0.saturating_add(duration.as_millis() as u64))
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// How long ago this was, zero if it is yet to come.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(Self::now().0.saturating_sub(self.0))
+    }
+
+    /// How long until this time, zero if it has passed.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(Self::now().0))
+    }
+}
+
+/// Formats the time of day in UTC, which is all a chat line needs.
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let seconds = (self.0 / 1000) % (24 * 60 * 60);
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            (seconds / 60) % 60,
+            seconds % 60
+        )
+    }
+}
+
+/// A short human readable duration like "5m", rounded up to its largest unit.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs().max(1);
+    [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")]
+        .into_iter()
+        .find(|(unit, _)| seconds >= *unit)
+        .map(|(unit, suffix)| format!("{}{}", seconds.div_ceil(unit), suffix))
+        .unwrap_or_else(|| format!("{}s", seconds))
+}
@@ -0,0 +1,107 @@
+use std::{collections::HashMap, hash::Hash, time::Duration};
+use tokio::time::Instant;
+
+/// How long someone is shown as typing without hearing from them again. Clients renew the
+/// indicator well within this while the user keeps typing.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Who is typing, and until when. Only changes are passed on to others, so starting again
+/// while already typing only pushes the expiry back.
+#[derive(Debug)]
+pub struct TypingTracker<K> {
+    until: HashMap<K, Instant>,
+}
+
+impl<K> Default for TypingTracker<K> {
+    fn default() -> Self {
+        Self {
+            until: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> TypingTracker<K> {
+    /// Note that `key` is typing, returning whether they just started.
+    pub fn start(&mut self, key: K) -> bool {
+        self.until
+            .insert(key, Instant::now() + TYPING_TIMEOUT)
+            .is_none()
+    }
+
+    /// Note that `key` stopped typing, returning whether they were.
+    pub fn stop(&mut self, key: &K) -> bool {
+        self.until.remove(key).is_some()
+    }
+
+    /// When the next indicator runs out, if anyone is typing.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.until.values().min().copied()
+    }
+
+    /// Forget those who have not been heard from in time, returning them.
+    pub fn expire(&mut self) -> Vec<K> {
+        let now = Instant::now();
+        let expired: Vec<K> = self
+            .until
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.until.remove(key);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn only_changes_are_reported() {
+        let mut typing = TypingTracker::default();
+        assert!(typing.start("alice"));
+        assert!(!typing.start("alice"));
+        assert!(typing.stop(&"alice"));
+        assert!(!typing.stop(&"alice"));
+        assert_eq!(typing.next_expiry(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn indicators_run_out() {
+        let mut typing = TypingTracker::default();
+        typing.start("alice");
+        tokio::time::advance(Duration::from_secs(2)).await;
+        typing.start("bob");
+        assert_eq!(
+            typing.next_expiry(),
+            Some(Instant::now() + TYPING_TIMEOUT - Duration::from_secs(2))
+        );
+
+        tokio::time::advance(TYPING_TIMEOUT - Duration::from_secs(2)).await;
+        assert_eq!(typing.expire(), ["alice"]);
+        assert_eq!(
+            typing.next_expiry(),
+            Some(Instant::now() + Duration::from_secs(2))
+        );
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(typing.expire(), ["bob"]);
+        assert!(typing.expire().is_empty());
+        assert_eq!(typing.next_expiry(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn typing_again_pushes_the_expiry_back() {
+        let mut typing = TypingTracker::default();
+        typing.start("alice");
+        tokio::time::advance(TYPING_TIMEOUT - Duration::from_secs(1)).await;
+        typing.start("alice");
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(typing.expire().is_empty());
+
+        tokio::time::advance(TYPING_TIMEOUT).await;
+        assert_eq!(typing.expire(), ["alice"]);
+    }
+}
@@ -1,30 +1,130 @@
 use super::messages::ServerMessage;
-use super::{CommonError, Result};
+use super::{
+    AccountStore, ChatRecord, CommonError, MailboxStore, MessageId, Result, RoomName,
+    SharedAccountStore,
+};
 
 use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Debug, Display, Formatter},
     hash::Hash,
+    net::IpAddr,
 };
 use tokio::sync::mpsc;
 use tracing::error;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+pub const MAX_USER_NAME_LENGTH: usize = 24;
 
-#[derive(Debug, Clone, Encode, Decode, PartialEq, Hash, Eq)]
+/// The name of a user. Names that only differ in case or in characters that look alike, like
+/// `Alice` and an `alice` spelled with a Cyrillic `а`, are equal so that nobody can pass for
+/// someone else.
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct UserName {
     user_name: String,
 }
 
 impl UserName {
+    /// A name taken as is, for names chosen by the server itself. Names chosen by users go
+    /// through [`UserName::parse`].
     pub fn new(username: impl Into<String>) -> Self {
         Self {
             user_name: username.into(),
         }
     }
 
+    /// Check and normalise a name chosen by a user: up to [`MAX_USER_NAME_LENGTH`] characters,
+    /// without whitespace or control characters.
+    pub fn parse(username: &str) -> std::result::Result<Self, InvalidUserName> {
+        let user_name: String = username.trim().nfc().collect();
+        if user_name.is_empty() {
+            return Err(InvalidUserName::Empty);
+        }
+        if user_name.chars().count() > MAX_USER_NAME_LENGTH {
+            return Err(InvalidUserName::TooLong {
+                max: MAX_USER_NAME_LENGTH,
+            });
+        }
+        if let Some(c) = user_name
+            .chars()
+            .find(|c| c.is_control() || c.is_whitespace())
+        {
+            return Err(InvalidUserName::InvalidCharacter(c));
+        }
+        Ok(Self { user_name })
+    }
+
     pub fn user_name(&self) -> &str {
         &self.user_name
     }
+
+    /// What names are compared by: case folded and reduced to the skeleton of Unicode's
+    /// confusables, so that look-alike names share one.
+    fn key(&self) -> String {
+        let folded: String = self.user_name.nfkc().flat_map(char::to_lowercase).collect();
+        skeleton(&folded).collect()
+    }
+}
+
+impl PartialEq for UserName {
+    fn eq(&self, other: &Self) -> bool {
+        self.user_name == other.user_name || self.key() == other.key()
+    }
+}
+
+impl Eq for UserName {}
+
+impl Hash for UserName {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl TryFrom<&str> for UserName {
+    type Error = InvalidUserName;
+
+    fn try_from(username: &str) -> std::result::Result<Self, Self::Error> {
+        Self::parse(username)
+    }
+}
+
+impl TryFrom<String> for UserName {
+    type Error = InvalidUserName;
+
+    fn try_from(username: String) -> std::result::Result<Self, Self::Error> {
+        Self::parse(&username)
+    }
+}
+
+/// Why a name was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub enum InvalidUserName {
+    Empty,
+    TooLong {
+        max: usize,
+    },
+    InvalidCharacter(char),
+    /// The server keeps the name for itself.
+    Reserved,
+}
+
+impl Display for InvalidUserName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidUserName::Empty => write!(f, "user names can't be empty"),
+            InvalidUserName::TooLong { max } => {
+                write!(f, "user names can be at most {} characters long", max)
+            }
+            InvalidUserName::InvalidCharacter(c) => {
+                write!(f, "user names can't have {:?} in them", c)
+            }
+            InvalidUserName::Reserved => write!(f, "that name is reserved"),
+        }
+    }
 }
 
 impl Display for UserName {
@@ -35,28 +135,70 @@ impl Display for UserName {
 
 impl PartialEq<&str> for UserName {
     fn eq(&self, other: &&str) -> bool {
-        self.user_name == *other
+        *self == UserName::new(*other)
     }
 }
 
 impl PartialEq<String> for UserName {
     fn eq(&self, other: &String) -> bool {
-        self.user_name == *other
+        *self == UserName::new(other.as_str())
     }
 }
 
-impl From<String> for UserName {
-    fn from(username: String) -> Self {
-        Self::new(username)
+/// Whether a user is around to chat, as they set it. Users who stay inactive for a while are
+/// set away by the server.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize,
+)]
+pub enum Presence {
+    #[default]
+    Online,
+    Away,
+    /// Around but not to be disturbed.
+    Busy,
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Away => write!(f, "away"),
+            Presence::Busy => write!(f, "busy"),
+        }
     }
 }
 
-impl From<&str> for UserName {
-    fn from(username: &str) -> Self {
-        Self::new(username.to_string())
+/// A connected user along with their presence and status text, as listed to clients.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub struct UserStatus {
+    pub user: UserName,
+    pub presence: Presence,
+    pub text: Option<String>,
+}
+
+impl Display for UserStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.user)?;
+        if self.presence != Presence::Online {
+            write!(f, " ({})", self.presence)?;
+        }
+        if let Some(text) = &self.text {
+            write!(f, " \"{}\"", text)?;
+        }
+        Ok(())
     }
 }
 
+/// What the server keeps of a user's presence.
+#[derive(Debug, Clone, Default)]
+struct PresenceState {
+    presence: Presence,
+    text: Option<String>,
+    /// Set away by the server for being inactive, rather than by the user. Such users are back
+    /// online as soon as they do something.
+    idle: bool,
+}
+
 // trait RoomStatus {
 //     fn is_in_room() -> bool;
 // }
@@ -82,6 +224,8 @@ impl From<&str> for UserName {
 #[derive(Debug, Clone)]
 pub struct User {
     user_name: UserName,
+    /// The account the user logged in with, which stays the same when they change their name.
+    login: UserName,
     user_tx: mpsc::Sender<ServerMessage>,
 }
 
@@ -106,11 +250,16 @@ impl Display for User {
 }
 
 impl User {
-    pub fn new(user_name: impl Into<UserName>) -> (Self, mpsc::Receiver<ServerMessage>) {
-        let (user_tx, user_rx) = mpsc::channel(16);
+    pub fn new(
+        user_name: impl Into<UserName>,
+        queue_size: usize,
+    ) -> (Self, mpsc::Receiver<ServerMessage>) {
+        let (user_tx, user_rx) = mpsc::channel(queue_size);
+        let user_name = user_name.into();
         (
             Self {
-                user_name: user_name.into(),
+                login: user_name.clone(),
+                user_name,
                 user_tx,
             },
             user_rx,
@@ -121,23 +270,112 @@ impl User {
         &self.user_name
     }
 
+    pub fn login(&self) -> &UserName {
+        &self.login
+    }
+
     pub fn user_tx(&self) -> mpsc::Sender<ServerMessage> {
         self.user_tx.clone()
     }
+
+    /// The same connection under another name.
+    pub fn renamed(&self, user_name: UserName) -> Self {
+        Self {
+            user_name,
+            login: self.login.clone(),
+            user_tx: self.user_tx.clone(),
+        }
+    }
+
+    /// Whether both are the same connection, not only the same user.
+    pub fn same_session(&self, other: &User) -> bool {
+        self.user_tx.same_channel(&other.user_tx)
+    }
 }
 
-#[derive(Default)]
 pub struct UserManager {
     users: HashMap<UserName, User>,
+    /// Where each connected user connects from.
+    addresses: HashMap<UserName, IpAddr>,
+    /// Accounts kept from connecting, with the reason if one was given.
+    banned_users: HashMap<UserName, Option<String>>,
+    banned_addresses: HashSet<IpAddr>,
+    /// The accounts of the server admins, who are never kept out by an address ban.
+    admins: HashSet<UserName>,
+    /// Names nobody may connect as.
+    reserved: HashSet<UserName>,
+    /// The rooms each connected user is in.
+    rooms: HashMap<UserName, HashSet<RoomName>>,
+    /// The presence of each connected user.
+    presence: HashMap<UserName, PresenceState>,
+    accounts: SharedAccountStore,
+    /// Private messages to registered users who are offline.
+    mailbox: MailboxStore,
+    /// Capacity of the queue of messages to each user.
+    queue_size: usize,
 }
 
 impl UserManager {
+    pub fn new(accounts: AccountStore, mailbox: MailboxStore, queue_size: usize) -> Self {
+        Self {
+            users: HashMap::new(),
+            addresses: HashMap::new(),
+            banned_users: HashMap::new(),
+            banned_addresses: HashSet::new(),
+            admins: HashSet::new(),
+            reserved: HashSet::new(),
+            rooms: HashMap::new(),
+            presence: HashMap::new(),
+            accounts: SharedAccountStore::new(accounts),
+            mailbox,
+            queue_size,
+        }
+    }
+
+    /// The account store, for checking credentials away from the user processor.
+    pub fn accounts(&self) -> SharedAccountStore {
+        self.accounts.clone()
+    }
+
+    /// Check that the name is not reserved and that neither the user nor their address is
+    /// banned. Users connect under the name of their account, so `user_name` is their login.
+    pub fn admit(&self, user_name: &UserName, address: IpAddr) -> Result<()> {
+        if self.reserved.contains(user_name) {
+            return Err(CommonError::InvalidUserName(InvalidUserName::Reserved));
+        }
+        if self.banned_addresses.contains(&address) && !self.is_admin(user_name) {
+            return Err(CommonError::BannedFromServer(None));
+        }
+        if let Some(reason) = self.banned_users.get(user_name) {
+            return Err(CommonError::BannedFromServer(reason.clone()));
+        }
+        Ok(())
+    }
+
+    /// Admin names are kept for the accounts of the admins, so nobody may register one and gain
+    /// their rights.
+    pub fn check_registration(&self, user_name: &UserName) -> Result<()> {
+        if self.is_admin(user_name) {
+            return Err(CommonError::InvalidUserName(InvalidUserName::Reserved));
+        }
+        Ok(())
+    }
+
+    /// Add a user whose credentials were checked with
+    /// [`SharedAccountStore::authenticate`] to the connected users. They are admitted again, as
+    /// they may have been banned while their password was being checked.
     pub fn add_new_user(
         &mut self,
         user_name: impl Into<UserName>,
+        address: IpAddr,
     ) -> Result<mpsc::Receiver<ServerMessage>> {
-        let (user, user_rx) = User::new(user_name);
+        let user_name = user_name.into();
+        self.admit(&user_name, address)?;
+        let (user, user_rx) = User::new(user_name.clone(), self.queue_size);
         self.add_user(user)?;
+        self.addresses.insert(user_name.clone(), address);
+        self.presence
+            .insert(user_name.clone(), PresenceState::default());
         Ok(user_rx)
     }
 
@@ -152,6 +390,9 @@ impl UserManager {
     }
 
     pub fn remove_user(&mut self, user_name: &UserName) -> Result<User> {
+        self.addresses.remove(user_name);
+        self.rooms.remove(user_name);
+        self.presence.remove(user_name);
         self.users
             .remove(user_name)
             .ok_or(CommonError::UserExists(user_name.clone()))
@@ -163,7 +404,435 @@ impl UserManager {
             .ok_or(CommonError::UserNotExists(user_name.clone()))
     }
 
-    pub fn list_users(&self) -> Vec<UserName> {
-        self.users.keys().cloned().collect()
+    /// The connected user going by `user_name`, or the one who logged in with that account and
+    /// goes by another name since.
+    pub fn recipient(&self, user_name: &UserName) -> Option<&User> {
+        self.users
+            .get(user_name)
+            .or_else(|| self.users.values().find(|user| user.login() == user_name))
+    }
+
+    /// The account of the connected user going by `user_name`. Anyone else is taken to go by the
+    /// name of their account, as users do when they connect.
+    pub fn login_of(&self, user_name: &UserName) -> UserName {
+        self.users
+            .get(user_name)
+            .map_or(user_name, User::login)
+            .clone()
+    }
+
+    /// Keep a private message for a registered user until they next log in.
+    pub fn queue_message(&mut self, user_name: &UserName, record: ChatRecord) -> Result<()> {
+        if !self.accounts.contains(user_name) {
+            return Err(CommonError::UserNotExists(user_name.clone()));
+        }
+        self.mailbox.push(user_name, record)
+    }
+
+    /// The private messages kept for `user_name` while they were offline, oldest first. They
+    /// stay queued until [`clear_messages`](Self::clear_messages) is called.
+    pub fn queued_messages(&self, user_name: &UserName) -> Vec<ChatRecord> {
+        self.mailbox.messages(user_name)
+    }
+
+    pub fn clear_messages(&mut self, user_name: &UserName) -> Result<()> {
+        self.mailbox.clear(user_name)
+    }
+
+    /// Apply an edit to a message still kept for `user_name`, if it is.
+    pub fn edit_queued_message(&mut self, user_name: &UserName, record: &ChatRecord) -> Result<()> {
+        self.mailbox.replace(user_name, record)
+    }
+
+    /// Drop a message kept for `user_name`, if it is.
+    pub fn delete_queued_message(&mut self, user_name: &UserName, id: MessageId) -> Result<()> {
+        self.mailbox.remove(user_name, id)
+    }
+
+    /// Every connected user with their presence, sorted by name.
+    pub fn list_users(&self) -> Vec<UserStatus> {
+        let mut users: Vec<_> = self
+            .users
+            .keys()
+            .filter_map(|user_name| self.status_of(user_name))
+            .collect();
+        users.sort_by(|a, b| a.user.user_name().cmp(b.user.user_name()));
+        users
+    }
+
+    pub fn status_of(&self, user_name: &UserName) -> Option<UserStatus> {
+        let (user, state) = self.presence.get_key_value(user_name)?;
+        Some(UserStatus {
+            user: user.clone(),
+            presence: state.presence,
+            text: state.text.clone(),
+        })
+    }
+
+    /// Set the presence chosen by a connected user, returning their new status.
+    pub fn set_presence(
+        &mut self,
+        user_name: &UserName,
+        presence: Presence,
+        text: Option<String>,
+    ) -> Result<UserStatus> {
+        let state = self
+            .presence
+            .get_mut(user_name)
+            .ok_or(CommonError::UserNotExists(user_name.clone()))?;
+        *state = PresenceState {
+            presence,
+            text,
+            idle: false,
+        };
+        self.status_of(user_name)
+            .ok_or(CommonError::UserNotExists(user_name.clone()))
+    }
+
+    /// Set a user who has been inactive away, unless they chose a presence other than online.
+    /// Returns their new status if it changed.
+    pub fn went_idle(&mut self, user_name: &UserName) -> Option<UserStatus> {
+        let state = self.presence.get_mut(user_name)?;
+        if state.presence != Presence::Online {
+            return None;
+        }
+        state.presence = Presence::Away;
+        state.idle = true;
+        self.status_of(user_name)
+    }
+
+    /// Bring a user the server set away back online. Returns their new status if it changed.
+    pub fn became_active(&mut self, user_name: &UserName) -> Option<UserStatus> {
+        let state = self.presence.get_mut(user_name)?;
+        if !state.idle {
+            return None;
+        }
+        state.presence = Presence::Online;
+        state.idle = false;
+        self.status_of(user_name)
+    }
+
+    /// The connected users who share at least one room with `user_name`, not counting them.
+    pub fn room_mates(&self, user_name: &UserName) -> Vec<&User> {
+        let Some(rooms) = self.rooms.get(user_name) else {
+            return Vec::new();
+        };
+        self.rooms
+            .iter()
+            .filter(|(other, other_rooms)| *other != user_name && !other_rooms.is_disjoint(rooms))
+            .filter_map(|(other, _)| self.users.get(other))
+            .collect()
+    }
+
+    /// Give a connected user a new name, returning them under it along with the rooms they are
+    /// in. Names of other connected users, of accounts and of admins are taken, except for the
+    /// account the user logged in with.
+    pub fn rename_user(
+        &mut self,
+        user_name: &UserName,
+        new_name: UserName,
+    ) -> Result<(User, Vec<RoomName>)> {
+        let login = self.get_user(user_name)?.login().clone();
+        if self.reserved.contains(&new_name)
+            || (self.admins.contains(&new_name) && new_name != login)
+        {
+            return Err(CommonError::InvalidUserName(InvalidUserName::Reserved));
+        }
+        if self.users.contains_key(&new_name) && new_name != *user_name {
+            return Err(CommonError::UserExists(new_name));
+        }
+        if self.accounts.contains(&new_name) && new_name != login {
+            return Err(CommonError::AccountExists(new_name));
+        }
+
+        let rooms = self.rooms_of(user_name);
+        let address = self.addresses.get(user_name).copied();
+        let presence = self.presence.get(user_name).cloned().unwrap_or_default();
+        let user = self.remove_user(user_name)?.renamed(new_name.clone());
+        self.presence.insert(new_name.clone(), presence);
+        self.users.insert(new_name.clone(), user.clone());
+        if let Some(address) = address {
+            self.addresses.insert(new_name.clone(), address);
+        }
+        self.rooms.insert(new_name, rooms.iter().cloned().collect());
+        Ok((user, rooms))
+    }
+
+    /// Record that `session` joined `room`. Returns false if the session is no longer connected,
+    /// in which case nothing is recorded.
+    pub fn joined_room(&mut self, session: &User, room: RoomName) -> bool {
+        let connected = self
+            .users
+            .get(session.user_name())
+            .is_some_and(|user| user.same_session(session));
+        if connected {
+            self.rooms
+                .entry(session.user_name().clone())
+                .or_default()
+                .insert(room);
+        }
+        connected
+    }
+
+    pub fn left_room(&mut self, user_name: &UserName, room: &RoomName) {
+        if let Some(rooms) = self.rooms.get_mut(user_name) {
+            rooms.remove(room);
+        }
+    }
+
+    /// The rooms `user_name` is in, sorted by name.
+    pub fn rooms_of(&self, user_name: &UserName) -> Vec<RoomName> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .get(user_name)
+            .map(|rooms| rooms.iter().cloned().collect())
+            .unwrap_or_default();
+        rooms.sort_by(|a, b| a.room_name().cmp(b.room_name()));
+        rooms
+    }
+
+    pub fn reserve_name(&mut self, user_name: UserName) {
+        self.reserved.insert(user_name);
+    }
+
+    pub fn add_admin(&mut self, user_name: UserName) {
+        self.admins.insert(user_name);
+    }
+
+    /// Whether the connected user going by `user_name` logged in with an admin account.
+    pub fn is_admin(&self, user_name: &UserName) -> bool {
+        self.admins.contains(&self.login_of(user_name))
+    }
+
+    /// The connected users connecting from `address`, leaving out the admins.
+    pub fn users_at(&self, address: &IpAddr) -> Vec<UserName> {
+        self.addresses
+            .iter()
+            .filter(|(user_name, a)| *a == address && !self.is_admin(user_name))
+            .map(|(user_name, _)| user_name.clone())
+            .collect()
+    }
+
+    /// Ban the account of `user_name`, so that changing names does not get around the ban.
+    pub fn ban_user(&mut self, user_name: &UserName, reason: Option<String>) {
+        self.banned_users.insert(self.login_of(user_name), reason);
+    }
+
+    pub fn unban_user(&mut self, user_name: &UserName) -> Result<()> {
+        self.banned_users
+            .remove(&self.login_of(user_name))
+            .map(|_| ())
+            .ok_or(CommonError::UserNotBanned(user_name.clone()))
+    }
+
+    pub fn ban_address(&mut self, address: IpAddr) {
+        self.banned_addresses.insert(address);
+    }
+
+    pub fn unban_address(&mut self, address: &IpAddr) -> Result<()> {
+        if self.banned_addresses.remove(address) {
+            Ok(())
+        } else {
+            Err(CommonError::AddressNotBanned(*address))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::messages::Authentication;
+
+    fn name(user_name: &str) -> UserName {
+        UserName::new(user_name)
+    }
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    fn manager(dir: &tempfile::TempDir) -> UserManager {
+        let accounts = AccountStore::open(dir.path().join("accounts.bin")).unwrap();
+        let mailbox = MailboxStore::open(dir.path().join("mailboxes.bin"), 10).unwrap();
+        UserManager::new(accounts, mailbox, 16)
+    }
+
+    fn register(users: &UserManager, user_name: &str) {
+        let password = "correct horse".to_string();
+        users
+            .accounts()
+            .authenticate(&name(user_name), Authentication::Register { password })
+            .unwrap();
+    }
+
+    #[test]
+    fn names_are_trimmed_and_normalised() {
+        let user_name = UserName::parse("  e\u{301}mile ").unwrap();
+        assert_eq!(user_name.user_name(), "\u{e9}mile");
+    }
+
+    #[test]
+    fn names_are_limited_in_length_by_characters() {
+        let longest = "\u{e9}".repeat(MAX_USER_NAME_LENGTH);
+        assert!(UserName::parse(&longest).is_ok());
+        assert_eq!(
+            UserName::parse(&format!("{}e", longest)).unwrap_err(),
+            InvalidUserName::TooLong {
+                max: MAX_USER_NAME_LENGTH
+            }
+        );
+        assert_eq!(UserName::parse("   ").unwrap_err(), InvalidUserName::Empty);
+    }
+
+    #[test]
+    fn names_have_no_whitespace_or_control_characters() {
+        for (user_name, c) in [
+            ("al ice", ' '),
+            ("al\u{a0}ice", '\u{a0}'),
+            ("al\u{7}ice", '\u{7}'),
+        ] {
+            assert_eq!(
+                UserName::parse(user_name).unwrap_err(),
+                InvalidUserName::InvalidCharacter(c)
+            );
+        }
+    }
+
+    #[test]
+    fn look_alike_names_are_the_same_user() {
+        let alice = UserName::parse("alice").unwrap();
+        // Upper case, a Cyrillic `а` and fullwidth letters.
+        for other in [
+            "Alice",
+            "\u{430}lice",
+            "\u{ff41}\u{ff4c}\u{ff49}\u{ff43}\u{ff45}",
+        ] {
+            let other = UserName::parse(other).unwrap();
+            assert_eq!(alice, other);
+            assert!(HashSet::from([alice.clone()]).contains(&other));
+        }
+        assert_ne!(alice, UserName::parse("alicia").unwrap());
+    }
+
+    #[test]
+    fn admin_names_cannot_be_registered() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut users = manager(&dir);
+        users.add_admin(name("alice"));
+        assert!(matches!(
+            users.check_registration(&name("Alice")),
+            Err(CommonError::InvalidUserName(InvalidUserName::Reserved))
+        ));
+        assert!(users.check_registration(&name("bob")).is_ok());
+        // The admin logs in to their own account as anyone else would.
+        assert!(users.admit(&name("alice"), localhost()).is_ok());
+    }
+
+    #[test]
+    fn address_bans_keep_out_everyone_but_admins() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut users = manager(&dir);
+        let elsewhere: IpAddr = "192.0.2.1".parse().unwrap();
+        users.add_admin(name("root"));
+        users.ban_address(localhost());
+
+        assert!(matches!(
+            users.admit(&name("bob"), localhost()),
+            Err(CommonError::BannedFromServer(None))
+        ));
+        assert!(users.admit(&name("root"), localhost()).is_ok());
+        assert!(users.admit(&name("bob"), elsewhere).is_ok());
+
+        users.ban_user(&name("bob"), Some("spam".to_string()));
+        assert!(matches!(
+            users.admit(&name("bob"), elsewhere),
+            Err(CommonError::BannedFromServer(Some(reason))) if reason == "spam"
+        ));
+        users.unban_address(&localhost()).unwrap();
+        assert!(matches!(
+            users.unban_address(&localhost()),
+            Err(CommonError::AddressNotBanned(_))
+        ));
+    }
+
+    #[test]
+    fn renaming_leaves_other_names_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut users = manager(&dir);
+        users.reserve_name(name("admin"));
+        users.add_admin(name("root"));
+        register(&users, "carol");
+        let _alice = users.add_new_user(name("alice"), localhost()).unwrap();
+        let _bob = users.add_new_user(name("bob"), localhost()).unwrap();
+
+        assert!(matches!(
+            users.rename_user(&name("alice"), name("Bob")),
+            Err(CommonError::UserExists(_))
+        ));
+        assert!(matches!(
+            users.rename_user(&name("alice"), name("carol")),
+            Err(CommonError::AccountExists(_))
+        ));
+        for reserved in ["admin", "root"] {
+            assert!(matches!(
+                users.rename_user(&name("alice"), name(reserved)),
+                Err(CommonError::InvalidUserName(InvalidUserName::Reserved))
+            ));
+        }
+
+        let (renamed, _) = users.rename_user(&name("alice"), name("al")).unwrap();
+        assert_eq!(*renamed.user_name(), "al");
+        assert_eq!(*renamed.login(), "alice");
+        assert!(users.get_user(&name("alice")).is_err());
+        assert!(users.rename_user(&name("al"), name("alice")).is_ok());
+    }
+
+    #[test]
+    fn server_bans_and_admin_rights_follow_the_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut users = manager(&dir);
+        users.add_admin(name("root"));
+        let _root = users.add_new_user(name("root"), localhost()).unwrap();
+        let _bob = users.add_new_user(name("bob"), localhost()).unwrap();
+        users.rename_user(&name("root"), name("boss")).unwrap();
+        users.rename_user(&name("bob"), name("robert")).unwrap();
+
+        assert!(users.is_admin(&name("boss")));
+        users.ban_address(localhost());
+        assert_eq!(users.users_at(&localhost()), vec![name("robert")]);
+        users.unban_address(&localhost()).unwrap();
+
+        users.ban_user(&name("robert"), None);
+        users.remove_user(&name("robert")).unwrap();
+        assert!(matches!(
+            users.admit(&name("bob"), localhost()),
+            Err(CommonError::BannedFromServer(None))
+        ));
+        users.unban_user(&name("bob")).unwrap();
+        assert!(users.admit(&name("bob"), localhost()).is_ok());
+    }
+
+    #[test]
+    fn idle_users_are_away_until_they_come_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut users = manager(&dir);
+        let _alice = users.add_new_user(name("alice"), localhost()).unwrap();
+        let alice = name("alice");
+
+        assert_eq!(users.went_idle(&alice).unwrap().presence, Presence::Away);
+        assert!(users.went_idle(&alice).is_none());
+        assert_eq!(
+            users.became_active(&alice).unwrap().presence,
+            Presence::Online
+        );
+        assert!(users.became_active(&alice).is_none());
+
+        // A presence the user chose is theirs to change.
+        let text = Some("in a meeting".to_string());
+        let status = users.set_presence(&alice, Presence::Busy, text).unwrap();
+        assert_eq!(status.text.as_deref(), Some("in a meeting"));
+        assert!(users.went_idle(&alice).is_none());
+        users.set_presence(&alice, Presence::Away, None).unwrap();
+        assert!(users.became_active(&alice).is_none());
     }
 }
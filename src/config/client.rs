@@ -0,0 +1,137 @@
+use super::{load, parse_override, quote, to_toml, LogConfig, LogLevel, Result};
+use crate::connection::{ClientTlsConfig, ServerVerification};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Prefix of the env vars overriding client settings, e.g. `CHAT_CLIENT_HOST=chat.example.com`.
+const ENV_PREFIX: &str = "CHAT_CLIENT_";
+
+/// Env vars read before the settings had a config file, still honoured.
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("HOST", "host"),
+    ("PORT", "port"),
+    ("TLS_CA_FILE", "tls.ca_file"),
+    ("TLS_FINGERPRINT", "tls.fingerprint"),
+    ("TLS_SERVER_NAME", "tls.server_name"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    pub log: LogConfig,
+    pub tls: ClientTlsSettings,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 8080,
+            log: LogConfig::default(),
+            tls: ClientTlsSettings::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Load the settings from the defaults, the config file, the environment and `args`.
+    pub fn load(args: &ClientArgs) -> Result<Self> {
+        let mut config: Self = load(
+            args.config.as_deref(),
+            ENV_PREFIX,
+            ENV_ALIASES,
+            args.overrides(),
+        )?;
+        // Log lines would be drawn over the terminal UI.
+        if args.tui {
+            config.log.level = LogLevel::OFF;
+        }
+        Ok(config)
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// How to connect over TLS, if it is enabled. The certificate has to be valid for
+    /// `tls.server_name`, which defaults to `host`.
+    pub fn tls(&self) -> Option<ClientTlsConfig> {
+        let verification = match (&self.tls.ca_file, &self.tls.fingerprint) {
+            (Some(ca_file), _) => ServerVerification::CaFile(ca_file.clone()),
+            (_, Some(fingerprint)) => ServerVerification::Fingerprint(fingerprint.clone()),
+            _ => return None,
+        };
+        let server_name = self.tls.server_name.as_ref().unwrap_or(&self.host);
+        Some(ClientTlsConfig::new(server_name, verification))
+    }
+
+    /// The effective settings, as they would be written in the config file.
+    pub fn to_toml(&self) -> Result<String> {
+        to_toml(self)
+    }
+}
+
+/// TLS is enabled when either a CA file or a certificate fingerprint is set, the CA file winning
+/// if both are.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTlsSettings {
+    pub ca_file: Option<PathBuf>,
+    pub fingerprint: Option<String>,
+    pub server_name: Option<String>,
+}
+
+/// Command-line flags of the client. Anything without a dedicated flag can be set with `--set`.
+#[derive(Debug, Parser)]
+#[command(about = "Chat client")]
+pub struct ClientArgs {
+    /// TOML file to read the settings from.
+    #[arg(short, long, env = "CHAT_CLIENT_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[arg(long)]
+    pub host: Option<String>,
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// off, error, warn, info, debug or trace. Logging is always off in the terminal UI.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Trust the server certificates signed by this PEM file.
+    #[arg(long)]
+    pub tls_ca_file: Option<PathBuf>,
+    /// Trust the server certificate with this SHA-256 fingerprint.
+    #[arg(long)]
+    pub tls_fingerprint: Option<String>,
+    /// Set any setting by its dotted key, e.g. `--set tls.server_name=localhost`.
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+    /// Print the effective settings as TOML and exit.
+    #[arg(long)]
+    pub print_config: bool,
+    /// Use the terminal UI instead of reading lines from stdin.
+    #[arg(long)]
+    pub tui: bool,
+}
+
+impl ClientArgs {
+    fn overrides(&self) -> Vec<(String, String)> {
+        let flags = [
+            ("host", self.host.as_ref().map(quote)),
+            ("port", self.port.map(|port| port.to_string())),
+            ("log.level", self.log_level.as_ref().map(quote)),
+            (
+                "tls.ca_file",
+                self.tls_ca_file.as_ref().map(|path| quote(path.display())),
+            ),
+            ("tls.fingerprint", self.tls_fingerprint.as_ref().map(quote)),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .chain(self.overrides.iter().cloned())
+            .collect()
+    }
+}
@@ -0,0 +1,23 @@
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Debug, derive_more::From)]
+pub enum ConfigError {
+    ReadFile(PathBuf, std::io::Error),
+    #[from]
+    Parse(toml::de::Error),
+    #[from]
+    Serialize(toml::ser::Error),
+    InvalidKey(String),
+    InvalidLogLevel(String),
+}
+
+//Error boilerplate
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -0,0 +1,262 @@
+//! Typed configuration for the server and client binaries.
+//!
+//! Settings are layered, each layer overriding the previous one:
+//! 1. the defaults,
+//! 2. a TOML file given with `--config`,
+//! 3. environment variables (also read from `.env`),
+//! 4. command-line flags.
+//!
+//! Every setting can be reached from the environment and the command line by its dotted key,
+//! e.g. `queues.broadcast` is `CHAT_SERVER_QUEUES__BROADCAST=64` or `--set queues.broadcast=64`.
+
+mod client;
+mod error;
+mod server;
+
+pub use client::{ClientArgs, ClientConfig, ClientTlsSettings};
+pub use error::ConfigError;
+use error::Result;
+pub use server::{
+    AdminConfig, LimitConfig, QueueConfig, RoomConfig, ServerArgs, ServerConfig, StorageConfig,
+    TimeoutConfig,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use toml::{Table, Value};
+use tracing::level_filters::LevelFilter;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+impl LogConfig {
+    fn with_level(level: LevelFilter) -> Self {
+        Self {
+            level: LogLevel(level),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self::with_level(LevelFilter::INFO)
+    }
+}
+
+/// A tracing level written as `off`, `error`, `warn`, `info`, `debug` or `trace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogLevel(LevelFilter);
+
+impl LogLevel {
+    pub const OFF: Self = Self(LevelFilter::OFF);
+
+    pub fn filter(&self) -> LevelFilter {
+        self.0
+    }
+}
+
+impl TryFrom<String> for LogLevel {
+    type Error = ConfigError;
+
+    fn try_from(level: String) -> Result<Self> {
+        LevelFilter::from_str(&level)
+            .map(Self)
+            .map_err(|_| ConfigError::InvalidLogLevel(level))
+    }
+}
+
+impl From<LogLevel> for String {
+    fn from(level: LogLevel) -> Self {
+        level.to_string()
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_string().to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+/// Parse a `KEY=VALUE` command-line override.
+fn parse_override(arg: &str) -> std::result::Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", arg))
+}
+
+/// Quote a flag value so it is always read as a string, whatever it looks like.
+fn quote(value: impl Display) -> String {
+    Value::String(value.to_string()).to_string()
+}
+
+/// Build a config from its defaults, the TOML file at `path`, the env vars in `env_aliases`
+/// (older names kept working), the env vars starting with `env_prefix` and finally the
+/// command-line `overrides`, in that order.
+fn load<T>(
+    path: Option<&Path>,
+    env_prefix: &str,
+    env_aliases: &[(&str, &str)],
+    overrides: Vec<(String, String)>,
+) -> Result<T>
+where
+    T: Default + Serialize + DeserializeOwned,
+{
+    dotenv::dotenv().ok();
+
+    let mut config = match Value::try_from(T::default())? {
+        Value::Table(table) => table,
+        _ => Table::new(),
+    };
+
+    if let Some(path) = path {
+        let file = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::ReadFile(path.to_path_buf(), e))?;
+        merge(&mut config, toml::from_str(&file)?);
+    }
+
+    for (var, key) in env_aliases {
+        if let Ok(value) = std::env::var(var) {
+            set(&mut config, key, &value)?;
+        }
+    }
+    for (var, value) in std::env::vars() {
+        // `<PREFIX>CONFIG` names the config file itself.
+        if let Some(key) = var.strip_prefix(env_prefix).filter(|key| *key != "CONFIG") {
+            set(&mut config, &key.to_lowercase().replace("__", "."), &value)?;
+        }
+    }
+    for (key, value) in overrides {
+        set(&mut config, &key, &value)?;
+    }
+
+    Ok(Value::Table(config).try_into()?)
+}
+
+/// Merge `overlay` into `base`, recursing into tables so a file only needs the keys it changes.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Set the value at a dotted `key`. The value is read as TOML if it can be, so numbers, booleans
+/// and arrays keep their type, and as a plain string otherwise.
+fn set(config: &mut Table, key: &str, value: &str) -> Result<()> {
+    let value = toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()));
+
+    let mut path: Vec<&str> = key.split('.').collect();
+    let last = path.pop().filter(|last| !last.is_empty());
+    let Some(last) = last else {
+        return Err(ConfigError::InvalidKey(key.to_string()));
+    };
+    let mut table = config;
+    for part in path {
+        let entry = table
+            .entry(part)
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(table) => table,
+            _ => return Err(ConfigError::InvalidKey(key.to_string())),
+        };
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// Render a config the way it would be written in its TOML file.
+fn to_toml<T: Serialize>(config: &T) -> Result<String> {
+    Ok(toml::to_string_pretty(config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::UserName;
+
+    fn overrides(overrides: &[(&str, &str)]) -> Vec<(String, String)> {
+        overrides
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn each_layer_overrides_the_previous_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        let file = "port = 9000\n[log]\nlevel = \"warn\"\n[queues]\nbroadcast = 8\nusers = 8\n";
+        std::fs::write(&path, file).unwrap();
+        std::env::set_var("CHAT_LAYERS_TEST_QUEUES__USERS", "16");
+        std::env::set_var("CHAT_LAYERS_TEST_QUEUES__ROOMS", "16");
+        std::env::set_var("LAYERS_TEST_HOST", "example.org");
+
+        let config: ServerConfig = load(
+            Some(&path),
+            "CHAT_LAYERS_TEST_",
+            &[("LAYERS_TEST_HOST", "host")],
+            overrides(&[("queues.users", "32"), ("admin.users", "[\"root\"]")]),
+        )
+        .unwrap();
+        assert_eq!(config.host, "example.org");
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.log.level, LogLevel(LevelFilter::WARN));
+        assert_eq!(config.queues.broadcast, 8);
+        assert_eq!(config.queues.rooms, 16);
+        assert_eq!(config.queues.users, 32);
+        assert_eq!(config.queues.server, QueueConfig::default().server);
+        assert_eq!(config.admin.users, vec![UserName::new("root")]);
+
+        // The effective config reads back as it was printed.
+        let printed: ServerConfig = toml::from_str(&to_toml(&config).unwrap()).unwrap();
+        assert_eq!(printed.host, "example.org");
+        assert_eq!(printed.queues.users, 32);
+    }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        assert_eq!(
+            parse_override("host=a=b"),
+            Ok(("host".to_string(), "a=b".to_string()))
+        );
+        assert!(parse_override("host").is_err());
+
+        let mut table = Table::new();
+        set(&mut table, "port", "1").unwrap();
+        for key in ["queues.", "port.number"] {
+            let set = set(&mut table, key, "1");
+            assert!(matches!(set, Err(ConfigError::InvalidKey(_))));
+        }
+
+        for (key, value) in [("queues.everything", "1"), ("log.level", "loud")] {
+            let config =
+                load::<ServerConfig>(None, "CHAT_REFUSED_TEST_", &[], overrides(&[(key, value)]));
+            assert!(matches!(config, Err(ConfigError::Parse(_))));
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use super::{load, parse_override, quote, to_toml, LogConfig, Result};
+use crate::common::{RoomLifecycle, RoomName, UserName};
+use crate::connection::ServerTlsConfig;
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// Prefix of the env vars overriding server settings, e.g. `CHAT_SERVER_PORT=9000`.
+const ENV_PREFIX: &str = "CHAT_SERVER_";
+
+/// Env vars read before the settings had a config file, still honoured.
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("HOST", "host"),
+    ("PORT", "port"),
+    ("WS_PORT", "websocket_port"),
+    ("TLS_CERT", "tls.cert_path"),
+    ("TLS_KEY", "tls.key_path"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Also accept WebSocket clients on this port, speaking JSON instead of bincode. They use TLS
+    /// too if it is enabled.
+    pub websocket_port: Option<u16>,
+    pub log: LogConfig,
+    /// Only accept TLS connections, presenting this certificate.
+    pub tls: Option<ServerTlsConfig>,
+    pub storage: StorageConfig,
+    pub queues: QueueConfig,
+    pub timeouts: TimeoutConfig,
+    pub limits: LimitConfig,
+    pub rooms: RoomConfig,
+    pub admin: AdminConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 8080,
+            websocket_port: None,
+            log: LogConfig::with_level(LevelFilter::TRACE),
+            tls: None,
+            storage: StorageConfig::default(),
+            queues: QueueConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            limits: LimitConfig::default(),
+            rooms: RoomConfig::default(),
+            admin: AdminConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Load the settings from the defaults, the config file, the environment and `args`.
+    pub fn load(args: &ServerArgs) -> Result<Self> {
+        load(
+            args.config.as_deref(),
+            ENV_PREFIX,
+            ENV_ALIASES,
+            args.overrides(),
+        )
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn websocket_address(&self) -> Option<String> {
+        self.websocket_port
+            .map(|port| format!("{}:{}", self.host, port))
+    }
+
+    /// The effective settings, as they would be written in the config file.
+    pub fn to_toml(&self) -> Result<String> {
+        to_toml(self)
+    }
+}
+
+/// Where the server keeps its data between runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub accounts_path: PathBuf,
+    pub history_path: PathBuf,
+    /// Private messages waiting for users who were offline when they were sent.
+    pub mailbox_path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            accounts_path: "accounts.bin".into(),
+            history_path: "history.log".into(),
+            mailbox_path: "mailbox.bin".into(),
+        }
+    }
+}
+
+/// Capacity of the channels between the tasks of the server. A full queue makes its senders wait,
+/// except for the broadcast queue where slow connections miss messages instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Messages sent to every connection.
+    pub broadcast: usize,
+    /// Messages from the connections to the server processor.
+    pub server: usize,
+    pub users: usize,
+    pub rooms: usize,
+    pub history: usize,
+    /// Messages to a single room.
+    pub room: usize,
+    /// Messages waiting to be written to a single connection.
+    pub client: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            broadcast: 32,
+            server: 64,
+            users: 32,
+            rooms: 32,
+            history: 64,
+            room: 32,
+            client: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// How long a client may take to be authenticated once it has sent its handshake.
+    pub handshake_secs: u64,
+    /// How long a connection may take to send what is still queued for it when shutting down.
+    pub shutdown_drain_secs: u64,
+    /// How long shutting down may take before giving up on the remaining tasks.
+    pub shutdown_secs: u64,
+    /// How long a user may go without doing anything before they are shown as away. Never if 0.
+    pub away_after_secs: u64,
+}
+
+impl TimeoutConfig {
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake_secs)
+    }
+
+    pub fn shutdown_drain(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain_secs)
+    }
+
+    pub fn shutdown(&self) -> Duration {
+        Duration::from_secs(self.shutdown_secs)
+    }
+
+    pub fn away_after(&self) -> Option<Duration> {
+        (self.away_after_secs > 0).then(|| Duration::from_secs(self.away_after_secs))
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            handshake_secs: 5,
+            shutdown_drain_secs: 3,
+            shutdown_secs: 10,
+            away_after_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /// Connections served at once, further clients are turned away. Unlimited if not set.
+    pub max_connections: Option<usize>,
+    /// Number of messages replayed to a user joining a room.
+    pub history_replay_limit: usize,
+    /// Longest chat message accepted, in characters.
+    pub max_message_length: usize,
+    /// Longest frame read from a client, in bytes. Longer ones close the connection before they
+    /// are read, so this should leave room for a message of `max_message_length` characters.
+    pub max_frame_size: usize,
+    /// Private messages kept for each offline user, further messages are refused.
+    pub max_queued_messages: usize,
+    /// How long senders may edit or delete a message after sending it. Never if 0.
+    pub edit_window_secs: u64,
+    /// How long global and private messages can be reacted to after they were sent, and at least
+    /// for as long as they may be edited. Room messages can be for as long as they are replayed.
+    pub reaction_window_secs: u64,
+    /// Most messages kept in memory to be edited, deleted or reacted to, on top of those
+    /// replayed. Older ones can't be changed any more, even within those windows.
+    pub max_kept_messages: usize,
+}
+
+impl LimitConfig {
+    pub fn edit_window(&self) -> Option<Duration> {
+        (self.edit_window_secs > 0).then(|| Duration::from_secs(self.edit_window_secs))
+    }
+
+    /// How long messages are kept in memory after they were sent, to be changed or reacted to.
+    pub fn keep_messages_for(&self) -> Option<Duration> {
+        let reaction_window =
+            (self.reaction_window_secs > 0).then(|| Duration::from_secs(self.reaction_window_secs));
+        self.edit_window().max(reaction_window)
+    }
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            history_replay_limit: 50,
+            max_message_length: 4096,
+            max_frame_size: 64 * 1024,
+            max_queued_messages: 100,
+            edit_window_secs: 900,
+            reaction_window_secs: 86400,
+            max_kept_messages: 10_000,
+        }
+    }
+}
+
+/// When rooms go away on their own. Nothing is deleted unless asked for.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    /// Delete a room as soon as its last member leaves.
+    pub delete_when_empty: bool,
+    /// Delete a room once nobody has joined it or sent anything to it for this many minutes.
+    pub idle_timeout_mins: Option<u64>,
+    /// Rooms created when the server starts, owned by the server and never deleted on their own.
+    pub permanent: Vec<RoomName>,
+}
+
+impl RoomConfig {
+    /// The lifecycle of the rooms created by users.
+    pub fn lifecycle(&self) -> RoomLifecycle {
+        RoomLifecycle {
+            delete_when_empty: self.delete_when_empty,
+            idle_timeout: self
+                .idle_timeout_mins
+                .map(|mins| Duration::from_secs(mins * 60)),
+        }
+    }
+}
+
+/// Names the server needs for itself: the one it sends its own messages as, and the one the
+/// client shows for the user's own messages.
+const BUILT_IN_RESERVED_NAMES: [&str; 2] = ["server", "you"];
+
+/// Who runs the server and who is kept out of it. Bans made by admins while the server runs are
+/// added to these and last until it stops.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Users allowed to use the admin commands.
+    pub users: Vec<UserName>,
+    /// Users that may not connect.
+    pub banned_users: Vec<UserName>,
+    /// Addresses clients may not connect from.
+    pub banned_ips: Vec<IpAddr>,
+    /// Names nobody may connect as, whatever their case or look-alike spelling, on top of the
+    /// ones the server keeps for itself.
+    pub reserved_names: Vec<UserName>,
+}
+
+impl AdminConfig {
+    /// The configured reserved names along with the built-in ones.
+    pub fn reserved(&self) -> impl Iterator<Item = UserName> + '_ {
+        BUILT_IN_RESERVED_NAMES
+            .into_iter()
+            .map(UserName::new)
+            .chain(self.reserved_names.iter().cloned())
+    }
+}
+
+/// Command-line flags of the server. Anything without a dedicated flag can be set with `--set`.
+#[derive(Debug, Parser)]
+#[command(about = "Chat server")]
+pub struct ServerArgs {
+    /// TOML file to read the settings from.
+    #[arg(short, long, env = "CHAT_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[arg(long)]
+    pub host: Option<String>,
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Also accept WebSocket clients on this port.
+    #[arg(long)]
+    pub websocket_port: Option<u16>,
+    /// off, error, warn, info, debug or trace.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// full, compact, pretty or json.
+    #[arg(long)]
+    pub log_format: Option<String>,
+    /// PEM certificate chain, enables TLS together with --tls-key.
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// Set any setting by its dotted key, e.g. `--set queues.broadcast=64`.
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+    /// Print the effective settings as TOML and exit.
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+impl ServerArgs {
+    fn overrides(&self) -> Vec<(String, String)> {
+        let flags = [
+            ("host", self.host.as_ref().map(quote)),
+            ("port", self.port.map(|port| port.to_string())),
+            (
+                "websocket_port",
+                self.websocket_port.map(|port| port.to_string()),
+            ),
+            ("log.level", self.log_level.as_ref().map(quote)),
+            ("log.format", self.log_format.as_ref().map(quote)),
+            (
+                "tls.cert_path",
+                self.tls_cert.as_ref().map(|path| quote(path.display())),
+            ),
+            (
+                "tls.key_path",
+                self.tls_key.as_ref().map(|path| quote(path.display())),
+            ),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .chain(self.overrides.iter().cloned())
+            .collect()
+    }
+}
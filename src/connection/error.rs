@@ -1,3 +1,5 @@
+use crate::common::messages::{ErrorCode, ErrorMessage};
+
 pub type Result<T> = std::result::Result<T, ConnectionError>;
 
 #[derive(Debug, derive_more::From)]
@@ -13,6 +15,25 @@ pub enum ConnectionError {
     ConnectionClosed,
     ConnectionDropped,
     InvalidFrameSize,
+    /// The peer announced a frame longer than allowed, the rest of the stream cannot be trusted.
+    FrameTooLarge(usize),
+    TlsHandshake(std::io::Error),
+    #[from]
+    Tls(rustls::Error),
+    InvalidServerName(String),
+    InvalidFingerprint(String),
+    NoCertificates(std::path::PathBuf),
+    NoPrivateKey(std::path::PathBuf),
+    #[from]
+    Json(serde_json::Error),
+    // Boxed, the tungstenite error is large enough to bloat every `Result` carrying this enum.
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for ConnectionError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        ConnectionError::WebSocket(Box::new(e))
+    }
 }
 
 //Error boilerplate
@@ -23,3 +44,31 @@ impl core::fmt::Display for ConnectionError {
 }
 
 impl std::error::Error for ConnectionError {}
+
+impl ConnectionError {
+    /// Whether a whole frame was read but could not be decoded, leaving the connection usable.
+    pub fn is_invalid_frame(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::BincodeDecode(_)
+                | ConnectionError::Json(_)
+                | ConnectionError::InvalidFrameSize
+        )
+    }
+}
+
+/// What the peer is told. Only frames it sent that could not be decoded are its own doing.
+impl From<&ConnectionError> for ErrorMessage {
+    fn from(e: &ConnectionError) -> Self {
+        match e {
+            ConnectionError::BincodeDecode(e) => {
+                ErrorMessage::new(ErrorCode::InvalidRequest, e.to_string())
+            }
+            ConnectionError::Json(e) => ErrorMessage::new(ErrorCode::InvalidRequest, e.to_string()),
+            ConnectionError::InvalidFrameSize => {
+                ErrorMessage::new(ErrorCode::InvalidRequest, "trailing bytes after frame")
+            }
+            _ => ErrorMessage::new(ErrorCode::Internal, ""),
+        }
+    }
+}
@@ -3,12 +3,17 @@ use super::{ConnectionError, Result};
 use async_trait::async_trait;
 use bincode::{config, Decode, Encode};
 use bytes::BytesMut;
+use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::{Debug, Display};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{error, warn};
 
+/// A message exchanged between client and server. Frames are bincode encoded on the native
+/// protocol and JSON encoded on the WebSocket gateway, so they need both sets of derives.
 #[async_trait]
-pub trait FrameType: Debug + Display + Encode + Decode + Send + Sync {
+pub trait FrameType:
+    Debug + Display + Encode + Decode + Serialize + DeserializeOwned + Send + Sync
+{
     async fn write_frame_to<W>(&self, writer: &mut W) -> Result<()>
     where
         W: AsyncWriteExt + Unpin + Send,
@@ -24,8 +29,12 @@ pub trait FrameType: Debug + Display + Encode + Decode + Send + Sync {
         Ok(())
     }
 
-    /// This function is cancel safe as it is used in a select!.
-    async fn read_frame_from<R>(reader: &mut R) -> Result<Self>
+    /// Not cancel safe, as the frame is read in two steps: the length first, then the frame
+    /// itself. See [`spawn_frame_reader`](super::spawn_frame_reader).
+    ///
+    /// Frames announced as longer than `max_size` bytes are refused before anything is allocated
+    /// for them, the peer picks the length.
+    async fn read_frame_from<R>(reader: &mut R, max_size: usize) -> Result<Self>
     where
         R: AsyncReadExt + Unpin + Send,
     {
@@ -48,9 +57,18 @@ pub trait FrameType: Debug + Display + Encode + Decode + Send + Sync {
             .map_err(convert_err)?;
         // Convert the buffer to a u32
         let size = u32::from_le_bytes(size_buf) as usize;
+        if size > max_size {
+            warn!(
+                "Refusing frame of {} bytes, at most {} allowed",
+                size, max_size
+            );
+            return Err(ConnectionError::FrameTooLarge(size));
+        }
         // Define a buffer with the exact size of the frame, as specified in the first 4 bytes.
-        let mut buf = BytesMut::with_capacity(size);
-        reader.read_buf(&mut buf).await.map_err(convert_err)?;
+        let mut buf = BytesMut::zeroed(size);
+        // A single read is not guaranteed to return the whole frame (TLS for one hands back a
+        // record at a time), so keep reading until the buffer is full.
+        reader.read_exact(&mut buf).await.map_err(convert_err)?;
         // Maybe pointless to freeze, but ensures that the buffer is not modified and we have the underlying bytes.
         let data = buf.freeze();
         // Check if the buffer size is the same as the size of the frame
@@ -60,7 +78,58 @@ pub trait FrameType: Debug + Display + Encode + Decode + Send + Sync {
         }
         // Decode the frame from the buffer
         let (frame, decode_size) = bincode::decode_from_slice(&data, config::standard())?;
-        assert_eq!(size, decode_size);
+        // Trailing bytes mean the peer encoded something else than what it was decoded as.
+        if size != decode_size {
+            warn!("Frame of {} bytes decoded from {} bytes", size, decode_size);
+            return Err(ConnectionError::InvalidFrameSize);
+        }
         Ok(frame)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::messages::{ClientMessage, ClientRequest, RequestId};
+
+    fn request() -> ClientRequest {
+        ClientRequest {
+            id: RequestId::new(1),
+            message: ClientMessage::GlobalChatMessage("hello".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let mut data = Vec::new();
+        request().write_frame_to(&mut data).await.unwrap();
+        let frame = ClientRequest::read_frame_from(&mut data.as_slice(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(frame.id.id(), 1);
+        assert_eq!(frame.message.content(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_being_read() {
+        // Only the length is sent, a reader allocating it first would wait for the rest.
+        let data = u32::MAX.to_le_bytes();
+        let result = ClientRequest::read_frame_from(&mut data.as_slice(), 1024).await;
+        assert!(
+            matches!(result, Err(ConnectionError::FrameTooLarge(size)) if size == u32::MAX as usize)
+        );
+    }
+
+    #[tokio::test]
+    async fn trailing_bytes_are_an_invalid_frame() {
+        let mut body = bincode::encode_to_vec(request(), config::standard()).unwrap();
+        body.push(0);
+        let mut data = (body.len() as u32).to_le_bytes().to_vec();
+        data.extend(body);
+
+        let result = ClientRequest::read_frame_from(&mut data.as_slice(), 1024).await;
+        let error = result.unwrap_err();
+        assert!(matches!(error, ConnectionError::InvalidFrameSize));
+        assert!(error.is_invalid_frame());
+    }
+}
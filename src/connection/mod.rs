@@ -1,61 +1,215 @@
 mod error;
 mod frame;
+mod tls;
+mod websocket;
 
 pub use error::ConnectionError;
 use error::Result;
 pub use frame::FrameType;
+pub use tls::{ClientTlsConfig, ServerTlsConfig, ServerVerification};
+pub use websocket::{WebSocketConnection, WebSocketReader, WebSocketWriter};
 
-use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
+use async_trait::async_trait;
+use std::fmt::{self, Debug, Formatter};
+use tokio::io::{
+    split, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf,
+};
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 use tracing::info;
 
-pub type Reader<'a> = BufReader<ReadHalf<'a>>;
-pub type Writer<'a> = BufWriter<WriteHalf<'a>>;
-pub type OwnedReader = BufReader<OwnedReadHalf>;
-pub type OwnedWriter = BufWriter<OwnedWriteHalf>;
-#[derive(Debug)]
+/// Any byte stream a connection can run over, e.g. a plain `TcpStream` or a TLS stream.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+pub type Reader<'a> = BufReader<ReadHalf<&'a mut BoxedStream>>;
+pub type Writer<'a> = BufWriter<WriteHalf<&'a mut BoxedStream>>;
+pub type OwnedWriter = BufWriter<WriteHalf<BoxedStream>>;
+
+/// How many frames [`spawn_frame_reader`] reads ahead of whoever takes them.
+const READ_AHEAD: usize = 16;
+
+/// Longest frame read from a peer unless told otherwise, in bytes.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// The read half of a connection, whatever the transport and encoding underneath.
+#[async_trait]
+pub trait FrameReader: Send {
+    /// Read the next frame. This is not cancel safe: a frame can be read in several steps, and
+    /// the part already read is lost when the future is dropped. Use [`spawn_frame_reader`] to
+    /// wait for frames in a `select!`.
+    async fn read_frame<F: FrameType>(&mut self) -> Result<F>;
+}
+
+/// Read frames on a task of their own and hand them out through the returned channel, which can
+/// be received from in a `select!` without losing anything. The task stops after the first error
+/// that leaves the stream unreadable, or once the receiver is dropped.
+pub fn spawn_frame_reader<F, R>(mut reader: R) -> mpsc::Receiver<Result<F>>
+where
+    F: FrameType + 'static,
+    R: FrameReader + 'static,
+{
+    let (frames_tx, frames_rx) = mpsc::channel(READ_AHEAD);
+    tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                frame = reader.read_frame::<F>() => frame,
+                // Nobody wants the rest of the stream, a frame read halfway does not matter.
+                _ = frames_tx.closed() => break,
+            };
+            let readable = frame
+                .as_ref()
+                .map_or_else(|e| e.is_invalid_frame(), |_| true);
+            if frames_tx.send(frame).await.is_err() || !readable {
+                break;
+            }
+        }
+    });
+    frames_rx
+}
+
+/// The write half of a connection, whatever the transport and encoding underneath.
+#[async_trait]
+pub trait FrameWriter: Send {
+    async fn write_frame<F: FrameType>(&mut self, frame: &F) -> Result<()>;
+
+    /// Flush anything buffered and close the connection cleanly.
+    async fn close(&mut self) -> Result<()>;
+}
+
+/// The read half of a [`Connection`], still refusing frames longer than the connection did.
+pub struct OwnedReader {
+    reader: BufReader<ReadHalf<BoxedStream>>,
+    max_frame_size: usize,
+}
+
+#[async_trait]
+impl FrameReader for OwnedReader {
+    async fn read_frame<F: FrameType>(&mut self) -> Result<F> {
+        F::read_frame_from(&mut self.reader, self.max_frame_size).await
+    }
+}
+
+#[async_trait]
+impl FrameWriter for OwnedWriter {
+    async fn write_frame<F: FrameType>(&mut self, frame: &F) -> Result<()> {
+        frame.write_frame_to(self).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(self.shutdown().await?)
+    }
+}
+
 pub struct Connection {
-    stream: TcpStream,
+    stream: BoxedStream,
+    tls: bool,
+    max_frame_size: usize,
+}
+
+impl Debug for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("tls", &self.tls)
+            .field("max_frame_size", &self.max_frame_size)
+            .finish()
+    }
 }
 
 impl Connection {
     pub async fn init(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = Self::connect(addr).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Connect to the server and perform a TLS handshake, verifying the server as configured in
+    /// `tls`.
+    pub async fn init_tls(addr: impl ToSocketAddrs, tls: &ClientTlsConfig) -> Result<Self> {
+        let stream = Self::connect(addr).await?;
+        let connector = TlsConnector::from(tls.client_config()?);
+        let stream = connector
+            .connect(tls.server_name()?, stream)
+            .await
+            .map_err(ConnectionError::TlsHandshake)?;
+        info!("TLS session established");
+
+        Ok(Self {
+            stream: Box::new(stream),
+            tls: true,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        })
+    }
+
+    async fn connect(addr: impl ToSocketAddrs) -> Result<TcpStream> {
         let stream = TcpStream::connect(addr)
             .await
             .map_err(ConnectionError::UnableToConnectToServer)?;
         info!("Connected to server {}", stream.peer_addr()?);
+        Ok(stream)
+    }
+
+    pub fn from_stream(stream: impl Stream) -> Self {
+        Self {
+            stream: Box::new(stream),
+            tls: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Refuse frames from the peer longer than `max_frame_size` bytes, closing the connection.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Accept a TLS session on a freshly accepted TCP stream.
+    pub async fn accept_tls(stream: TcpStream, acceptor: &TlsAcceptor) -> Result<Self> {
+        let stream = acceptor
+            .accept(stream)
+            .await
+            .map_err(ConnectionError::TlsHandshake)?;
+        Ok(Self {
+            stream: Box::new(stream),
+            tls: true,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        })
+    }
 
-        Ok(Self { stream })
+    /// Give up the framing and get the underlying stream back, e.g. to run another protocol
+    /// over an established TLS session.
+    pub fn into_stream(self) -> BoxedStream {
+        self.stream
     }
 
-    pub fn from_stream(stream: TcpStream) -> Self {
-        Self { stream }
+    pub fn is_tls(&self) -> bool {
+        self.tls
     }
 
     /// Convenience method to read a frame from the stream
     pub async fn read_frame<F: FrameType>(&mut self) -> Result<F> {
-        F::read_frame_from(&mut self.stream.split().0).await
+        F::read_frame_from(&mut self.stream, self.max_frame_size).await
     }
 
     /// Convenience method to write a frame to the stream
     pub async fn write_frame<F: FrameType>(&mut self, frame: &F) -> Result<()> {
-        frame.write_frame_to(&mut self.stream.split().1).await
+        frame.write_frame_to(&mut self.stream).await
     }
 
     pub fn split_into(self) -> (OwnedReader, OwnedWriter) {
-        let (reader, writer) = self.stream.into_split();
-
-        (BufReader::new(reader), BufWriter::new(writer))
+        let (reader, writer) = split(self.stream);
+        let reader = OwnedReader {
+            reader: BufReader::new(reader),
+            max_frame_size: self.max_frame_size,
+        };
+        (reader, BufWriter::new(writer))
     }
 
     pub fn split(&mut self) -> (Reader<'_>, Writer<'_>) {
-        let (reader, writer) = self.stream.split();
-        (BufReader::new(reader), BufWriter::new(writer))
-    }
-
-    pub fn split_owned(stream: TcpStream) -> (OwnedReader, OwnedWriter) {
-        let (reader, writer) = stream.into_split();
+        let (reader, writer) = split(&mut self.stream);
         (BufReader::new(reader), BufWriter::new(writer))
     }
 
@@ -0,0 +1,201 @@
+use super::{ConnectionError, Result};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+/// Certificate chain and private key the server presents to clients, both PEM encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl ServerTlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certs = load_certs(&self.cert_path)?;
+        info!(
+            "Loaded TLS certificate with fingerprint {}",
+            fingerprint(&certs[0])
+        );
+        let key = load_private_key(&self.key_path)?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// How the client decides whether to trust the server's certificate.
+#[derive(Debug, Clone)]
+pub enum ServerVerification {
+    /// Trust certificates signed by one of the PEM encoded certificates in this file. A self
+    /// signed server certificate can be used as its own CA, as long as it is not marked as a CA
+    /// certificate itself (`basicConstraints=CA:FALSE`).
+    CaFile(PathBuf),
+    /// Trust exactly one certificate, identified by the SHA-256 fingerprint of its DER encoding
+    /// written in hex, optionally separated by colons.
+    Fingerprint(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientTlsConfig {
+    /// Name the server certificate must be valid for, usually the host connected to.
+    pub server_name: String,
+    pub verification: ServerVerification,
+}
+
+impl ClientTlsConfig {
+    pub fn new(server_name: impl Into<String>, verification: ServerVerification) -> Self {
+        Self {
+            server_name: server_name.into(),
+            verification,
+        }
+    }
+
+    pub(super) fn server_name(&self) -> Result<ServerName<'static>> {
+        ServerName::try_from(self.server_name.clone())
+            .map_err(|_| ConnectionError::InvalidServerName(self.server_name.clone()))
+    }
+
+    pub(super) fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let config = match &self.verification {
+            ServerVerification::CaFile(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+                ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth()
+            }
+            ServerVerification::Fingerprint(fingerprint) => ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(FingerprintVerifier::new(fingerprint)?))
+                .with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// SHA-256 fingerprint of a certificate, formatted the way it is expected in
+/// [`ServerVerification::Fingerprint`].
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(ConnectionError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| ConnectionError::NoPrivateKey(path.to_path_buf()))
+}
+
+/// Accepts the server certificate only if it matches a pinned fingerprint. The chain and the
+/// server name are not checked, which is what makes pinning work with self-signed certificates;
+/// the handshake signatures are still verified so the server has to own the pinned key.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl FingerprintVerifier {
+    fn new(fingerprint: &str) -> Result<Self> {
+        let normalised = fingerprint.replace(':', "").to_lowercase();
+        if normalised.len() != 64 || !normalised.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ConnectionError::InvalidFingerprint(fingerprint.to_string()));
+        }
+        let fingerprint = normalised
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        Ok(Self {
+            fingerprint,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        })
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
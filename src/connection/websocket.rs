@@ -0,0 +1,153 @@
+use super::{BoxedStream, ConnectionError, FrameReader, FrameType, FrameWriter, Result, Stream};
+
+use async_trait::async_trait;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use std::fmt::{self, Debug, Formatter};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::warn;
+
+pub type WebSocketReader = SplitStream<WebSocketStream<BoxedStream>>;
+pub type WebSocketWriter = SplitSink<WebSocketStream<BoxedStream>, Message>;
+
+/// A connection from a browser or any other WebSocket client. Frames are the same messages the
+/// terminal client sends, encoded as JSON in text messages instead of length-prefixed bincode.
+pub struct WebSocketConnection {
+    stream: WebSocketStream<BoxedStream>,
+}
+
+impl Debug for WebSocketConnection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketConnection").finish()
+    }
+}
+
+impl WebSocketConnection {
+    /// Perform the WebSocket upgrade on an accepted stream, plain or TLS. Messages from the peer
+    /// longer than `max_frame_size` bytes close the connection.
+    pub async fn accept(stream: impl Stream, max_frame_size: usize) -> Result<Self> {
+        let stream: BoxedStream = Box::new(stream);
+        let config = WebSocketConfig {
+            max_message_size: Some(max_frame_size),
+            max_frame_size: Some(max_frame_size),
+            ..Default::default()
+        };
+        let stream = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+        Ok(Self { stream })
+    }
+
+    pub async fn read_frame<F: FrameType>(&mut self) -> Result<F> {
+        read_message(&mut self.stream).await
+    }
+
+    pub async fn write_frame<F: FrameType>(&mut self, frame: &F) -> Result<()> {
+        write_message(&mut self.stream, frame).await
+    }
+
+    pub fn split_into(self) -> (WebSocketReader, WebSocketWriter) {
+        let (writer, reader) = self.stream.split();
+        (reader, writer)
+    }
+}
+
+/// Read the next JSON frame, skipping control messages. Pings are answered by tungstenite itself.
+async fn read_message<S, F>(stream: &mut S) -> Result<F>
+where
+    S: futures::Stream<Item = std::result::Result<Message, tokio_tungstenite::tungstenite::Error>>
+        + Unpin
+        + Send,
+    F: FrameType,
+{
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Binary(data))) => return Ok(serde_json::from_slice(&data)?),
+            Some(Ok(Message::Close(_))) | None => {
+                warn!("Connection closed!");
+                return Err(ConnectionError::ConnectionClosed);
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+async fn write_message<S, F>(sink: &mut S, frame: &F) -> Result<()>
+where
+    S: futures::Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin + Send,
+    F: FrameType,
+{
+    let text = serde_json::to_string(frame)?;
+    sink.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl FrameReader for WebSocketReader {
+    async fn read_frame<F: FrameType>(&mut self) -> Result<F> {
+        read_message(self).await
+    }
+}
+
+#[async_trait]
+impl FrameWriter for WebSocketWriter {
+    async fn write_frame<F: FrameType>(&mut self, frame: &F) -> Result<()> {
+        write_message(self, frame).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(SinkExt::close(self).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::messages::{ClientMessage, ClientRequest, RequestId, ServerInternal};
+
+    /// Accept a WebSocket client on one end of an in-memory stream, returning the client's end.
+    async fn connect(max_frame_size: usize) -> (WebSocketConnection, WebSocketStream<BoxedStream>) {
+        let (client, server) = tokio::io::duplex(4096);
+        let client: BoxedStream = Box::new(client);
+        let (server, client) = tokio::join!(
+            WebSocketConnection::accept(server, max_frame_size),
+            tokio_tungstenite::client_async("ws://localhost/", client)
+        );
+        (server.unwrap(), client.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn frames_are_json_text_messages() {
+        let (mut server, mut client) = connect(1024).await;
+        let request = r#"{"id":7,"message":{"GlobalChatMessage":"hello"}}"#;
+        client
+            .send(Message::Text(request.to_string()))
+            .await
+            .unwrap();
+        let frame: ClientRequest = server.read_frame().await.unwrap();
+        assert_eq!(frame.id, RequestId::new(7));
+        assert_eq!(frame.message.content(), Some("hello"));
+
+        server.write_frame(&ServerInternal::Pong(3)).await.unwrap();
+        let Some(Ok(Message::Text(text))) = client.next().await else {
+            panic!("expected a text message");
+        };
+        assert_eq!(text, r#"{"Pong":3}"#);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_close_the_connection() {
+        let (mut server, mut client) = connect(64).await;
+        let message = ClientMessage::GlobalChatMessage("x".repeat(128));
+        let text = serde_json::to_string(&ClientRequest {
+            id: RequestId::new(1),
+            message,
+        })
+        .unwrap();
+        client.send(Message::Text(text)).await.unwrap();
+        let result = server.read_frame::<ClientRequest>().await;
+        assert!(matches!(result, Err(ConnectionError::WebSocket(_))));
+    }
+}
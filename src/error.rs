@@ -13,6 +13,8 @@ pub enum Error {
     Server(crate::server::ServerError),
     #[from]
     Client(crate::client::ClientError),
+    #[from]
+    Config(crate::config::ConfigError),
 }
 
 //Error boilerplate
@@ -1,63 +1,154 @@
 use super::{Result, ServerError};
 use crate::common::{
     messages::{
-        ClientMessage, Handshake, ProcessInternal, ProcessMessage, ServerInternal, ServerMessage,
-        UserInternal, UserMessage,
+        ClientMessage, ClientRequest, ErrorCode, ErrorMessage, Handshake, HandshakeRejection,
+        HandshakeResponse, ProcessInternal, ProcessMessage, Reply, RequestId, ServerInternal,
+        ServerMessage, UserInternal, UserMessage, VersionedFrame,
     },
-    UserName,
+    CommonError, MessageId, UserName,
 };
-use crate::connection::{Connection, FrameType};
+use crate::connection::{spawn_frame_reader, ConnectionError, FrameReader, FrameWriter};
 
-use crossterm::style::Stylize;
-use tokio::{
-    net::TcpStream,
-    sync::{broadcast, mpsc, oneshot},
-};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-/// Handles the client connection, reading and writing messages to the stream.
-pub struct ClientHandler {
+/// Limits applied to every connection, taken from the server config.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientSettings {
+    /// How long the user may take to be authenticated once the handshake is received.
+    pub handshake_timeout: Duration,
+    /// How long the connection may take to send what is still queued for it when shutting down.
+    pub drain_timeout: Duration,
+    /// Longest chat message accepted, in characters.
+    pub max_message_length: usize,
+    /// How long the user may go without doing anything before they are shown as away.
+    pub away_after: Option<Duration>,
+}
+
+/// The answer to a request, resolved by whichever processor handled it. An error from the
+/// channel means the processor dropped the request without answering.
+type PendingReply = BoxFuture<
+    'static,
+    (
+        RequestId,
+        std::result::Result<
+            std::result::Result<Option<MessageId>, ErrorMessage>,
+            oneshot::error::RecvError,
+        >,
+    ),
+>;
+
+/// Handles the client connection, reading and writing messages to the stream. The reader and
+/// writer are the two halves of either a native connection or a WebSocket connection, both feed
+/// the same pipeline.
+pub struct ClientHandler<W> {
     user: UserName,
-    connection: Connection,
+    /// The account the user logged in with, `user` changes along with their name.
+    login: UserName,
+    /// The protocol version negotiated in the handshake, frames are sent as it lays them out.
+    version: u16,
+    /// Frames from the client, read on a task of their own as reading is not cancel safe.
+    frames_rx: mpsc::Receiver<std::result::Result<ClientRequest, ConnectionError>>,
+    writer: W,
     client_rx: mpsc::Receiver<ServerMessage>,
     server_command_tx: mpsc::Sender<ProcessMessage>,
     server_broadcast_rx: broadcast::Receiver<ServerMessage>,
+    /// Requests sent on to the processors and not answered yet.
+    pending: FuturesUnordered<PendingReply>,
+    settings: ClientSettings,
+    shutdown: CancellationToken,
+    /// When the user last did something, see [`ClientMessage::is_activity`].
+    last_active: Instant,
+    /// Whether the user was reported idle and has not done anything since.
+    idle: bool,
 }
 
-impl ClientHandler {
-    pub async fn init(
-        connection: TcpStream,
+impl<W: FrameWriter> ClientHandler<W> {
+    pub async fn init<R: FrameReader + 'static>(
+        mut reader: R,
+        mut writer: W,
+        address: IpAddr,
         server_broadcast_rx: broadcast::Receiver<ServerMessage>,
         mut server_command_tx: mpsc::Sender<ProcessMessage>,
+        settings: ClientSettings,
+        shutdown: CancellationToken,
     ) -> Result<Self> {
-        let mut connection = Connection::from_stream(connection);
-
-        let (user, client_rx) = Self::authenticate(&mut connection, &mut server_command_tx).await?;
+        let (user, version, client_rx) = Self::authenticate(
+            &mut reader,
+            &mut writer,
+            address,
+            &mut server_command_tx,
+            settings.handshake_timeout,
+        )
+        .await?;
 
         Ok(Self {
+            login: user.clone(),
             user,
-            connection,
+            version,
+            frames_rx: spawn_frame_reader(reader),
+            writer,
             client_rx,
             server_command_tx,
             server_broadcast_rx,
+            pending: FuturesUnordered::new(),
+            settings,
+            shutdown,
+            last_active: Instant::now(),
+            idle: false,
         })
     }
 
     // TODO: ewww clean this up
     async fn authenticate(
-        connection: &mut Connection,
+        reader: &mut impl FrameReader,
+        writer: &mut W,
+        address: IpAddr,
         server_command_tx: &mut mpsc::Sender<ProcessMessage>,
-    ) -> Result<(UserName, mpsc::Receiver<ServerMessage>)> {
+        handshake_timeout: Duration,
+    ) -> Result<(UserName, u16, mpsc::Receiver<ServerMessage>)> {
         debug!("Waiting for handshake frame");
-        let user = match connection.read_frame().await {
-            Ok(Handshake(user)) => user,
-            _ => {
-                error!("Expected Handshake frame");
+        let handshake: Handshake = match reader.read_frame().await {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                error!("Expected Handshake frame: {}", e);
+                let _ = writer
+                    .write_frame(&HandshakeResponse::Rejected(
+                        HandshakeRejection::InvalidHandshake,
+                    ))
+                    .await;
                 return Err(ServerError::InvalidHandshake);
             }
         };
 
-        debug!("Handshake received: {}", user);
+        debug!("Handshake received: {}", handshake);
+
+        let (version, capabilities) = match handshake.negotiate() {
+            Ok(negotiated) => negotiated,
+            Err(rejection) => {
+                warn!("Handshake rejected: {}", rejection);
+                return Self::reject(writer, rejection).await;
+            }
+        };
+        let Handshake {
+            user_name: user,
+            authentication,
+            ..
+        } = handshake;
+        let user = match UserName::parse(user.user_name()) {
+            Ok(user) => user,
+            Err(reason) => {
+                warn!("Handshake rejected, invalid user name: {}", reason);
+                return Self::reject(writer, HandshakeRejection::InvalidUserName(reason)).await;
+            }
+        };
 
         debug!("Add user to server");
         let (oneshot_tx, oneshot_rx) = oneshot::channel();
@@ -66,72 +157,152 @@ impl ClientHandler {
             .send(ProcessMessage::Internal(ProcessInternal::UserMessage(
                 UserMessage {
                     from_user: user.clone(),
-                    message: UserInternal::NewUser(oneshot_tx),
+                    message: UserInternal::NewUser {
+                        authentication,
+                        address,
+                        sender: oneshot_tx,
+                    },
                 },
             )))
             .await?;
 
         tokio::select! {
-            _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {
+            _ = tokio::time::sleep(handshake_timeout) => {
                 error!("Handshake timeout");
+                Self::reject(writer, HandshakeRejection::Timeout).await
             }
             Ok(client_rx_result) = oneshot_rx => {
                 match client_rx_result {
                     Ok(client_rx) => {
-
                         debug!("User added to server");
                         // Send back a response to the client
-                        connection
-                            .write_frame(&ServerInternal::ServerMessage(format!(
-                            "Welcome, {}!",
-                            user.to_string().green()
-                        ))).await?;
+                        writer
+                            .write_frame(&HandshakeResponse::Accepted {
+                                version,
+                                capabilities,
+                                user_name: user.clone(),
+                            })
+                            .await?;
                         debug!("Handshake complete");
-                        return Ok((user, client_rx))
+                        Ok((user, version, client_rx))
                     }
                     Err(e) => {
                         error!("Handshake failed: {}", e);
+                        let rejection = match e {
+                            CommonError::UserExists(user) => HandshakeRejection::UserExists(user),
+                            CommonError::InvalidUserName(reason) => {
+                                HandshakeRejection::InvalidUserName(reason)
+                            }
+                            CommonError::AccountExists(user) => {
+                                HandshakeRejection::AccountExists(user)
+                            }
+                            CommonError::InvalidCredentials => {
+                                HandshakeRejection::InvalidCredentials
+                            }
+                            CommonError::PasswordTooShort(min) => {
+                                HandshakeRejection::PasswordTooShort(min)
+                            }
+                            CommonError::BannedFromServer(reason) => {
+                                HandshakeRejection::Banned { reason }
+                            }
+                            _ => HandshakeRejection::ServerError,
+                        };
+                        Self::reject(writer, rejection).await
                     }
                 }
             }
         }
-        connection
-            .write_frame(&ServerInternal::ServerMessage(
-                "Handshake timeout".to_string(),
-            ))
+    }
+
+    async fn reject<T>(writer: &mut W, rejection: HandshakeRejection) -> Result<T> {
+        writer
+            .write_frame(&HandshakeResponse::Rejected(rejection.clone()))
             .await?;
-        Err(ServerError::HandshakeTimeout)
+        Err(ServerError::HandshakeRejected(rejection))
     }
 
     pub async fn run(&mut self) -> Result<()> {
-        let (mut reader, mut writer) = self.connection.split();
+        let result = self.serve().await;
+        // Let the client know why it is being dropped, unless the connection itself failed.
+        if let Err(e) = &result {
+            if !matches!(e, ServerError::Connection(_)) {
+                let _ = self.send(ServerInternal::Error(e.into())).await;
+            }
+        }
+        result
+    }
 
+    async fn serve(&mut self) -> Result<()> {
         loop {
+            let idle_at = match self.settings.away_after {
+                Some(away_after) if !self.idle => Some(self.last_active + away_after),
+                _ => None,
+            };
             tokio::select! {
-            frame = ClientMessage::read_frame_from(&mut reader) => {
-                match frame {
-                    Ok(frame) => {
+            frame = self.frames_rx.recv() => {
+                match frame.unwrap_or(Err(ConnectionError::ConnectionClosed)) {
+                    Ok(ClientRequest { id, message }) => {
+                        let max = self.settings.max_message_length;
+                        if message.content().is_some_and(|content| content.chars().count() > max) {
+                            warn!("Message from {} is too long", self.user);
+                            let error = ErrorMessage::new(
+                                ErrorCode::MessageTooLong,
+                                format!("messages are limited to {} characters", max),
+                            )
+                            .for_request(id);
+                            self.send(ServerInternal::Error(error)).await?;
+                            continue;
+                        }
+                        if message.is_activity() {
+                            // Setting a presence replaces the one set for being idle.
+                            let set_presence = matches!(message, ClientMessage::SetPresence { .. });
+                            self.active(!set_presence).await?;
+                        }
+                        let (reply, reply_rx) = Reply::new();
+                        self.pending.push(reply_rx.map(move |answer| (id, answer)).boxed());
                         let message = ProcessMessage::ClientMessage {
                             from_user: self.user.clone(),
-                            message: frame,
+                            login: self.login.clone(),
+                            message,
+                            reply,
                         };
                         self.server_command_tx.send(message).await?;}
+                    // The frame was read in full, so the next one can still be read.
+                    Err(e) if e.is_invalid_frame() => {
+                        warn!("Invalid frame from {}: {}", self.user, e);
+                        self.send(ServerInternal::Error((&e).into())).await?;
+                    }
                     Err(e) => {
                         error!("Error reading frame: {}", e);
-                        self.server_command_tx.send(ProcessMessage::Internal(
-                            ProcessInternal::UserMessage(UserMessage {
-                                from_user: self.user.clone(),
-                                message: UserInternal::DisconnectUser,
-                            }),
-                        )).await?;
+                        self.report(UserInternal::DisconnectUser).await?;
                         break;
                     }
             }},
 
+            _ = tokio::time::sleep_until(idle_at.unwrap_or_else(Instant::now)), if idle_at.is_some() => {
+                debug!("{} is idle", self.user);
+                self.idle = true;
+                self.report(UserInternal::Idle).await?;
+            },
+
+            _ = self.shutdown.cancelled() => {
+                self.drain(None).await;
+                break;
+            },
+
             Ok(ServerMessage { from_user, content }) = self.server_broadcast_rx.recv() => {
-                if self.user != from_user {
+                if let ServerInternal::ServerShutdown { .. } = content {
+                    self.drain(Some(content)).await;
+                    break;
+                }
+                // The connection may not have heard of its own new name yet.
+                let own_rename = matches!(
+                    &content,
+                    ServerInternal::NickChanged { from, .. } if *from == self.user
+                );
+                if self.user != from_user && !own_rename {
                     info!("Sending from server_broadcast_rx");
-                    content.write_frame_to(&mut writer).await?;
+                    self.send(content).await?;
                 }
             },
 
@@ -140,7 +311,28 @@ impl ClientHandler {
                     debug!("Message from self");
                 }
                 info!("Sending from client_rx send user: {} current user: {}", from_user, self.user);
-                content.write_frame_to(&mut writer).await?;
+                if self.forward(content).await? {
+                    break;
+                }
+            },
+
+            Some((id, answer)) = self.pending.next() => {
+                // Whatever the request produced went out through the user's queue before it was
+                // answered, send that first so the ack comes last.
+                while let Ok(ServerMessage { content, .. }) = self.client_rx.try_recv() {
+                    if self.forward(content).await? {
+                        return Ok(());
+                    }
+                }
+                let frame = match answer {
+                    Ok(Ok(message)) => ServerInternal::Ack { request: id, message },
+                    Ok(Err(error)) => ServerInternal::Error(error.for_request(id)),
+                    Err(_) => {
+                        error!("Request {} from {} was dropped unanswered", id, self.user);
+                        ServerInternal::Error(ErrorMessage::new(ErrorCode::Internal, "").for_request(id))
+                    }
+                };
+                self.send(frame).await?;
             },
                 else => break
             }
@@ -153,4 +345,92 @@ impl ClientHandler {
 
         Ok(())
     }
+
+    /// Note that the user did something, telling the server they are back if they were reported
+    /// idle and `report_back` is set.
+    async fn active(&mut self, report_back: bool) -> Result<()> {
+        self.last_active = Instant::now();
+        if std::mem::take(&mut self.idle) && report_back {
+            self.report(UserInternal::Active).await?;
+        }
+        Ok(())
+    }
+
+    /// Write a frame the way the client's protocol version lays it out, leaving out frames that
+    /// version has never heard of.
+    async fn send(&mut self, frame: ServerInternal) -> Result<()> {
+        if let Some(frame) = VersionedFrame::new(frame, self.version) {
+            self.writer.write_frame(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Tell the user processor something about this connection's user.
+    async fn report(&mut self, message: UserInternal) -> Result<()> {
+        self.server_command_tx
+            .send(ProcessMessage::Internal(ProcessInternal::UserMessage(
+                UserMessage {
+                    from_user: self.user.clone(),
+                    message,
+                },
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// Write a frame from the user's queue, returning whether it ended the connection. Users
+    /// disconnected by an admin are already gone from the server, so the connection is closed
+    /// right after the notice.
+    async fn forward(&mut self, content: ServerInternal) -> Result<bool> {
+        if let ServerInternal::NickChanged { from, to } = &content {
+            if *from == self.user {
+                info!("{} is now known as {}", from, to);
+                self.user = to.clone();
+            }
+        }
+        let disconnected = matches!(content, ServerInternal::Disconnected { .. });
+        self.send(content).await?;
+        if disconnected {
+            info!("{} was disconnected by an admin", self.user);
+            self.writer.close().await?;
+        }
+        Ok(disconnected)
+    }
+
+    /// Send everything still queued for the client, ending with the shutdown notice, and close the
+    /// connection. Gives up once the drain timeout has passed.
+    async fn drain(&mut self, notice: Option<ServerInternal>) {
+        info!("Draining connection of {}", self.user);
+        match tokio::time::timeout(self.settings.drain_timeout, self.drain_queues(notice)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Error draining connection of {}: {}", self.user, e),
+            Err(_) => warn!("Timed out draining connection of {}", self.user),
+        }
+    }
+
+    async fn drain_queues(&mut self, mut notice: Option<ServerInternal>) -> Result<()> {
+        while let Ok(ServerMessage { content, .. }) = self.client_rx.try_recv() {
+            self.send(content).await?;
+        }
+        loop {
+            match self.server_broadcast_rx.try_recv() {
+                Ok(ServerMessage {
+                    content: content @ ServerInternal::ServerShutdown { .. },
+                    ..
+                }) => notice = Some(content),
+                Ok(ServerMessage { from_user, content }) => {
+                    if self.user != from_user {
+                        self.send(content).await?;
+                    }
+                }
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        if let Some(notice) = notice {
+            self.send(notice).await?;
+        }
+        self.writer.close().await?;
+        Ok(())
+    }
 }
@@ -1,4 +1,7 @@
-use crate::common::messages::{ProcessMessage, RoomMessage, ServerMessage, UserMessage};
+use crate::common::messages::{
+    ErrorCode, ErrorMessage, HandshakeRejection, HistoryMessage, ProcessMessage, RoomMessage,
+    ServerMessage, UserMessage,
+};
 use crate::common::UserName;
 
 pub type Result<T> = std::result::Result<T, ServerError>;
@@ -11,20 +14,18 @@ pub enum ServerError {
     Io(std::io::Error),
     #[from]
     GetUserBroadcastFailed(tokio::sync::oneshot::error::RecvError),
-    #[from]
-    ServerBroadcastFailed(tokio::sync::broadcast::error::SendError<ServerMessage>),
-    #[from]
-    ClientBroadcastFailed(tokio::sync::mpsc::error::SendError<ProcessMessage>),
-    #[from]
-    OutputBroadcastFailed(tokio::sync::mpsc::error::SendError<ServerMessage>),
+    ServerBroadcastFailed(Box<tokio::sync::broadcast::error::SendError<ServerMessage>>),
+    ClientBroadcastFailed(Box<tokio::sync::mpsc::error::SendError<ProcessMessage>>),
+    OutputBroadcastFailed(Box<tokio::sync::mpsc::error::SendError<ServerMessage>>),
     #[from]
     UserBroadcastFailed(tokio::sync::mpsc::error::SendError<UserMessage>),
+    RoomBroadcastFailed(Box<tokio::sync::mpsc::error::SendError<RoomMessage>>),
     #[from]
-    RoomBroadcastFailed(tokio::sync::mpsc::error::SendError<RoomMessage>),
+    HistoryBroadcastFailed(tokio::sync::mpsc::error::SendError<HistoryMessage>),
     #[from]
     Common(crate::common::CommonError),
     InvalidHandshake,
-    HandshakeTimeout,
+    HandshakeRejected(HandshakeRejection),
     UserNotFound(UserName),
 }
 
@@ -36,3 +37,54 @@ impl core::fmt::Display for ServerError {
 }
 
 impl std::error::Error for ServerError {}
+
+// Client, room and server messages are large enough to bloat every `Result` if they are not
+// boxed.
+impl From<tokio::sync::broadcast::error::SendError<ServerMessage>> for ServerError {
+    fn from(e: tokio::sync::broadcast::error::SendError<ServerMessage>) -> Self {
+        ServerError::ServerBroadcastFailed(Box::new(e))
+    }
+}
+
+impl From<tokio::sync::mpsc::error::SendError<ServerMessage>> for ServerError {
+    fn from(e: tokio::sync::mpsc::error::SendError<ServerMessage>) -> Self {
+        ServerError::OutputBroadcastFailed(Box::new(e))
+    }
+}
+
+impl From<tokio::sync::mpsc::error::SendError<ProcessMessage>> for ServerError {
+    fn from(e: tokio::sync::mpsc::error::SendError<ProcessMessage>) -> Self {
+        ServerError::ClientBroadcastFailed(Box::new(e))
+    }
+}
+
+impl From<tokio::sync::mpsc::error::SendError<RoomMessage>> for ServerError {
+    fn from(e: tokio::sync::mpsc::error::SendError<RoomMessage>) -> Self {
+        ServerError::RoomBroadcastFailed(Box::new(e))
+    }
+}
+
+/// What the client is told. Failures internal to the server are not detailed.
+impl From<&ServerError> for ErrorMessage {
+    fn from(e: &ServerError) -> Self {
+        match e {
+            ServerError::Common(e) => e.into(),
+            ServerError::Connection(e) => e.into(),
+            ServerError::InvalidHandshake => ErrorMessage::new(ErrorCode::InvalidRequest, ""),
+            ServerError::HandshakeRejected(rejection) => {
+                ErrorMessage::new(ErrorCode::InvalidRequest, rejection.to_string())
+            }
+            ServerError::UserNotFound(user) => {
+                ErrorMessage::new(ErrorCode::UserNotFound, format!("{} is not online", user))
+            }
+            ServerError::Io(_)
+            | ServerError::GetUserBroadcastFailed(_)
+            | ServerError::ServerBroadcastFailed(_)
+            | ServerError::ClientBroadcastFailed(_)
+            | ServerError::OutputBroadcastFailed(_)
+            | ServerError::UserBroadcastFailed(_)
+            | ServerError::RoomBroadcastFailed(_)
+            | ServerError::HistoryBroadcastFailed(_) => ErrorMessage::new(ErrorCode::Internal, ""),
+        }
+    }
+}
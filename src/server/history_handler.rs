@@ -0,0 +1,75 @@
+use super::Result;
+
+use crate::common::{messages::HistoryMessage, HistoryStore};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, instrument};
+
+/// Owns the history store so that every message is written to the log from a single task.
+pub struct HistoryProcessor {
+    history_processor_rx: mpsc::Receiver<HistoryMessage>,
+    history: HistoryStore,
+}
+
+impl HistoryProcessor {
+    pub fn new(
+        history_processor_rx: mpsc::Receiver<HistoryMessage>,
+        history: HistoryStore,
+    ) -> Self {
+        Self {
+            history_processor_rx,
+            history,
+        }
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    pub async fn run(mut self) -> Result<()> {
+        while let Some(message) = self.history_processor_rx.recv().await {
+            match message {
+                HistoryMessage::Record(record) => {
+                    debug!("Recording message from: {}", record.from_user);
+                    // Losing a history record should not take the chat down with it.
+                    if let Err(e) = self.history.record(*record) {
+                        error!("Failed to record message: {}", e);
+                    }
+                }
+                HistoryMessage::Edit(record) => {
+                    debug!("Recording edit of message {}", record.id);
+                    if let Err(e) = self.history.edit(*record) {
+                        error!("Failed to record edit: {}", e);
+                    }
+                }
+                HistoryMessage::Delete(id) => {
+                    debug!("Recording deletion of message {}", id);
+                    if let Err(e) = self.history.delete(id) {
+                        error!("Failed to record deletion: {}", e);
+                    }
+                }
+                HistoryMessage::React {
+                    id,
+                    user,
+                    reaction,
+                    add,
+                    sender,
+                } => {
+                    debug!("Recording reaction of {} to message {}", user, id);
+                    let result = self.history.react(id, user, reaction, add);
+                    if let Err(e) = &result {
+                        error!("Failed to record reaction: {}", e);
+                    }
+                    let _ = sender.send(result);
+                }
+                HistoryMessage::Find { id, sender } => {
+                    let _ = sender.send(self.history.find(id));
+                }
+                HistoryMessage::RoomHistory { room_name, sender } => {
+                    info!("History requested for room: {}", room_name);
+                    let _ = sender.send(self.history.room_history(&room_name));
+                }
+            }
+        }
+        // Every sender is gone, so nothing else can be recorded.
+        info!("Flushing history");
+        self.history.flush()?;
+        Ok(())
+    }
+}
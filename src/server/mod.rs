@@ -1,53 +1,105 @@
 mod client_handler;
 mod error;
+mod history_handler;
 mod processor;
 mod room_handler;
 mod user_handler;
 
-use crate::common::messages::ServerMessage;
-use client_handler::ClientHandler;
+use crate::common::{
+    messages::{
+        HandshakeRejection, HandshakeResponse, ProcessMessage, ServerInternal, ServerMessage,
+    },
+    AccountStore, HistoryStore, MailboxStore, UserManager, UserName,
+};
+use crate::config::ServerConfig;
+use crate::connection::{Connection, FrameReader, FrameWriter, WebSocketConnection};
+use client_handler::{ClientHandler, ClientSettings};
 use error::Result;
 pub use error::ServerError;
+use history_handler::HistoryProcessor;
 use processor::ServerProcessor;
 use room_handler::RoomProcessor;
 use user_handler::UserProcessor;
 
-use tokio::net::{TcpListener, ToSocketAddrs};
-use tokio::sync::{broadcast, mpsc};
-use tracing::{error, info};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
 
 #[derive(Debug)]
 pub struct Server {
+    config: ServerConfig,
     server_broadcast_tx: broadcast::Sender<ServerMessage>,
+    shutdown_tx: mpsc::Sender<Option<String>>,
+    shutdown_rx: mpsc::Receiver<Option<String>>,
 }
 
 impl Default for Server {
     fn default() -> Self {
+        Self::new(ServerConfig::default())
+    }
+}
+
+impl Server {
+    pub fn new(config: ServerConfig) -> Self {
         // Create a broadcast channel: Used to send server messages to all threads.
-        let (server_broadcast_tx, _) = broadcast::channel(32);
+        let (server_broadcast_tx, _) = broadcast::channel(config.queues.broadcast);
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
         Self {
+            config,
             server_broadcast_tx,
+            shutdown_tx,
+            shutdown_rx,
         }
     }
-}
 
-impl Server {
-    pub async fn run(&mut self, addr: impl ToSocketAddrs) -> Result<()> {
+    /// A handle that can stop the server from another task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown_tx: self.shutdown_tx.clone(),
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
         info!("Server started");
-        let listener = TcpListener::bind(addr).await?;
+        let listener = TcpListener::bind(self.config.address()).await?;
         info!("Listening on: {}", listener.local_addr()?);
 
+        let acceptor = match &self.config.tls {
+            Some(tls) => {
+                info!("TLS enabled");
+                Some(tls.acceptor()?)
+            }
+            None => None,
+        };
+
+        // Every task is spawned on the tracker so that shutting down can wait for all of them, and
+        // every connection watches the token to know when to wrap up.
+        let tasks = TaskTracker::new();
+        let shutdown = CancellationToken::new();
+
         // Start a new task to handle users
-        let (user_processor_tx, user_processor_rx) = mpsc::channel(32);
-        let (server_processor_tx, server_processor_rx) = mpsc::channel(64);
-        let (room_processor_tx, room_processor_rx) = mpsc::channel(32);
+        let queues = &self.config.queues;
+        let (user_processor_tx, user_processor_rx) = mpsc::channel(queues.users);
+        let (server_processor_tx, server_processor_rx) = mpsc::channel(queues.server);
+        let (room_processor_tx, room_processor_rx) = mpsc::channel(queues.rooms);
+        let (history_processor_tx, history_processor_rx) = mpsc::channel(queues.history);
 
-        let user_processor =
-            UserProcessor::new(user_processor_rx, self.server_broadcast_tx.clone());
+        let history = HistoryStore::open(
+            &self.config.storage.history_path,
+            self.config.limits.history_replay_limit,
+            self.config.limits.keep_messages_for(),
+            self.config.limits.max_kept_messages,
+        )?;
+        let history_processor = HistoryProcessor::new(history_processor_rx, history);
 
-        tokio::spawn(async move {
-            if let Err(e) = user_processor.run().await {
-                error!("Error running user processor: {}", e);
+        tasks.spawn(async move {
+            if let Err(e) = history_processor.run().await {
+                error!("Error running history processor: {}", e);
             }
         });
 
@@ -55,9 +107,33 @@ impl Server {
             room_processor_rx,
             user_processor_tx.clone(),
             self.server_broadcast_tx.clone(),
+            history_processor_tx.clone(),
+            tasks.clone(),
+            self.config.queues.room,
+            &self.config.rooms,
+        );
+
+        let accounts = AccountStore::open(&self.config.storage.accounts_path)?;
+        let mailbox = MailboxStore::open(
+            &self.config.storage.mailbox_path,
+            self.config.limits.max_queued_messages,
+        )?;
+        let user_processor = UserProcessor::new(
+            user_processor_rx,
+            self.server_broadcast_tx.clone(),
+            history_processor_tx.clone(),
+            room_handler.user_events_tx(),
+            UserManager::new(accounts, mailbox, self.config.queues.client),
+            &self.config.admin,
         );
 
-        tokio::spawn(async move {
+        tasks.spawn(async move {
+            if let Err(e) = user_processor.run().await {
+                error!("Error running user processor: {}", e);
+            }
+        });
+
+        tasks.spawn(async move {
             if let Err(e) = room_handler.run().await {
                 error!("Error running room processor: {}", e);
             }
@@ -68,38 +144,244 @@ impl Server {
             user_processor_tx,
             room_processor_tx,
             self.server_broadcast_tx.clone(),
+            history_processor_tx,
+            self.config.admin.users.clone(),
+            self.config.limits.edit_window(),
         );
 
         // Spawn the server processor to handle server commands and take that processing task away from client connections.
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             if let Err(e) = server_processor.run().await {
                 error!("Error running server processor: {}", e);
             }
         });
 
-        loop {
-            let (socket, client_address) = listener.accept().await?;
+        let context = ConnectionContext {
+            server_broadcast_tx: self.server_broadcast_tx.clone(),
+            server_processor_tx,
+            settings: ClientSettings {
+                handshake_timeout: self.config.timeouts.handshake(),
+                drain_timeout: self.config.timeouts.shutdown_drain(),
+                away_after: self.config.timeouts.away_after(),
+                max_message_length: self.config.limits.max_message_length,
+            },
+            max_frame_size: self.config.limits.max_frame_size,
+            connections: self
+                .config
+                .limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            shutdown: shutdown.clone(),
+        };
+
+        if let Some(websocket_addr) = self.config.websocket_address() {
+            let listener = TcpListener::bind(websocket_addr).await?;
+            info!(
+                "Listening for WebSocket clients on: {}",
+                listener.local_addr()?
+            );
+            tasks.spawn(accept_websockets(
+                listener,
+                acceptor.clone(),
+                context.clone(),
+                tasks.clone(),
+            ));
+        }
+
+        let reason = loop {
+            let (socket, client_address) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                // The server holds a sender itself, so the channel never closes.
+                reason = self.shutdown_rx.recv() => break reason.flatten(),
+            };
             info!("Accepted connection from: {:#}", client_address);
 
-            let mut handler = match ClientHandler::init(
-                socket,
-                self.server_broadcast_tx.subscribe(),
-                server_processor_tx.clone(),
-            )
+            let acceptor = acceptor.clone();
+            let context = context.clone();
+
+            // The TLS and chat handshakes happen in the connection's own task so that a slow
+            // client cannot hold up accepting other connections.
+            tasks.spawn(async move {
+                let connection = match acceptor {
+                    Some(acceptor) => {
+                        match context
+                            .shutdown
+                            .run_until_cancelled(Connection::accept_tls(socket, &acceptor))
+                            .await
+                        {
+                            Some(Ok(connection)) => connection,
+                            Some(Err(e)) => {
+                                error!("TLS handshake with {} failed: {}", client_address, e);
+                                return;
+                            }
+                            None => return,
+                        }
+                    }
+                    None => Connection::from_stream(socket),
+                };
+                let (reader, writer) = connection
+                    .with_max_frame_size(context.max_frame_size)
+                    .split_into();
+                handle_client(reader, writer, client_address.ip(), context).await;
+            });
+        };
+
+        // Stop accepting before telling everyone we are going away.
+        drop(listener);
+        info!(
+            "Shutting down: {}",
+            reason.as_deref().unwrap_or("no reason given")
+        );
+
+        // Connections send the notice as the last frame once they have drained their queues.
+        send_broadcast(
+            &self.server_broadcast_tx,
+            ServerMessage {
+                from_user: UserName::new("server"),
+                content: ServerInternal::ServerShutdown { reason },
+            },
+        );
+        shutdown.cancel();
+
+        // Once the connections are gone the processors see their channels close one after the
+        // other, finish what is queued and exit, the history processor flushing the log last.
+        drop(context);
+        tasks.close();
+        if tokio::time::timeout(self.config.timeouts.shutdown(), tasks.wait())
             .await
-            {
-                Ok(handler) => handler,
-                Err(e) => {
-                    error!("Error initializing client handler: {}", e);
-                    continue;
+            .is_err()
+        {
+            warn!("Timed out waiting for {} tasks to finish", tasks.len());
+        }
+        info!("Server stopped");
+        Ok(())
+    }
+}
+
+/// Stops a running [`Server`]. Handles can be cloned and sent to other tasks, e.g. one waiting
+/// for Ctrl-C.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    shutdown_tx: mpsc::Sender<Option<String>>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to shut down, telling the connected clients `reason`. Does nothing if a
+    /// shutdown has already been requested.
+    pub fn shutdown(&self, reason: Option<String>) {
+        let _ = self.shutdown_tx.try_send(reason);
+    }
+}
+
+/// Accept loop of the WebSocket gateway. Upgraded connections are handed to a [`ClientHandler`]
+/// just like native ones, so both kinds of clients share users, rooms and messages.
+async fn accept_websockets(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    context: ConnectionContext,
+    tasks: TaskTracker,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.cancelled() => break,
+        };
+        let (socket, client_address) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Error accepting WebSocket connection: {}", e);
+                continue;
+            }
+        };
+        info!("Accepted WebSocket connection from: {:#}", client_address);
+
+        let acceptor = acceptor.clone();
+        let context = context.clone();
+
+        tasks.spawn(async move {
+            let max_frame_size = context.max_frame_size;
+            let upgrade = async {
+                match acceptor {
+                    Some(acceptor) => {
+                        let connection = Connection::accept_tls(socket, &acceptor).await?;
+                        WebSocketConnection::accept(connection.into_stream(), max_frame_size).await
+                    }
+                    None => WebSocketConnection::accept(socket, max_frame_size).await,
                 }
             };
-
-            tokio::spawn(async move {
-                if let Err(e) = handler.run().await {
-                    error!("Error handling connection: {}", e);
+            let connection = match context.shutdown.run_until_cancelled(upgrade).await {
+                Some(Ok(connection)) => connection,
+                Some(Err(e)) => {
+                    error!("WebSocket upgrade with {} failed: {}", client_address, e);
+                    return;
                 }
-            });
+                None => return,
+            };
+            let (reader, writer) = connection.split_into();
+            handle_client(reader, writer, client_address.ip(), context).await;
+        });
+    }
+}
+
+/// What a connection task needs from the server, cloned into each of them.
+#[derive(Debug, Clone)]
+struct ConnectionContext {
+    server_broadcast_tx: broadcast::Sender<ServerMessage>,
+    server_processor_tx: mpsc::Sender<ProcessMessage>,
+    settings: ClientSettings,
+    /// Longest frame read from a client, in bytes.
+    max_frame_size: usize,
+    /// One permit per connection allowed at once, if there is a limit.
+    connections: Option<Arc<Semaphore>>,
+    shutdown: CancellationToken,
+}
+
+/// Authenticate a freshly connected client and serve it until it disconnects or the server shuts
+/// down.
+async fn handle_client<R: FrameReader + 'static, W: FrameWriter>(
+    reader: R,
+    mut writer: W,
+    address: IpAddr,
+    context: ConnectionContext,
+) {
+    // Held until the connection is done with.
+    let _permit = match context.connections.map(Semaphore::try_acquire_owned) {
+        Some(Err(_)) => {
+            warn!("Turning a client away, the server is full");
+            let _ = writer
+                .write_frame(&HandshakeResponse::Rejected(HandshakeRejection::ServerFull))
+                .await;
+            let _ = writer.close().await;
+            return;
+        }
+        permit => permit,
+    };
+
+    let init = ClientHandler::init(
+        reader,
+        writer,
+        address,
+        context.server_broadcast_tx.subscribe(),
+        context.server_processor_tx,
+        context.settings,
+        context.shutdown.clone(),
+    );
+    let mut handler = match context.shutdown.run_until_cancelled(init).await {
+        Some(Ok(handler)) => handler,
+        Some(Err(e)) => {
+            error!("Error initializing client handler: {}", e);
+            return;
         }
+        None => return,
+    };
+
+    if let Err(e) = handler.run().await {
+        error!("Error handling connection: {}", e);
     }
 }
+
+/// Send `message` to every connected client but the one it is from. Sending only fails when
+/// nobody is left to receive the broadcast, which leaves nobody to tell either.
+fn send_broadcast(server_broadcast_tx: &broadcast::Sender<ServerMessage>, message: ServerMessage) {
+    let _ = server_broadcast_tx.send(message);
+}
@@ -1,20 +1,41 @@
-use super::Result;
+use super::{send_broadcast, Result};
 use crate::common::{
     messages::{
-        ClientMessage, ProcessInternal, ProcessMessage, RoomInternal, RoomMessage, ServerInternal,
-        ServerMessage, UserInternal, UserMessage,
+        AdminCommand, ClientMessage, ErrorCode, ErrorMessage, HistoryMessage, ProcessInternal,
+        ProcessMessage, Reply, RoomInternal, RoomMessage, ServerInternal, ServerMessage,
+        UserInternal, UserMessage,
     },
-    UserName,
+    ChatRecord, ChatTarget, CommonError, MessageId, Reaction, RoomMode, UserName,
 };
 
-use tokio::sync::{broadcast, mpsc};
-use tracing::{info, instrument, warn};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{debug, info, instrument, trace, warn};
+
+/// A change to a message already sent, once it was checked.
+enum MessageChange {
+    Edit(ChatRecord),
+    Delete {
+        /// Otherwise it takes a moderator of the room the message was sent to.
+        by_sender: bool,
+    },
+    React {
+        reaction: Reaction,
+        add: bool,
+    },
+}
 
 pub struct ServerProcessor {
     server_processor_rx: mpsc::Receiver<ProcessMessage>,
     user_processor_tx: mpsc::Sender<UserMessage>,
     room_processor_tx: mpsc::Sender<RoomMessage>,
     server_broadcast_tx: broadcast::Sender<ServerMessage>,
+    history_processor_tx: mpsc::Sender<HistoryMessage>,
+    /// Accounts allowed to use the admin commands, whatever name their users go by.
+    admins: HashSet<UserName>,
+    /// How long senders may change their messages, if at all.
+    edit_window: Option<Duration>,
 }
 
 impl ServerProcessor {
@@ -23,12 +44,18 @@ impl ServerProcessor {
         user_processor_tx: mpsc::Sender<UserMessage>,
         room_processor_tx: mpsc::Sender<RoomMessage>,
         server_broadcast_tx: broadcast::Sender<ServerMessage>,
+        history_processor_tx: mpsc::Sender<HistoryMessage>,
+        admins: impl IntoIterator<Item = UserName>,
+        edit_window: Option<Duration>,
     ) -> Self {
         Self {
             server_processor_rx,
             user_processor_tx,
             room_processor_tx,
             server_broadcast_tx,
+            history_processor_tx,
+            admins: admins.into_iter().collect(),
+            edit_window,
         }
     }
 
@@ -42,15 +69,21 @@ impl ServerProcessor {
                     // Start new task
                     self.handle_internal_message(process_internal).await?;
                 }
-                ProcessMessage::ClientMessage { from_user, message } => {
-                    self.handle_client_message(from_user, message).await?;
+                ProcessMessage::ClientMessage {
+                    from_user,
+                    login,
+                    message,
+                    reply,
+                } => {
+                    self.handle_client_message(from_user, login, message, reply)
+                        .await?;
                 }
                 ProcessMessage::ServerMessage { from_user, message } => {
-                    warn!("Received server message from {}", from_user);
+                    debug!("Received server message from {}", from_user);
                     self.handle_server_message(from_user, message).await?;
                 }
             }
-            info!("Received server command");
+            trace!("Received server command");
         }
         Ok(())
     }
@@ -59,14 +92,14 @@ impl ServerProcessor {
     async fn handle_internal_message(&mut self, process_internal: ProcessInternal) -> Result<()> {
         match process_internal {
             ProcessInternal::UserMessage(user_message) => {
-                warn!("Received user message: {:?}", user_message);
+                trace!("Received user message: {:?}", user_message);
                 self.user_processor_tx.send(user_message).await?;
             }
             ProcessInternal::Response(response) => {
                 warn!("Received response: {:?}", response)
             }
             ProcessInternal::RoomMessage(room_message) => {
-                warn!("Received room message: {:?}", room_message);
+                trace!("Received room message: {:?}", room_message);
                 self.room_processor_tx.send(room_message).await?;
             }
         }
@@ -77,13 +110,16 @@ impl ServerProcessor {
     async fn handle_client_message(
         &mut self,
         from_user: UserName,
+        login: UserName,
         message: ClientMessage,
+        reply: Reply,
     ) -> Result<()> {
-        info!("Received client message from {}: {:?}", from_user, message);
+        trace!("Received client message from {}: {:?}", from_user, message);
 
         match message {
             ClientMessage::Disconnect => {
-                // TODO: Remove from any room
+                reply.ack();
+                // The user processor also takes the user out of the rooms they are in.
                 self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
                     from_user,
                     message: UserInternal::DisconnectUser,
@@ -91,29 +127,110 @@ impl ServerProcessor {
                 .await?;
             }
             ClientMessage::GlobalChatMessage(content) => {
-                self.server_broadcast_tx.send(ServerMessage {
-                    from_user: from_user.clone(),
-                    content: ServerInternal::GlobalChatMessage { from_user, content },
-                })?;
+                let record = ChatRecord::new(from_user.clone(), ChatTarget::Global, &content);
+                let (id, timestamp) = (record.id, record.timestamp);
+                self.history_processor_tx
+                    .send(HistoryMessage::Record(Box::new(record)))
+                    .await?;
+                send_broadcast(
+                    &self.server_broadcast_tx,
+                    ServerMessage {
+                        from_user: from_user.clone(),
+                        content: ServerInternal::GlobalChatMessage {
+                            id,
+                            timestamp,
+                            from_user,
+                            content,
+                        },
+                    },
+                );
+                reply.sent(id);
+            }
+            ClientMessage::EditMessage { id, content } => {
+                self.edit_message(from_user, id, content, reply).await?;
+            }
+            ClientMessage::DeleteMessage(id) => {
+                self.delete_message(from_user, id, reply).await?;
+            }
+            ClientMessage::AddReaction { id, reaction } => {
+                self.react(from_user, id, reaction, true, reply).await?;
+            }
+            ClientMessage::RemoveReaction { id, reaction } => {
+                self.react(from_user, id, reaction, false, reply).await?;
             }
             ClientMessage::PrivateMessage { to_user, content } => {
                 self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
                     from_user,
-                    message: UserInternal::PrivateMessage { to_user, content },
+                    message: UserInternal::PrivateMessage {
+                        to_user,
+                        content,
+                        reply,
+                    },
                 }))
                 .await?;
             }
             ClientMessage::Ping(nonce) => {
                 self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
                     from_user,
-                    message: UserInternal::Ping(nonce),
+                    message: UserInternal::Ping(nonce, reply),
                 }))
                 .await?;
             }
             ClientMessage::ListUsers => {
                 self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
                     from_user,
-                    message: UserInternal::ListUsers,
+                    message: UserInternal::ListUsers(reply),
+                }))
+                .await?;
+            }
+            ClientMessage::ChangeNick(user_name) => {
+                self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
+                    from_user,
+                    message: UserInternal::ChangeNick { user_name, reply },
+                }))
+                .await?;
+            }
+            ClientMessage::Typing {
+                target: ChatTarget::Room(room),
+                typing,
+            } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Typing { typing },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::Typing {
+                target: ChatTarget::Private(to_user),
+                typing,
+            } => {
+                self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
+                    from_user,
+                    message: UserInternal::Typing {
+                        to_user,
+                        typing,
+                        reply,
+                    },
+                }))
+                .await?;
+            }
+            ClientMessage::Typing {
+                target: ChatTarget::Global,
+                ..
+            } => reply.error(ErrorMessage::new(
+                ErrorCode::InvalidRequest,
+                "typing is only shown in rooms and private conversations",
+            )),
+            ClientMessage::SetPresence { presence, text } => {
+                self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
+                    from_user,
+                    message: UserInternal::SetPresence {
+                        presence,
+                        text,
+                        reply,
+                    },
                 }))
                 .await?;
             }
@@ -121,7 +238,10 @@ impl ServerProcessor {
                 self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
                     from_user,
                     room_name: room,
-                    message: RoomInternal::NewRoom,
+                    message: RoomInternal::NewRoom {
+                        mode: RoomMode::Public,
+                    },
+                    reply,
                 }))
                 .await?;
             }
@@ -129,7 +249,8 @@ impl ServerProcessor {
                 self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
                     from_user,
                     room_name: room,
-                    message: RoomInternal::JoinRoom,
+                    message: RoomInternal::JoinRoom { password: None },
+                    reply,
                 }))
                 .await?;
             }
@@ -138,6 +259,7 @@ impl ServerProcessor {
                     from_user,
                     room_name: room,
                     message: RoomInternal::LeaveRoom,
+                    reply,
                 }))
                 .await?;
             }
@@ -146,6 +268,7 @@ impl ServerProcessor {
                     from_user,
                     room_name: "N/A".into(),
                     message: RoomInternal::ListRooms,
+                    reply,
                 }))
                 .await?;
             }
@@ -154,6 +277,7 @@ impl ServerProcessor {
                     from_user,
                     room_name: room,
                     message: RoomInternal::ListUsers,
+                    reply,
                 }))
                 .await?;
             }
@@ -162,6 +286,171 @@ impl ServerProcessor {
                     from_user,
                     room_name: room,
                     message: RoomInternal::RoomMessage(content),
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::SetRoomRole { room, user, role } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::SetRole { user, role },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::CreateRoomWithMode { room, mode } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::NewRoom { mode },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::JoinRoomWithPassword { room, password } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::JoinRoom {
+                        password: Some(password),
+                    },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::SetRoomMode { room, mode } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::SetMode { mode },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::InviteToRoom { room, user } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Invite { user },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::UninviteFromRoom { room, user } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Uninvite { user },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::SetRoomTopic { room, topic } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::SetTopic { topic },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::SetRoomDescription { room, description } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::SetDescription { description },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::KickUser { room, user, reason } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Kick { user, reason },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::BanUser {
+                room,
+                user,
+                duration_secs,
+                reason,
+            } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Ban {
+                        user,
+                        duration: duration_secs.map(Duration::from_secs),
+                        reason,
+                    },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::UnbanUser { room, user } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Unban { user },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::MuteUser {
+                room,
+                user,
+                duration_secs,
+            } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Mute {
+                        user,
+                        duration: duration_secs.map(Duration::from_secs),
+                    },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::UnmuteUser { room, user } => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::Unmute { user },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::DeleteRoom(room) => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::DeleteRoom { forced: false },
+                    reply,
+                }))
+                .await?;
+            }
+            ClientMessage::Admin(command) => {
+                if !self.admins.contains(&login) {
+                    warn!("{} is not an admin: {}", from_user, command);
+                    reply.error(ErrorMessage::new(
+                        ErrorCode::PermissionDenied,
+                        "only server admins can do that",
+                    ));
+                    return Ok(());
+                }
+                self.handle_admin_command(from_user, command, reply).await?;
+            }
+            ClientMessage::ListRoomRoles(room) => {
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message: RoomInternal::ListRoles,
+                    reply,
                 }))
                 .await?;
             }
@@ -170,14 +459,282 @@ impl ServerProcessor {
         Ok(())
     }
 
+    /// Send `content` to everyone, including the user it comes from. Broadcasts skip their
+    /// sender, so it goes out as the server.
+    fn broadcast_as_server(&self, content: ServerInternal) {
+        send_broadcast(
+            &self.server_broadcast_tx,
+            ServerMessage {
+                from_user: UserName::new("server"),
+                content,
+            },
+        );
+    }
+
+    /// A message that may still be edited or deleted.
+    async fn find_message(&self, id: MessageId) -> Result<Option<ChatRecord>> {
+        let (sender, receiver) = oneshot::channel();
+        self.history_processor_tx
+            .send(HistoryMessage::Find { id, sender })
+            .await?;
+        Ok(receiver.await.unwrap_or_default())
+    }
+
+    /// Edit a message for its sender.
+    #[instrument(skip(self, content, reply), level = "debug")]
+    async fn edit_message(
+        &mut self,
+        from_user: UserName,
+        id: MessageId,
+        content: String,
+        reply: Reply,
+    ) -> Result<()> {
+        let edit_window = self.edit_window;
+        let user = from_user.clone();
+        self.change_message(from_user, id, reply, move |message| {
+            message.check_sender(&user, edit_window)?;
+            let mut message = message.clone();
+            message.edit(content);
+            Ok(MessageChange::Edit(message))
+        })
+        .await
+    }
+
+    /// Delete a message, for its sender or, in a room, one of its moderators which the room
+    /// checks for itself.
+    #[instrument(skip(self, reply), level = "debug")]
+    async fn delete_message(
+        &mut self,
+        from_user: UserName,
+        id: MessageId,
+        reply: Reply,
+    ) -> Result<()> {
+        let edit_window = self.edit_window;
+        let user = from_user.clone();
+        self.change_message(from_user, id, reply, move |message| {
+            match message.check_sender(&user, edit_window) {
+                Ok(()) => Ok(MessageChange::Delete { by_sender: true }),
+                Err(_) if matches!(message.target, ChatTarget::Room(_)) => {
+                    Ok(MessageChange::Delete { by_sender: false })
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+
+    /// Add a reaction to a message, or take it back, for anyone who got the message.
+    #[instrument(skip(self, reply), level = "debug")]
+    async fn react(
+        &mut self,
+        from_user: UserName,
+        id: MessageId,
+        reaction: Reaction,
+        add: bool,
+        reply: Reply,
+    ) -> Result<()> {
+        if let Err(e) = reaction.validate() {
+            reply.error(&e);
+            return Ok(());
+        }
+        let user = from_user.clone();
+        self.change_message(from_user, id, reply, move |message| {
+            if add && !message.reactions.has_room_for(&reaction) {
+                return Err(CommonError::TooManyReactions(id));
+            }
+            // Nobody else knows of the message, so it does not exist as far as they can tell.
+            if let ChatTarget::Private(to_user) = &message.target {
+                if user != message.from_user && user != *to_user {
+                    return Err(CommonError::MessageNotFound(id));
+                }
+            }
+            Ok(MessageChange::React { reaction, add })
+        })
+        .await
+    }
+
+    /// Find the message with this ID and work out what `check` makes of the change asked for.
+    /// Changes to global messages are recorded and broadcast here, those to messages sent to a
+    /// room or user are handed to the processor that delivered them, which records them.
+    async fn change_message(
+        &mut self,
+        from_user: UserName,
+        id: MessageId,
+        reply: Reply,
+        check: impl FnOnce(&ChatRecord) -> std::result::Result<MessageChange, CommonError>,
+    ) -> Result<()> {
+        let Some(message) = self.find_message(id).await? else {
+            reply.error(&CommonError::MessageNotFound(id));
+            return Ok(());
+        };
+        let change = match check(&message) {
+            Ok(change) => change,
+            Err(e) => {
+                warn!("{} may not change message {}: {}", from_user, id, e);
+                reply.error(&e);
+                return Ok(());
+            }
+        };
+        match message.target.clone() {
+            ChatTarget::Global => self.change_global(from_user, message, change, reply).await,
+            ChatTarget::Room(room) => {
+                let message = match change {
+                    MessageChange::Edit(message) => RoomInternal::EditMessage(message),
+                    MessageChange::Delete { by_sender } => {
+                        RoomInternal::DeleteMessage { id, by_sender }
+                    }
+                    MessageChange::React { reaction, add } => {
+                        RoomInternal::React { id, reaction, add }
+                    }
+                };
+                self.handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                    from_user,
+                    room_name: room,
+                    message,
+                    reply,
+                }))
+                .await
+            }
+            ChatTarget::Private(_) => {
+                let message = match change {
+                    MessageChange::Edit(message) => UserInternal::EditMessage {
+                        message: Box::new(message),
+                        reply,
+                    },
+                    MessageChange::Delete { .. } => UserInternal::DeleteMessage {
+                        message: Box::new(message),
+                        reply,
+                    },
+                    MessageChange::React { reaction, add } => UserInternal::React {
+                        id,
+                        reaction,
+                        add,
+                        reply,
+                    },
+                };
+                self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
+                    from_user,
+                    message,
+                }))
+                .await
+            }
+        }
+    }
+
+    /// Record a change to a global message and tell everyone about it.
+    async fn change_global(
+        &mut self,
+        from_user: UserName,
+        message: ChatRecord,
+        change: MessageChange,
+        reply: Reply,
+    ) -> Result<()> {
+        let id = message.id;
+        match change {
+            MessageChange::Edit(message) => {
+                self.history_processor_tx
+                    .send(HistoryMessage::Edit(Box::new(message.clone())))
+                    .await?;
+                self.broadcast_as_server(ServerInternal::MessageEdited(Box::new(message)));
+            }
+            MessageChange::Delete { .. } => {
+                self.history_processor_tx
+                    .send(HistoryMessage::Delete(id))
+                    .await?;
+                self.broadcast_as_server(ServerInternal::MessageDeleted {
+                    id,
+                    target: ChatTarget::Global,
+                    by: from_user,
+                });
+            }
+            MessageChange::React { reaction, add } => {
+                let (sender, receiver) = oneshot::channel();
+                self.history_processor_tx
+                    .send(HistoryMessage::React {
+                        id,
+                        user: from_user,
+                        reaction,
+                        add,
+                        sender,
+                    })
+                    .await?;
+                match receiver.await.unwrap_or(Ok(None)) {
+                    Ok(Some(message)) => {
+                        self.broadcast_as_server(ServerInternal::ReactionsChanged {
+                            id,
+                            target: ChatTarget::Global,
+                            reactions: message.reactions,
+                        });
+                    }
+                    Ok(None) => {
+                        reply.error(&CommonError::MessageNotFound(id));
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        reply.error(&e);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        reply.ack();
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    async fn handle_admin_command(
+        &mut self,
+        from_user: UserName,
+        command: AdminCommand,
+        reply: Reply,
+    ) -> Result<()> {
+        info!("Admin command from {}: {}", from_user, command);
+
+        let message = match command {
+            AdminCommand::Disconnect { user, reason } => UserInternal::ForceDisconnect {
+                user,
+                reason,
+                reply,
+            },
+            AdminCommand::BanUser { user, reason } => UserInternal::Ban {
+                user,
+                reason,
+                reply,
+            },
+            AdminCommand::UnbanUser(user) => UserInternal::Unban { user, reply },
+            AdminCommand::BanIp(address) => UserInternal::BanAddress { address, reply },
+            AdminCommand::UnbanIp(address) => UserInternal::UnbanAddress { address, reply },
+            AdminCommand::DeleteRoom(room) => {
+                return self
+                    .handle_internal_message(ProcessInternal::RoomMessage(RoomMessage {
+                        from_user,
+                        room_name: room,
+                        message: RoomInternal::DeleteRoom { forced: true },
+                        reply,
+                    }))
+                    .await;
+            }
+            AdminCommand::Announce(content) => {
+                self.broadcast_as_server(ServerInternal::Announcement { from_user, content });
+                reply.ack();
+                return Ok(());
+            }
+        };
+        self.handle_internal_message(ProcessInternal::UserMessage(UserMessage {
+            from_user,
+            message,
+        }))
+        .await
+    }
+
     #[instrument(skip(self), name = "Server", level = "warn")]
     async fn handle_server_message(
         &mut self,
         from_user: UserName,
         message: ServerMessage,
     ) -> Result<()> {
-        info!("Received server message from {}: {:?}", from_user, message);
-        self.server_broadcast_tx.send(message)?;
+        trace!("Received server message from {}: {:?}", from_user, message);
+        send_broadcast(&self.server_broadcast_tx, message);
         Ok(())
     }
 }
@@ -1,21 +1,37 @@
 use std::collections::HashMap;
 
-use super::Result;
+use super::{send_broadcast, Result};
 
 use crate::common::{
     messages::{
-        RoomInternal, RoomMessage, ServerInternal, ServerMessage, UserInternal, UserMessage,
+        HistoryMessage, Reply, RoomInternal, RoomMessage, ServerInternal, ServerMessage, UserEvent,
+        UserInternal, UserMessage,
     },
-    RoomManager, RoomName, User, UserName,
+    CommonError, RoomLifecycle, RoomManager, RoomMode, RoomName, User, UserName,
 };
+use crate::config::RoomConfig;
 use tokio::sync::{broadcast, mpsc, oneshot};
-use tracing::info;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
 
 pub struct RoomProcessor {
     room_processor_rx: mpsc::Receiver<RoomMessage>,
     user_processor_tx: mpsc::Sender<UserMessage>,
     server_broadcast_tx: broadcast::Sender<ServerMessage>,
+    history_processor_tx: mpsc::Sender<HistoryMessage>,
     room_manager: HashMap<RoomName, mpsc::Sender<RoomMessage>>,
+    /// Rooms whose task has finished, reported by the task itself along with whether the room
+    /// was listed to everyone.
+    closed_tx: mpsc::Sender<(RoomName, bool)>,
+    closed_rx: mpsc::Receiver<(RoomName, bool)>,
+    /// Users who left the server or changed their name, reported by the user processor.
+    user_events_tx: mpsc::UnboundedSender<UserEvent>,
+    user_events_rx: mpsc::UnboundedReceiver<UserEvent>,
+    tasks: TaskTracker,
+    /// Capacity of the queue of messages to each room.
+    room_queue_size: usize,
+    /// When the rooms created by users go away on their own.
+    lifecycle: RoomLifecycle,
 }
 
 impl RoomProcessor {
@@ -23,79 +39,287 @@ impl RoomProcessor {
         room_processor_rx: mpsc::Receiver<RoomMessage>,
         user_processor_tx: mpsc::Sender<UserMessage>,
         server_broadcast_tx: broadcast::Sender<ServerMessage>,
+        history_processor_tx: mpsc::Sender<HistoryMessage>,
+        tasks: TaskTracker,
+        room_queue_size: usize,
+        rooms: &RoomConfig,
     ) -> Self {
-        Self {
+        let (closed_tx, closed_rx) = mpsc::channel(room_queue_size);
+        let (user_events_tx, user_events_rx) = mpsc::unbounded_channel();
+        let mut room_processor = Self {
             room_processor_rx,
             user_processor_tx,
             server_broadcast_tx,
+            history_processor_tx,
             room_manager: HashMap::new(),
+            closed_tx,
+            closed_rx,
+            user_events_tx,
+            user_events_rx,
+            tasks,
+            room_queue_size,
+            lifecycle: rooms.lifecycle(),
+        };
+        for room_name in &rooms.permanent {
+            if let Err(e) = room_name.validate() {
+                warn!("Skipping permanent room {}: {}", room_name, e);
+                continue;
+            }
+            info!("Permanent room: {}", room_name);
+            room_processor.spawn_room(
+                room_name.clone(),
+                UserName::new("server"),
+                RoomMode::Public,
+                RoomLifecycle::default(),
+            );
         }
+        room_processor
+    }
+
+    /// Where the user processor reports the users who left the server or changed their name.
+    pub fn user_events_tx(&self) -> mpsc::UnboundedSender<UserEvent> {
+        self.user_events_tx.clone()
+    }
+
+    /// Start the task of a new room. The task reports back on `closed_tx` when it ends.
+    fn spawn_room(
+        &mut self,
+        room_name: RoomName,
+        owner: UserName,
+        mode: RoomMode,
+        lifecycle: RoomLifecycle,
+    ) {
+        let (mut room_manager, room_tx) = RoomManager::new(
+            room_name.clone(),
+            owner,
+            mode,
+            lifecycle,
+            self.user_processor_tx.clone(),
+            self.history_processor_tx.clone(),
+            self.room_queue_size,
+        );
+        self.room_manager.insert(room_name.clone(), room_tx);
+
+        let closed_tx = self.closed_tx.clone();
+        self.tasks.spawn(async move {
+            if let Err(e) = room_manager.run().await {
+                info!("Error running room manager: {}", e);
+            }
+            let listed = room_manager.is_listed();
+            let _ = closed_tx.send((room_name, listed)).await;
+        });
     }
 
     pub async fn run(mut self) -> Result<()> {
-        while let Some(RoomMessage {
+        loop {
+            tokio::select! {
+                message = self.room_processor_rx.recv() => match message {
+                    Some(message) => self.handle_message(message).await,
+                    None => break,
+                },
+                Some((room_name, listed)) = self.closed_rx.recv() => {
+                    self.room_closed(room_name, listed)
+                }
+                Some(event) = self.user_events_rx.recv() => self.user_event(event).await,
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget a room once its task has finished. A room of the same name may have been created
+    /// again since, so only an entry whose room is gone is removed.
+    fn room_closed(&mut self, room_name: RoomName, listed: bool) {
+        if !self
+            .room_manager
+            .get(&room_name)
+            .is_some_and(|room_tx| room_tx.is_closed())
+        {
+            return;
+        }
+        self.room_manager.remove(&room_name);
+        info!("Room {} closed", room_name);
+        if listed {
+            self.announce(
+                UserName::new("server"),
+                format!("Room {} deleted", room_name),
+            );
+        }
+    }
+
+    /// Tell everyone on the server about a room.
+    fn announce(&self, from_user: UserName, message: String) {
+        send_broadcast(
+            &self.server_broadcast_tx,
+            ServerMessage {
+                from_user,
+                content: ServerInternal::ServerMessage(message),
+            },
+        );
+    }
+
+    /// Pass what happened to a user on to each room they are in.
+    async fn user_event(&mut self, event: UserEvent) {
+        let (from_user, user, rooms, renamed) = match event {
+            UserEvent::Departed { user, rooms } => (user.user_name().clone(), user, rooms, false),
+            UserEvent::Renamed { from, user, rooms } => (from, user, rooms, true),
+        };
+        for room_name in rooms {
+            let Some(room_tx) = self.room_manager.get(&room_name) else {
+                continue;
+            };
+            let (reply, _) = Reply::new();
+            let message = RoomMessage {
+                from_user: from_user.clone(),
+                room_name,
+                message: if renamed {
+                    RoomInternal::Renamed(user.clone())
+                } else {
+                    RoomInternal::Departed(user.clone())
+                },
+                reply,
+            };
+            // A room that is gone has nobody left to update.
+            let _ = room_tx.send(message).await;
+        }
+    }
+
+    /// Handle a single request. Whatever goes wrong with it is the requester's to hear about,
+    /// the other rooms carry on.
+    async fn handle_message(&mut self, message: RoomMessage) {
+        let RoomMessage {
             from_user,
             room_name,
             message,
-        }) = self.room_processor_rx.recv().await
-        {
-            match message {
-                RoomInternal::NewRoom => {
-                    info!("New room: {}", from_user);
-                    let (room_manager, room_tx) =
-                        RoomManager::new(room_name.clone(), self.user_processor_tx.clone());
-                    self.room_manager.insert(room_name.clone(), room_tx);
-
-                    tokio::spawn(async move {
-                        if let Err(e) = room_manager.run().await {
-                            info!("Error running room manager: {}", e);
-                        }
-                    });
-                    self.notify_user(
-                        from_user.clone(),
-                        format!("You created room: {}", room_name).to_string(),
-                    )
-                    .await?;
-
-                    self.server_broadcast_tx.send(ServerMessage {
-                        from_user,
-                        content: ServerInternal::ServerMessage(
-                            format!("Room {} created", room_name).to_string(),
-                        ),
-                    })?;
+            reply,
+        } = message;
+        match message {
+            RoomInternal::NewRoom { mode } => {
+                if let Err(e) = room_name.validate() {
+                    reply.error(&e);
+                    return;
                 }
-                RoomInternal::ListRooms => {
-                    info!("List rooms: {}", from_user);
-                    let rooms: String = self
-                        .room_manager
-                        .keys()
-                        .map(|room_name| room_name.to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ");
-
-                    self.notify_user(
+                // A room whose task has ended but which is not forgotten yet is as good as gone.
+                if self
+                    .room_manager
+                    .get(&room_name)
+                    .is_some_and(|room_tx| !room_tx.is_closed())
+                {
+                    reply.error(&CommonError::RoomExists(room_name));
+                    return;
+                }
+                info!("New {} room: {}", mode, from_user);
+                let listed = mode.is_listed();
+                // The room belongs to the account of its creator, who may have left already.
+                let owner = match self.get_user_info(from_user.clone()).await {
+                    Ok(user) => user.login().clone(),
+                    Err(_) => from_user.clone(),
+                };
+                self.spawn_room(room_name.clone(), owner, mode, self.lifecycle);
+                // The room is there for everyone even if its creator went away in the meantime.
+                if let Err(e) = self
+                    .notify_user(
                         from_user.clone(),
-                        format!("Rooms: [{:}]", rooms).to_string(),
+                        format!("You created room: {}", room_name),
                     )
-                    .await?;
+                    .await
+                {
+                    info!(
+                        "Failed to tell {} about room {}: {}",
+                        from_user, room_name, e
+                    );
                 }
-                RoomInternal::JoinRoom
-                | RoomInternal::LeaveRoom
-                | RoomInternal::ListUsers
-                | RoomInternal::RoomMessage(_) => {
-                    if let Some(room_tx) = self.room_manager.get(&room_name) {
-                        room_tx
-                            .send(RoomMessage {
-                                from_user,
-                                room_name,
-                                message,
-                            })
-                            .await?;
-                    }
+
+                if listed {
+                    self.announce(from_user, format!("Room {} created", room_name));
                 }
+                reply.ack();
             }
+            RoomInternal::ListRooms => {
+                info!("List rooms: {}", from_user);
+                let user = match self.get_user_info(from_user.clone()).await {
+                    Ok(user) => user,
+                    Err(e) => {
+                        reply.error(&e);
+                        return;
+                    }
+                };
+                let room_txs: Vec<_> = self
+                    .room_manager
+                    .iter()
+                    .map(|(room_name, room_tx)| (room_name.clone(), room_tx.clone()))
+                    .collect();
+                // Every room decides whether the user may see it, asking them all is left to a
+                // task of its own so that a busy room does not hold up the others.
+                self.tasks.spawn(async move {
+                    let mut rooms = Vec::new();
+                    for (room_name, room_tx) in room_txs {
+                        let (listing_tx, listing_rx) = oneshot::channel();
+                        let (room_reply, _) = Reply::new();
+                        let message = RoomMessage {
+                            from_user: from_user.clone(),
+                            room_name: room_name.clone(),
+                            message: RoomInternal::Listing(listing_tx),
+                            reply: room_reply,
+                        };
+                        if room_tx.send(message).await.is_err() {
+                            continue;
+                        }
+                        if let Ok(Some(info)) = listing_rx.await {
+                            rooms.push(info);
+                        }
+                    }
+                    rooms.sort_by(|a, b| a.name.room_name().cmp(b.name.room_name()));
+                    let listing = ServerMessage {
+                        from_user: from_user.clone(),
+                        content: ServerInternal::RoomList { rooms },
+                    };
+                    match user.user_tx().send(listing).await {
+                        Ok(()) => reply.ack(),
+                        Err(e) => info!("Failed to list rooms to {}: {}", from_user, e),
+                    }
+                });
+            }
+            RoomInternal::JoinRoom { .. }
+            | RoomInternal::LeaveRoom
+            | RoomInternal::ListUsers
+            | RoomInternal::RoomMessage(_)
+            | RoomInternal::SetRole { .. }
+            | RoomInternal::ListRoles
+            | RoomInternal::Kick { .. }
+            | RoomInternal::Ban { .. }
+            | RoomInternal::Unban { .. }
+            | RoomInternal::Mute { .. }
+            | RoomInternal::Unmute { .. }
+            | RoomInternal::SetTopic { .. }
+            | RoomInternal::SetDescription { .. }
+            | RoomInternal::SetMode { .. }
+            | RoomInternal::Invite { .. }
+            | RoomInternal::Uninvite { .. }
+            | RoomInternal::Listing(_)
+            | RoomInternal::DeleteRoom { .. }
+            | RoomInternal::Departed(_)
+            | RoomInternal::Renamed(_)
+            | RoomInternal::Typing { .. }
+            | RoomInternal::EditMessage(_)
+            | RoomInternal::DeleteMessage { .. }
+            | RoomInternal::React { .. } => match self.room_manager.get(&room_name) {
+                Some(room_tx) => {
+                    let message = RoomMessage {
+                        from_user,
+                        room_name,
+                        message,
+                        reply,
+                    };
+                    // The room may have shut down before its entry was removed.
+                    if let Err(mpsc::error::SendError(message)) = room_tx.send(message).await {
+                        message
+                            .reply
+                            .error(&CommonError::RoomNotFound(message.room_name));
+                    }
+                }
+                None => reply.error(&CommonError::RoomNotFound(room_name)),
+            },
         }
-        Ok(())
     }
 
     async fn get_user_info(&mut self, from_user: UserName) -> Result<User> {
@@ -1,125 +1,300 @@
-use super::Result;
+use super::{send_broadcast, Result};
 use crossterm::style::Stylize;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{info, instrument, warn};
+use std::net::IpAddr;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, error, info, instrument, trace, warn};
 
 use crate::common::{
-    messages::{ServerInternal, ServerMessage, UserInternal, UserMessage},
-    UserManager, UserName,
+    messages::{
+        Authentication, ErrorCode, ErrorMessage, HistoryMessage, ServerInternal, ServerMessage,
+        UserEvent, UserInternal, UserMessage,
+    },
+    ChatRecord, ChatTarget, CommonError, RoomName, TypingTracker, User, UserManager, UserName,
+    UserStatus,
 };
+use crate::config::AdminConfig;
 
 pub struct UserProcessor {
     user_processor_rx: mpsc::Receiver<UserMessage>,
     server_broadcast_tx: broadcast::Sender<ServerMessage>,
+    history_processor_tx: mpsc::Sender<HistoryMessage>,
+    /// Users who left the server or changed their name while in rooms, for the room processor
+    /// to update those rooms. Unbounded as the room processor waits on this processor.
+    user_events_tx: mpsc::UnboundedSender<UserEvent>,
     user_manager: UserManager,
+    /// Users typing a private message, along with who to.
+    typing: TypingTracker<(UserName, UserName)>,
+    /// Credentials being checked away from this processor, as hashing passwords takes a while.
+    authenticating: JoinSet<Authenticated>,
+}
+
+/// A new user whose credentials were checked, to be added to the connected users if they are
+/// right.
+struct Authenticated {
+    user_name: UserName,
+    address: IpAddr,
+    sender: oneshot::Sender<std::result::Result<mpsc::Receiver<ServerMessage>, CommonError>>,
+    result: std::result::Result<(), CommonError>,
 }
 
 impl UserProcessor {
     pub fn new(
         user_processor_rx: mpsc::Receiver<UserMessage>,
         server_broadcast_tx: broadcast::Sender<ServerMessage>,
+        history_processor_tx: mpsc::Sender<HistoryMessage>,
+        user_events_tx: mpsc::UnboundedSender<UserEvent>,
+        mut user_manager: UserManager,
+        admin: &AdminConfig,
     ) -> Self {
+        for user in &admin.users {
+            user_manager.add_admin(user.clone());
+        }
+        for user in &admin.banned_users {
+            user_manager.ban_user(user, None);
+        }
+        for address in &admin.banned_ips {
+            user_manager.ban_address(*address);
+        }
+        for user in admin.reserved() {
+            user_manager.reserve_name(user);
+        }
         Self {
             user_processor_rx,
             server_broadcast_tx,
-            user_manager: UserManager::default(),
+            history_processor_tx,
+            user_events_tx,
+            user_manager,
+            typing: TypingTracker::default(),
+            authenticating: JoinSet::new(),
         }
     }
 
     #[instrument(skip_all, level = "debug")]
     pub async fn run(mut self) -> Result<()> {
         loop {
+            let typing_expiry = self.typing.next_expiry();
             tokio::select! {
                 Some(user_message) = self.user_processor_rx.recv() => {
-                    info!("User message: {:?}", user_message);
+                    trace!("User message: {:?}", user_message);
                     self.process_user_message(user_message).await?;
                 }
-
+                Some(authenticated) = self.authenticating.join_next() => match authenticated {
+                    Ok(authenticated) => self.add_new_user(authenticated).await?,
+                    Err(e) => error!("Checking credentials failed: {}", e),
+                },
+                _ = tokio::time::sleep_until(typing_expiry.unwrap_or_else(Instant::now)), if typing_expiry.is_some() => {
+                    for (user, to_user) in self.typing.expire() {
+                        self.send_typing(&user, &to_user, false);
+                    }
+                }
+                else => break,
             }
         }
+        Ok(())
     }
 
     #[instrument(skip_all, level = "debug")]
     async fn process_user_message(&mut self, user_message: UserMessage) -> Result<()> {
         let UserMessage { from_user, message } = user_message;
         match message {
-            UserInternal::NewUser(sender) => {
-                info!("New user: {}", from_user);
-                let user_rx = self.user_manager.add_new_user(from_user.clone());
-                sender.send(user_rx).unwrap();
-                self.server_broadcast_tx.send(ServerMessage {
-                    from_user: from_user.clone(),
-                    content: ServerInternal::ServerMessage(
-                        format!("{} joined the server", from_user.to_string().green()).to_string(),
-                    ),
-                })?;
+            UserInternal::NewUser {
+                authentication,
+                address,
+                sender,
+            } => {
+                info!("New user: {} from {}", from_user, address);
+                let admitted =
+                    self.user_manager.admit(&from_user, address).and_then(
+                        |()| match authentication {
+                            Authentication::Register { .. } => {
+                                self.user_manager.check_registration(&from_user)
+                            }
+                            Authentication::Login { .. } => Ok(()),
+                        },
+                    );
+                if let Err(e) = admitted {
+                    let _ = sender.send(Err(e));
+                    return Ok(());
+                }
+                let accounts = self.user_manager.accounts();
+                self.authenticating.spawn(async move {
+                    let user_name = from_user.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        accounts.authenticate(&user_name, authentication)
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err(e.into()));
+                    Authenticated {
+                        user_name: from_user,
+                        address,
+                        sender,
+                        result,
+                    }
+                });
             }
             UserInternal::GetUser(sender) => {
                 info!("Get user info: {}", from_user);
-                sender
-                    .send(Ok(self.user_manager.get_user(&from_user)?.clone()))
-                    .unwrap();
+                // The user may be offline, which is for the asker to handle. The asker may also
+                // have stopped waiting.
+                let _ = sender.send(self.user_manager.get_user(&from_user).cloned());
             }
             UserInternal::DisconnectUser => {
                 info!("Disconnecting user: {}", from_user);
-                match self.user_manager.remove_user(&from_user) {
+                match self.remove_user(&from_user) {
                     Ok(_) => {
-                        self.server_broadcast_tx.send(ServerMessage {
-                            from_user: from_user.clone(),
-                            content: ServerInternal::ServerMessage(format!(
-                                "{} disconnected",
-                                from_user
-                            )),
-                        })?;
+                        send_broadcast(
+                            &self.server_broadcast_tx,
+                            ServerMessage {
+                                from_user: from_user.clone(),
+                                content: ServerInternal::ServerMessage(format!(
+                                    "{} disconnected",
+                                    from_user
+                                )),
+                            },
+                        );
                     }
                     Err(e) => {
                         warn!("Unable to disconnect user: {}", e);
                     }
                 }
             }
-            UserInternal::PrivateMessage { to_user, content } => {
+            UserInternal::PrivateMessage {
+                to_user,
+                content,
+                reply,
+            } => {
                 info!("Private message from: {} to: {}", from_user, to_user);
 
-                match self.user_manager.get_user(&to_user) {
-                    Ok(user) => {
-                        let to_user_tx = user.user_tx();
-                        if to_user == from_user {
-                            to_user_tx
-                                .send(ServerMessage {
-                                    from_user,
-                                    content: ServerInternal::Error(
-                                        "You can't send a private message to yourself".to_string(),
-                                    ),
-                                })
-                                .await?;
-                        } else {
-                            to_user_tx
+                if to_user == from_user {
+                    reply.error(ErrorMessage::new(
+                        ErrorCode::InvalidRequest,
+                        "you can't send a private message to yourself",
+                    ));
+                    return Ok(());
+                }
+                // The recipient seeing the message knows the sender is done typing it.
+                self.typing.stop(&(from_user.clone(), to_user.clone()));
+                let record = ChatRecord::new(
+                    from_user.clone(),
+                    ChatTarget::Private(to_user.clone()),
+                    &content,
+                );
+                let id = record.id;
+                match self.user_manager.recipient(&to_user) {
+                    Some(user) => {
+                        user.user_tx()
+                            .send(ServerMessage {
+                                from_user: from_user.clone(),
+                                content: ServerInternal::PrivateMessage {
+                                    id,
+                                    timestamp: record.timestamp,
+                                    from_user: from_user.clone(),
+                                    content,
+                                },
+                            })
+                            .await?;
+                    }
+                    None => {
+                        if let Err(e) = self.user_manager.queue_message(&to_user, record.clone()) {
+                            warn!("Unable to queue private message: {e}");
+                            reply.error(&e);
+                            return Ok(());
+                        }
+                        info!("{} is offline, message queued", to_user);
+                        if let Ok(user) = self.user_manager.get_user(&from_user) {
+                            user.user_tx()
                                 .send(ServerMessage {
                                     from_user: from_user.clone(),
-                                    content: ServerInternal::PrivateMessage { from_user, content },
+                                    content: ServerInternal::PrivateMessageQueued { to_user },
                                 })
                                 .await?;
                         }
                     }
+                }
+                self.history_processor_tx
+                    .send(HistoryMessage::Record(Box::new(record)))
+                    .await?;
+                reply.sent(id);
+            }
+            UserInternal::EditMessage { message, reply } => {
+                info!("{} edited private message {}", from_user, message.id);
+                if let ChatTarget::Private(to_user) = &message.target {
+                    if let Err(e) = self.user_manager.edit_queued_message(to_user, &message) {
+                        warn!("Unable to edit queued message {}: {}", message.id, e);
+                    }
+                }
+                self.history_processor_tx
+                    .send(HistoryMessage::Edit(message.clone()))
+                    .await?;
+                self.notify_private_change(
+                    &message,
+                    ServerInternal::MessageEdited(message.clone()),
+                )
+                .await;
+                reply.ack();
+            }
+            UserInternal::DeleteMessage { message, reply } => {
+                info!("{} deleted private message {}", from_user, message.id);
+                if let ChatTarget::Private(to_user) = &message.target {
+                    if let Err(e) = self.user_manager.delete_queued_message(to_user, message.id) {
+                        warn!("Unable to delete queued message {}: {}", message.id, e);
+                    }
+                }
+                self.history_processor_tx
+                    .send(HistoryMessage::Delete(message.id))
+                    .await?;
+                let deleted = ServerInternal::MessageDeleted {
+                    id: message.id,
+                    target: message.target.clone(),
+                    by: from_user,
+                };
+                self.notify_private_change(&message, deleted).await;
+                reply.ack();
+            }
+            UserInternal::React {
+                id,
+                reaction,
+                add,
+                reply,
+            } => {
+                let (sender, receiver) = oneshot::channel();
+                self.history_processor_tx
+                    .send(HistoryMessage::React {
+                        id,
+                        user: from_user,
+                        reaction,
+                        add,
+                        sender,
+                    })
+                    .await?;
+                let message = match receiver.await.unwrap_or(Ok(None)) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => {
+                        reply.error(&CommonError::MessageNotFound(id));
+                        return Ok(());
+                    }
                     Err(e) => {
-                        warn!("User does not exist: {e}");
-                        if let Ok(from_user) = self.user_manager.get_user(&from_user) {
-                            let from_user_name = from_user.user_name().clone();
-                            from_user
-                                .user_tx()
-                                .send(ServerMessage {
-                                    from_user: from_user_name,
-                                    content: ServerInternal::Error(format!(
-                                        "User not found: {}",
-                                        to_user
-                                    )),
-                                })
-                                .await?;
-                        };
+                        reply.error(&e);
+                        return Ok(());
+                    }
+                };
+                // The sender may react to a message still waiting for its recipient.
+                if let ChatTarget::Private(to_user) = &message.target {
+                    if let Err(e) = self.user_manager.edit_queued_message(to_user, &message) {
+                        warn!("Unable to update queued message {}: {}", message.id, e);
                     }
                 }
+                let changed = ServerInternal::ReactionsChanged {
+                    id,
+                    target: message.target.clone(),
+                    reactions: message.reactions.clone(),
+                };
+                self.notify_private_change(&message, changed).await;
+                reply.ack();
             }
-            UserInternal::Ping(nonce) => {
+            UserInternal::Ping(nonce, reply) => {
                 info!("Ping from: {}", from_user);
                 if let Ok(user) = self.user_manager.get_user(&from_user) {
                     user.user_tx()
@@ -129,10 +304,11 @@ impl UserProcessor {
                         })
                         .await?;
                 }
+                reply.ack();
             }
-            UserInternal::ListUsers => {
+            UserInternal::ListUsers(reply) => {
                 info!("List users from: {}", from_user);
-                let users: Vec<UserName> = self.user_manager.list_users();
+                let users = self.user_manager.list_users();
                 if let Ok(user_tx) = self.user_manager.get_user(&from_user) {
                     user_tx
                         .user_tx()
@@ -142,9 +318,305 @@ impl UserProcessor {
                         })
                         .await?;
                 }
+                reply.ack();
+            }
+            UserInternal::ChangeNick { user_name, reply } => {
+                match self.change_nick(&from_user, user_name).await {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                }
+            }
+            UserInternal::SetPresence {
+                presence,
+                text,
+                reply,
+            } => match self.user_manager.set_presence(&from_user, presence, text) {
+                Ok(status) => {
+                    info!("{} is now {}", from_user, presence);
+                    self.announce_presence(status);
+                    reply.ack();
+                }
+                Err(e) => reply.error(&e),
+            },
+            UserInternal::Typing {
+                to_user,
+                typing,
+                reply,
+            } => {
+                // Nobody is told about typing to someone offline, there is nobody to tell.
+                let key = (from_user.clone(), to_user.clone());
+                let changed = if typing && self.user_manager.recipient(&to_user).is_some() {
+                    self.typing.start(key)
+                } else {
+                    self.typing.stop(&key)
+                };
+                if changed {
+                    self.send_typing(&from_user, &to_user, typing);
+                }
+                reply.ack();
+            }
+            UserInternal::Idle => {
+                if let Some(status) = self.user_manager.went_idle(&from_user) {
+                    info!("{} is away after being inactive", from_user);
+                    self.announce_presence(status);
+                }
+            }
+            UserInternal::Active => {
+                if let Some(status) = self.user_manager.became_active(&from_user) {
+                    info!("{} is back", from_user);
+                    self.announce_presence(status);
+                }
+            }
+            UserInternal::ForceDisconnect {
+                user,
+                reason,
+                reply,
+            } => match self.disconnect(&from_user, &user, reason).await {
+                Ok(()) => reply.ack(),
+                Err(e) => reply.error(&e),
+            },
+            UserInternal::Ban {
+                user,
+                reason,
+                reply,
+            } => {
+                info!("{} banned {} from the server", from_user, user);
+                self.user_manager.ban_user(&user, reason.clone());
+                if self.user_manager.get_user(&user).is_ok() {
+                    let reason = Some(match reason {
+                        Some(reason) => format!("You were banned: {}", reason),
+                        None => "You were banned".to_string(),
+                    });
+                    self.disconnect(&from_user, &user, reason).await?;
+                }
+                reply.ack();
             }
+            UserInternal::Unban { user, reply } => match self.user_manager.unban_user(&user) {
+                Ok(()) => reply.ack(),
+                Err(e) => reply.error(&e),
+            },
+            UserInternal::BanAddress { address, reply } => {
+                info!("{} banned {} from the server", from_user, address);
+                self.user_manager.ban_address(address);
+                for user in self.user_manager.users_at(&address) {
+                    let reason = Some("Your address was banned".to_string());
+                    self.disconnect(&from_user, &user, reason).await?;
+                }
+                reply.ack();
+            }
+            UserInternal::UnbanAddress { address, reply } => {
+                match self.user_manager.unban_address(&address) {
+                    Ok(()) => reply.ack(),
+                    Err(e) => reply.error(&e),
+                }
+            }
+            UserInternal::JoinedRoom { room, session } => {
+                if !self.user_manager.joined_room(&session, room.clone()) {
+                    // The session went away before the room reported it, so the room was not
+                    // told when it did.
+                    self.leave_rooms(session, vec![room]);
+                }
+            }
+            UserInternal::LeftRoom { room } => self.user_manager.left_room(&from_user, &room),
+        }
+
+        Ok(())
+    }
+
+    /// Tell `to_user` that `user` started or stopped typing to them. Typing is not worth holding
+    /// up this processor for, a recipient whose queue is full misses it.
+    fn send_typing(&self, user: &UserName, to_user: &UserName, typing: bool) {
+        let Some(recipient) = self.user_manager.recipient(to_user) else {
+            return;
+        };
+        let notice = ServerMessage {
+            from_user: user.clone(),
+            content: ServerInternal::Typing {
+                user: user.clone(),
+                room: None,
+                typing,
+            },
+        };
+        if let Err(e) = recipient.user_tx().try_send(notice) {
+            debug!("Unable to tell {} that {} is typing: {}", to_user, user, e);
+        }
+    }
+
+    /// Add a user once their credentials were checked, and tell the client handler how it went.
+    async fn add_new_user(&mut self, authenticated: Authenticated) -> Result<()> {
+        let Authenticated {
+            user_name,
+            address,
+            sender,
+            result,
+        } = authenticated;
+        let user_rx =
+            result.and_then(|()| self.user_manager.add_new_user(user_name.clone(), address));
+        let joined = user_rx.is_ok();
+        if sender.send(user_rx).is_err() {
+            // The client handler stopped waiting, e.g. because the handshake timed out.
+            warn!("{} is gone before joining the server", user_name);
+            if joined {
+                let _ = self.remove_user(&user_name);
+            }
+            return Ok(());
+        }
+        if !joined {
+            return Ok(());
+        }
+        self.deliver_queued(&user_name).await;
+        send_broadcast(
+            &self.server_broadcast_tx,
+            ServerMessage {
+                from_user: user_name.clone(),
+                content: ServerInternal::ServerMessage(
+                    format!("{} joined the server", user_name.to_string().green()).to_string(),
+                ),
+            },
+        );
+        Ok(())
+    }
+
+    /// Send `user` the private messages kept for them while they were offline. They stay queued
+    /// if the connection went away in the meantime.
+    async fn deliver_queued(&mut self, user: &UserName) {
+        let messages = self.user_manager.queued_messages(user);
+        if messages.is_empty() {
+            return;
+        }
+        let Ok(session) = self.user_manager.get_user(user) else {
+            return;
+        };
+        info!("Delivering {} queued messages to {}", messages.len(), user);
+        let delivery = ServerMessage {
+            from_user: user.clone(),
+            content: ServerInternal::OfflineMessages { messages },
+        };
+        if session.user_tx().send(delivery).await.is_err() {
+            return;
         }
+        if let Err(e) = self.user_manager.clear_messages(user) {
+            warn!("Unable to clear the queued messages of {}: {}", user, e);
+        }
+    }
+
+    /// Tell the sender and the recipient of a private message, whichever of them are connected,
+    /// that it was edited or deleted.
+    async fn notify_private_change(&self, message: &ChatRecord, change: ServerInternal) {
+        let sender = self.user_manager.get_user(&message.from_user).ok();
+        let recipient = match &message.target {
+            ChatTarget::Private(to_user) => self.user_manager.recipient(to_user),
+            _ => None,
+        };
+        for user in sender.into_iter().chain(recipient) {
+            let notice = ServerMessage {
+                from_user: message.from_user.clone(),
+                content: change.clone(),
+            };
+            if let Err(e) = user.user_tx().send(notice).await {
+                warn!(
+                    "Unable to tell {} about message {}: {}",
+                    user, message.id, e
+                );
+            }
+        }
+    }
 
+    /// Tell the user and everyone sharing a room with them about their presence. Presence is
+    /// not worth holding up this processor for, connections whose queue is full miss it.
+    fn announce_presence(&self, status: UserStatus) {
+        let Ok(user) = self.user_manager.get_user(&status.user) else {
+            return;
+        };
+        let notice = ServerMessage {
+            from_user: status.user.clone(),
+            content: ServerInternal::PresenceChanged(status.clone()),
+        };
+        let room_mates = self.user_manager.room_mates(&status.user);
+        for user in std::iter::once(user).chain(room_mates) {
+            if let Err(e) = user.user_tx().try_send(notice.clone()) {
+                warn!("Unable to tell {} about {}: {}", user, status.user, e);
+            }
+        }
+    }
+
+    /// Rename `user`, on the server and in the rooms they are in, and tell everyone.
+    async fn change_nick(
+        &mut self,
+        user: &UserName,
+        user_name: UserName,
+    ) -> std::result::Result<(), CommonError> {
+        let new_name =
+            UserName::parse(user_name.user_name()).map_err(CommonError::InvalidUserName)?;
+        let (renamed, rooms) = self.user_manager.rename_user(user, new_name.clone())?;
+        info!("{} is now known as {}", user, new_name);
+        let notice = ServerMessage {
+            from_user: new_name.clone(),
+            content: ServerInternal::NickChanged {
+                from: user.clone(),
+                to: new_name,
+            },
+        };
+        // The connection learns its new name from the notice, the rename stands either way.
+        let _ = renamed.user_tx().send(notice.clone()).await;
+        if !rooms.is_empty() {
+            self.report_to_rooms(UserEvent::Renamed {
+                from: user.clone(),
+                user: renamed,
+                rooms,
+            });
+        }
+        send_broadcast(&self.server_broadcast_tx, notice);
         Ok(())
     }
+
+    /// Close the connection of `user` on behalf of `by`. The connection sends the notice as its
+    /// last frame.
+    async fn disconnect(
+        &mut self,
+        by: &UserName,
+        user: &UserName,
+        reason: Option<String>,
+    ) -> std::result::Result<(), CommonError> {
+        let removed = self.remove_user(user)?;
+        info!("{} disconnected {}", by, user);
+        // Sending only fails if the connection is already gone.
+        let _ = removed
+            .user_tx()
+            .send(ServerMessage {
+                from_user: by.clone(),
+                content: ServerInternal::Disconnected { reason },
+            })
+            .await;
+        send_broadcast(
+            &self.server_broadcast_tx,
+            ServerMessage {
+                from_user: by.clone(),
+                content: ServerInternal::ServerMessage(format!("{} was disconnected", user)),
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove `user` from the connected users and from the rooms they are in.
+    fn remove_user(&mut self, user: &UserName) -> std::result::Result<User, CommonError> {
+        let rooms = self.user_manager.rooms_of(user);
+        let removed = self.user_manager.remove_user(user)?;
+        self.leave_rooms(removed.clone(), rooms);
+        Ok(removed)
+    }
+
+    fn leave_rooms(&self, user: User, rooms: Vec<RoomName>) {
+        if rooms.is_empty() {
+            return;
+        }
+        info!("{} leaves {} room(s)", user, rooms.len());
+        self.report_to_rooms(UserEvent::Departed { user, rooms });
+    }
+
+    /// Pass what happened to a user on to the room processor. Sending only fails once the room
+    /// processor has stopped, when the server shuts down.
+    fn report_to_rooms(&self, event: UserEvent) {
+        let _ = self.user_events_tx.send(event);
+    }
 }
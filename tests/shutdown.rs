@@ -0,0 +1,84 @@
+use chat_app::common::messages::{
+    Authentication, ClientMessage, ClientRequest, Handshake, HandshakeResponse, RequestId,
+    ServerInternal,
+};
+use chat_app::common::UserName;
+use chat_app::config::{ServerConfig, StorageConfig};
+use chat_app::connection::Connection;
+use chat_app::Server;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// A port nobody listens on, for the server to bind.
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Connect once the server is listening and log in as a new user.
+async fn register(address: SocketAddr, user_name: &str) -> Connection {
+    let mut connection = loop {
+        match Connection::init(address).await {
+            Ok(connection) => break connection,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let authentication = Authentication::Register {
+        password: "correct horse".to_string(),
+    };
+    let handshake = Handshake::new(UserName::new(user_name), authentication);
+    connection.write_frame(&handshake).await.unwrap();
+    let response: HandshakeResponse = connection.read_frame().await.unwrap();
+    assert!(matches!(response, HandshakeResponse::Accepted { .. }));
+    connection
+}
+
+#[tokio::test]
+async fn clients_are_told_and_messages_kept_when_the_server_shuts_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ServerConfig {
+        host: "127.0.0.1".to_string(),
+        port: free_port().await,
+        storage: StorageConfig {
+            accounts_path: dir.path().join("accounts.bin"),
+            history_path: dir.path().join("history.log"),
+            mailbox_path: dir.path().join("mailbox.bin"),
+        },
+        ..ServerConfig::default()
+    };
+    let address: SocketAddr = config.address().parse().unwrap();
+
+    let mut server = Server::new(config.clone());
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(async move { server.run().await });
+    let mut alice = register(address, "alice").await;
+
+    let request = ClientRequest {
+        id: RequestId::new(1),
+        message: ClientMessage::GlobalChatMessage("last words".to_string()),
+    };
+    alice.write_frame(&request).await.unwrap();
+    loop {
+        if let ServerInternal::Ack { .. } = alice.read_frame().await.unwrap() {
+            break;
+        }
+    }
+
+    shutdown.shutdown(Some("maintenance".to_string()));
+    let reason = loop {
+        if let ServerInternal::ServerShutdown { reason } = alice.read_frame().await.unwrap() {
+            break reason;
+        }
+    };
+    assert_eq!(reason.as_deref(), Some("maintenance"));
+    assert!(alice.read_frame::<ServerInternal>().await.is_err());
+
+    let stopped = tokio::time::timeout(config.timeouts.shutdown(), running).await;
+    stopped.unwrap().unwrap().unwrap();
+    assert!(Connection::init(address).await.is_err());
+    let log = std::fs::read(&config.storage.history_path).unwrap();
+    let words = b"last words";
+    assert!(log.windows(words.len()).any(|window| window == words));
+}
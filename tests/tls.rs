@@ -0,0 +1,121 @@
+use chat_app::common::messages::{Authentication, Handshake};
+use chat_app::common::UserName;
+use chat_app::connection::{
+    ClientTlsConfig, Connection, ConnectionError, ServerTlsConfig, ServerVerification,
+};
+
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// A server presenting a freshly generated self-signed certificate for `localhost`, answering a
+/// single connection by reading a handshake from it.
+struct TestServer {
+    address: SocketAddr,
+    cert_path: PathBuf,
+    fingerprint: String,
+    handshake: JoinHandle<Result<Handshake, ConnectionError>>,
+    _dir: TempDir,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        let fingerprint = Sha256::digest(certified.cert.der())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let acceptor = ServerTlsConfig::new(&cert_path, &key_path)
+            .acceptor()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handshake = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = Connection::accept_tls(stream, &acceptor).await?;
+            connection.read_frame::<Handshake>().await
+        });
+
+        Self {
+            address,
+            cert_path,
+            fingerprint,
+            handshake,
+            _dir: dir,
+        }
+    }
+
+    async fn connect(&self, server_name: &str, verification: ServerVerification) -> Connection {
+        let tls = ClientTlsConfig::new(server_name, verification);
+        Connection::init_tls(self.address, &tls).await.unwrap()
+    }
+
+    async fn refused(&self, server_name: &str, verification: ServerVerification) {
+        let tls = ClientTlsConfig::new(server_name, verification);
+        let result = Connection::init_tls(self.address, &tls).await;
+        assert!(matches!(result, Err(ConnectionError::TlsHandshake(_))));
+    }
+}
+
+/// Send a handshake over `connection` and check the server read it back.
+async fn exchange_handshake(server: TestServer, mut connection: Connection) {
+    assert!(connection.is_tls());
+    let handshake = Handshake::new(
+        UserName::new("alice"),
+        Authentication::Login {
+            password: "correct horse".to_string(),
+        },
+    );
+    connection.write_frame(&handshake).await.unwrap();
+
+    let received = server.handshake.await.unwrap().unwrap();
+    assert_eq!(received.user_name, handshake.user_name);
+    assert_eq!(received.max_version, handshake.max_version);
+}
+
+#[tokio::test]
+async fn self_signed_certificate_as_ca() {
+    let server = TestServer::start().await;
+    let verification = ServerVerification::CaFile(server.cert_path.clone());
+    let connection = server.connect("localhost", verification).await;
+    exchange_handshake(server, connection).await;
+}
+
+#[tokio::test]
+async fn pinned_fingerprint() {
+    let server = TestServer::start().await;
+    let verification = ServerVerification::Fingerprint(server.fingerprint.to_uppercase());
+    let connection = server.connect("localhost", verification).await;
+    exchange_handshake(server, connection).await;
+}
+
+#[tokio::test]
+async fn wrong_fingerprint_is_refused() {
+    let server = TestServer::start().await;
+    let first = if server.fingerprint.starts_with("00") {
+        "11"
+    } else {
+        "00"
+    };
+    let fingerprint = format!("{}{}", first, &server.fingerprint[2..]);
+    server
+        .refused("localhost", ServerVerification::Fingerprint(fingerprint))
+        .await;
+}
+
+#[tokio::test]
+async fn certificate_for_another_name_is_refused() {
+    let server = TestServer::start().await;
+    let verification = ServerVerification::CaFile(server.cert_path.clone());
+    server.refused("example.com", verification).await;
+}